@@ -15,12 +15,6 @@ use time::OffsetDateTime;
 // - public client flow
 const CLIENT_ID: &str = "0cadb66e-6914-4a9f-8058-3ba6e5cb58d8";
 
-// Common Microsoft Identity Platform (Azure AD v2.0) endpoints
-const MS_GRAPH_AUTH_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
-const MS_GRAPH_TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
-const MS_GRAPH_DEVICE_AUTH_URL: &str =
-    "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode";
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenCache {
     pub access_token: String,
@@ -48,6 +42,18 @@ impl TokenCache {
     }
 }
 
+/// Scopes needed for the application, for the Graph API of the configured cloud
+pub fn scopes() -> Vec<String> {
+    let settings = crate::config::settings();
+    vec![
+        "offline_access".to_string(),           // Required for refresh tokens
+        settings.graph_scope("Mail.ReadWrite"), // Includes Mail.Read capabilities
+        settings.graph_scope("Mail.ReadWrite.Shared"), // Shared and delegated mailboxes
+        settings.graph_scope("MailboxSettings.ReadWrite"), // Outlook inbox rules
+        settings.graph_scope("User.Read"),      // For accessing user profile information
+    ]
+}
+
 pub struct Auth {
     client: BasicClient,
     token_cache_path: PathBuf,
@@ -55,16 +61,18 @@ pub struct Auth {
 
 impl Auth {
     pub fn new() -> Result<Self> {
-        // Create OAuth2 client for Microsoft identity platform
+        // Create OAuth2 client for Microsoft identity platform (endpoints are configurable)
+        let login_url = &crate::config::settings().login_url;
         let client = BasicClient::new(
             ClientId::new(CLIENT_ID.to_string()),
             None, // No client secret for public client
-            AuthUrl::new(MS_GRAPH_AUTH_URL.to_string())?,
-            Some(TokenUrl::new(MS_GRAPH_TOKEN_URL.to_string())?),
+            AuthUrl::new(format!("{}/authorize", login_url))?,
+            Some(TokenUrl::new(format!("{}/token", login_url))?),
         )
-        .set_device_authorization_url(DeviceAuthorizationUrl::new(
-            MS_GRAPH_DEVICE_AUTH_URL.to_string(),
-        )?)
+        .set_device_authorization_url(DeviceAuthorizationUrl::new(format!(
+            "{}/devicecode",
+            login_url
+        ))?)
         .set_redirect_uri(RedirectUrl::new("http://localhost".to_string())?); // Not used with device flow
 
         // Use our config module to get the token cache path
@@ -82,14 +90,11 @@ impl Auth {
             "Starting authentication flow with Microsoft Graph (client ID: {})",
             CLIENT_ID
         );
+        if crate::config::current_profile() != crate::config::DEFAULT_PROFILE {
+            println!("Profile: {}", crate::config::current_profile());
+        }
 
-        // Define scopes needed for the application
-        let scopes = vec![
-            "offline_access",                             // Required for refresh tokens
-            "https://graph.microsoft.com/Mail.ReadWrite", // Includes Mail.Read capabilities
-            "User.Read",                                  // For accessing user profile information
-        ];
-
+        let scopes = scopes();
         println!(
             "Requesting device code authentication with scopes: {:?}",
             scopes
@@ -99,7 +104,7 @@ impl Auth {
         let details: DeviceAuthorizationResponse<EmptyExtraDeviceAuthorizationFields> = self
             .client
             .exchange_device_code()?
-            .add_scopes(scopes.iter().map(|s| Scope::new(s.to_string())))
+            .add_scopes(scopes.into_iter().map(Scope::new))
             .request_async(async_http_client)
             .await
             .map_err(|e| anyhow::anyhow!("Device code request failed: {:?}", e))?;
@@ -178,6 +183,30 @@ impl Auth {
         }
     }
 
+    /// Lists the profiles that have a saved login
+    pub fn list_profiles() -> Result<()> {
+        let current = crate::config::current_profile();
+        let mut found = false;
+
+        for profile in crate::config::list_profiles()? {
+            let token_cache_path =
+                crate::config::get_profile_dir_for(&profile)?.join("token_cache.yaml");
+            if !token_cache_path.exists() {
+                continue;
+            }
+
+            found = true;
+            let marker = if profile == current { "*" } else { " " };
+            println!("{} {}", marker, profile);
+        }
+
+        if !found {
+            println!("No profiles are logged in. Run 'mailsweep auth login' to log in.");
+        }
+
+        Ok(())
+    }
+
     /// Logs out by removing the token cache
     pub fn logout(&self) -> Result<()> {
         if self.token_cache_path.exists() {
@@ -0,0 +1,259 @@
+use crate::auth::Auth;
+use crate::config::ImapSettings;
+use crate::graph_client::GraphClient;
+use crate::imap::Imap;
+use crate::maildir::Maildir;
+use crate::rules::Rule;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Structure representing an email message
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub id: String,
+    pub subject: String,
+    pub sender: String,
+    /// Email address of the sender, without the display name
+    pub sender_address: String,
+    pub received_date: DateTime<Utc>,
+    pub is_read: bool,
+    pub has_attachments: bool,
+    /// Outlook categories assigned to the message
+    pub categories: Vec<String>,
+    /// Follow-up flag of the message
+    pub flag_status: FlagStatus,
+    /// Start and due date of the follow-up flag, if it has them
+    pub flag_dates: Option<FlagDates>,
+    /// ID of the folder the message is in
+    pub folder_id: String,
+    pub matched_rule: Option<String>,
+    /// Actions of the matched rule, in order
+    pub actions: Vec<crate::rules::RuleAction>,
+}
+
+/// Follow-up flag state of a message, named as in Microsoft Graph
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FlagStatus {
+    #[default]
+    NotFlagged,
+    Flagged,
+    Complete,
+}
+
+/// Start and due date of a follow-up flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagDates {
+    pub start: DateTime<Utc>,
+    pub due: DateTime<Utc>,
+}
+
+impl FlagDates {
+    /// Dates of a flag starting when the message was received and due `due` later
+    pub fn after_received(message: &Message, due: Duration) -> Self {
+        Self {
+            start: message.received_date,
+            due: message.received_date + due,
+        }
+    }
+}
+
+/// Result of applying an operation to a set of messages
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    /// Processed messages as (ID before, ID after); moving a message assigns it a new ID.
+    /// The ID after is empty if the backend can't tell it.
+    pub succeeded: Vec<(String, String)>,
+    pub failed: usize,
+}
+
+/// Options for listing the messages of a folder
+pub struct ListOptions<'a> {
+    /// Stop after this many messages
+    pub limit: Option<usize>,
+    /// Number of messages to fetch per request, for backends that page
+    pub page_size: usize,
+    /// Rules the messages will be matched against. Backends that can filter on the
+    /// server use them to only return candidates.
+    pub rules: &'a [&'a Rule],
+}
+
+/// A mail store that `clean` and `undo` can work on
+#[async_trait]
+pub trait MailBackend: Send + Sync {
+    /// Resolve a well-known folder name (see `well_known_folder`) or a folder path of
+    /// display names separated by `/` to a folder ID
+    async fn resolve_folder(&self, path: &str, create_missing: bool) -> Result<String>;
+
+    /// IDs of all folders below a folder
+    async fn list_subfolders(&self, folder_id: &str) -> Result<Vec<String>>;
+
+    /// List the messages of a folder, newest first
+    async fn list_messages(&self, folder_id: &str, options: &ListOptions) -> Result<Vec<Message>>;
+
+    /// List the messages of a folder that are new or changed since `sync_state`, or all
+    /// messages without one. Returns the messages and the sync state to continue from.
+    async fn list_changed_messages(
+        &self,
+        _folder_id: &str,
+        _sync_state: Option<&str>,
+        _page_size: usize,
+    ) -> Result<(Vec<Message>, Option<String>)> {
+        anyhow::bail!("Incremental runs are only supported for Microsoft Graph mailboxes")
+    }
+
+    /// Move messages to the folder with the given ID
+    async fn move_messages(&self, messages: &[&Message], folder_id: &str) -> Result<BatchResult>;
+
+    /// Delete messages, keeping them recoverable (e.g. in a trash folder) where possible
+    async fn delete_messages(&self, messages: &[&Message]) -> Result<BatchResult>;
+
+    /// Mark messages as read or unread
+    async fn mark_read(&self, messages: &[&Message], read: bool) -> Result<BatchResult>;
+
+    /// Replace the categories of messages, each with its own list
+    async fn set_categories(&self, _updates: &[(&Message, Vec<String>)]) -> Result<BatchResult> {
+        anyhow::bail!("Categories are only supported for Microsoft Graph mailboxes")
+    }
+
+    /// Set the follow-up flag of messages. A due date is given relative to when each
+    /// message was received, and only applies to flagged messages. Without one, flagged
+    /// and completed messages keep the dates in their `flag_dates`, which is how undo
+    /// restores them.
+    async fn set_flag(
+        &self,
+        _messages: &[&Message],
+        _status: FlagStatus,
+        _due: Option<Duration>,
+    ) -> Result<BatchResult> {
+        anyhow::bail!("Follow-up flags are only supported for Microsoft Graph mailboxes")
+    }
+}
+
+/// A mail store that can be opened with `open`
+#[derive(Debug, Clone)]
+pub enum Store {
+    /// Microsoft Graph mailbox of the signed-in user, or another mailbox they can access
+    Graph { mailbox: Option<String> },
+    /// Local Maildir
+    Maildir(PathBuf),
+    /// IMAP account from the profile's settings
+    Imap(ImapSettings),
+}
+
+impl Store {
+    /// Mailbox or account name to show after "of" in progress messages, if any
+    pub fn label(&self) -> Option<String> {
+        match self {
+            Store::Graph { mailbox } => mailbox.clone(),
+            Store::Maildir(path) => Some(path.display().to_string()),
+            Store::Imap(settings) => Some(settings.account()),
+        }
+    }
+}
+
+/// Open a mail store, signing in to Microsoft Graph or the IMAP server as needed
+pub async fn open(store: &Store) -> Result<Box<dyn MailBackend>> {
+    match store {
+        Store::Maildir(path) => Ok(Box::new(Maildir::open(path)?)),
+        Store::Imap(settings) => Ok(Box::new(Imap::connect(settings).await?)),
+        Store::Graph { mailbox } => Ok(Box::new(graph_client(mailbox.as_deref()).await?)),
+    }
+}
+
+/// Sign in to Microsoft Graph with the cached token, for a mailbox other than the
+/// signed-in user's if given
+pub async fn graph_client(mailbox: Option<&str>) -> Result<GraphClient> {
+    let auth = Auth::new()?;
+    let token = auth.ensure_valid_token().await.map_err(|_| {
+        anyhow::anyhow!("You are not authenticated. Please run 'mailsweep auth login' first.")
+    })?;
+
+    Ok(GraphClient::new(token.access_token).with_mailbox(mailbox))
+}
+
+/// Map a folder name to its well-known folder name, accepting common aliases.
+/// The names are the ones Microsoft Graph uses.
+pub fn well_known_folder(name: &str) -> Option<&'static str> {
+    match name.trim().to_lowercase().as_str() {
+        "inbox" => Some("inbox"),
+        "junk" | "junkemail" | "junk email" | "junk e-mail" | "spam" => Some("junkemail"),
+        "sent" | "sentitems" | "sent items" => Some("sentitems"),
+        "archive" => Some("archive"),
+        "deleted" | "deleteditems" | "deleted items" | "trash" => Some("deleteditems"),
+        "drafts" => Some("drafts"),
+        "outbox" => Some("outbox"),
+        _ => None,
+    }
+}
+
+/// Folder names mail clients and servers use for well-known folders, preferred name first
+pub fn well_known_names(name: &str) -> &'static [&'static str] {
+    match name {
+        "junkemail" => &["Junk", "Spam", "Junk Email"],
+        "sentitems" => &["Sent", "Sent Items", "Sent Messages"],
+        "archive" => &["Archive", "Archives"],
+        "deleteditems" => &["Trash", "Deleted Items", "Deleted Messages"],
+        "drafts" => &["Drafts"],
+        "outbox" => &["Outbox"],
+        _ => &[],
+    }
+}
+
+/// Categories of a message after adding and removing some, compared case-insensitively.
+/// Existing categories keep their order and spelling; added ones go at the end.
+pub fn updated_categories(current: &[String], add: &[String], remove: &[String]) -> Vec<String> {
+    let mut categories: Vec<String> = current
+        .iter()
+        .filter(|c| !remove.iter().any(|r| r.trim().eq_ignore_ascii_case(c)))
+        .cloned()
+        .collect();
+
+    for category in add.iter().map(|c| c.trim()) {
+        if !categories.iter().any(|c| c.eq_ignore_ascii_case(category)) {
+            categories.push(category.to_string());
+        }
+    }
+
+    categories
+}
+
+/// Normalized form of a folder name or path, for comparing folders
+pub fn folder_key(folder: &str) -> String {
+    match well_known_folder(folder) {
+        Some(name) => name.to_string(),
+        None => folder
+            .split('/')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("/"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_updated_categories() {
+        let current = strings(&["Red", "Project X"]);
+
+        assert_eq!(
+            updated_categories(&current, &strings(&["project x", "Blue"]), &[]),
+            strings(&["Red", "Project X", "Blue"])
+        );
+        assert_eq!(
+            updated_categories(&current, &[], &strings(&["red", "Green"])),
+            strings(&["Project X"])
+        );
+        assert!(updated_categories(&[], &[], &strings(&["Red"])).is_empty());
+    }
+}
@@ -0,0 +1,494 @@
+use super::clean::{plan_folder_scans, select_store};
+use crate::backend::{self, ListOptions, Message};
+use crate::rules::{PatternSet, Rule, RuleAction, Rules, first_match};
+use anyhow::Result;
+use clap::Args;
+use inquire::{Confirm, MultiSelect, Select, Text};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Prefixes of replies and forwards, which are skipped when looking for a subject prefix
+const REPLY_PREFIXES: [&str; 5] = ["re:", "fw:", "fwd:", "aw:", "wg:"];
+
+#[derive(Debug, Args)]
+pub struct AnalyzeCommand {
+    /// Maximum number of messages to scan
+    #[arg(long, default_value_t = 500)]
+    limit: usize,
+
+    /// Number of messages to fetch per request (1-1000)
+    #[arg(long, default_value_t = 100)]
+    page_size: usize,
+
+    /// Number of senders, domains and subject prefixes to show
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+
+    /// Only show groups with at least this many messages
+    #[arg(long, default_value_t = 2)]
+    min_count: usize,
+
+    /// Only print the report, without offering to create rules
+    #[arg(long)]
+    report_only: bool,
+
+    /// Analyze a local Maildir instead of a Microsoft Graph mailbox or IMAP account
+    #[arg(long, value_name = "PATH")]
+    maildir: Option<PathBuf>,
+
+    /// Mailbox to analyze, e.g. a shared mailbox (overrides the mailbox in the rules file)
+    #[arg(long)]
+    mailbox: Option<String>,
+}
+
+/// What the messages of a group have in common
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum GroupKind {
+    Sender,
+    Domain,
+    SubjectPrefix,
+}
+
+impl GroupKind {
+    const ALL: [GroupKind; 3] = [
+        GroupKind::Sender,
+        GroupKind::Domain,
+        GroupKind::SubjectPrefix,
+    ];
+
+    fn heading(self) -> &'static str {
+        match self {
+            GroupKind::Sender => "Top senders",
+            GroupKind::Domain => "Top sender domains",
+            GroupKind::SubjectPrefix => "Recurring subject prefixes",
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            GroupKind::Sender => "Sender",
+            GroupKind::Domain => "Domain",
+            GroupKind::SubjectPrefix => "Prefix",
+        }
+    }
+}
+
+/// Messages sharing a sender, sender domain or subject prefix
+#[derive(Debug)]
+struct Group {
+    kind: GroupKind,
+    /// The sender address, domain or prefix, as first seen
+    key: String,
+    count: usize,
+    unread: usize,
+    /// Messages already matched by one of the existing rules
+    covered: usize,
+}
+
+impl Group {
+    fn unread_percent(&self) -> usize {
+        self.unread * 100 / self.count
+    }
+
+    fn rule_name(&self) -> String {
+        match self.kind {
+            GroupKind::Sender | GroupKind::Domain => format!("From {}", self.key),
+            GroupKind::SubjectPrefix => format!("Subject {}", self.key),
+        }
+    }
+
+    /// A rule matching the messages of the group, and no others with a similar sender
+    fn rule(&self, name: String, action: RuleAction) -> Rule {
+        let mut rule = Rule {
+            name,
+            action: Some(action),
+            ..Default::default()
+        };
+        match self.kind {
+            GroupKind::Sender => rule.sender_is = PatternSet::with_patterns(vec![self.key.clone()]),
+            // The address ends the sender text, also when it follows a display name
+            GroupKind::Domain => {
+                rule.sender_regex =
+                    PatternSet::with_patterns(vec![format!("@{}>?$", regex::escape(&self.key))])
+            }
+            GroupKind::SubjectPrefix => {
+                rule.subject_contains = PatternSet::with_patterns(vec![self.key.clone()])
+            }
+        }
+        rule
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({} messages, {}% unread)",
+            self.kind.column(),
+            self.key,
+            self.count,
+            self.unread_percent()
+        )
+    }
+}
+
+impl AnalyzeCommand {
+    pub async fn execute(&self) -> Result<()> {
+        let mut rules = Rules::load()?;
+
+        // Generated rules are added to the file, so it has to be valid
+        let validation_errors = rules.validate();
+        if !validation_errors.is_empty() {
+            anyhow::bail!(
+                "Rules have {} validation error(s). Run 'mailsweep rules check' for details.",
+                validation_errors.len()
+            );
+        }
+
+        // MS Graph API limit is 1000 messages per page
+        if !(1..=1000).contains(&self.page_size) {
+            anyhow::bail!("--page-size must be between 1 and 1000");
+        }
+        if self.limit == 0 {
+            anyhow::bail!("--limit must be at least 1");
+        }
+
+        let store = select_store(
+            self.maildir.clone(),
+            self.mailbox.clone(),
+            rules.mailbox.clone(),
+        )?;
+        let backend = backend::open(&store).await?;
+        let mailbox_label = match store.label() {
+            Some(label) => format!(" of {}", label),
+            None => String::new(),
+        };
+
+        println!("Fetching messages from inbox{}...", mailbox_label);
+        let inbox_id = backend.resolve_folder("inbox", false).await?;
+        let options = ListOptions {
+            limit: Some(self.limit),
+            page_size: self.page_size,
+            // No rules, so every message is fetched rather than only rule candidates
+            rules: &[],
+        };
+        let messages = backend.list_messages(&inbox_id, &options).await?;
+
+        if messages.is_empty() {
+            println!("No messages to analyze.");
+            return Ok(());
+        }
+
+        let unread = messages.iter().filter(|m| !m.is_read).count();
+        println!(
+            "Analyzed {} messages ({}% unread)",
+            messages.len(),
+            unread * 100 / messages.len()
+        );
+
+        // Show how much of each group the existing inbox rules already handle
+        let groups = {
+            let scans = plan_folder_scans(&rules, Some("inbox"));
+            let inbox_rules = scans.first().map(|s| s.rules.as_slice()).unwrap_or(&[]);
+            group_messages(&messages, inbox_rules)
+        };
+
+        let mut shown = Vec::new();
+        for kind in GroupKind::ALL {
+            let top: Vec<&Group> = groups
+                .iter()
+                .filter(|g| g.kind == kind && g.count >= self.min_count)
+                .take(self.top)
+                .collect();
+
+            println!("\n{}:", kind.heading());
+            if top.is_empty() {
+                println!("  (none with at least {} messages)", self.min_count);
+                continue;
+            }
+
+            println!(
+                "  {:<40} {:>8} {:>8} {:>8}",
+                kind.column(),
+                "Messages",
+                "Unread",
+                "Covered"
+            );
+            for group in &top {
+                println!(
+                    "  {:<40} {:>8} {:>7}% {:>8}",
+                    truncate(&group.key, 40),
+                    group.count,
+                    group.unread_percent(),
+                    group.covered
+                );
+            }
+            shown.extend(top);
+        }
+
+        // Offer rules for the groups the existing rules don't fully handle yet
+        let candidates: Vec<&Group> = shown.into_iter().filter(|g| g.covered < g.count).collect();
+        if self.report_only || candidates.is_empty() {
+            return Ok(());
+        }
+
+        println!();
+        let selected = MultiSelect::new("Select groups to create rules for:", candidates)
+            .with_page_size(15)
+            .prompt()?;
+        if selected.is_empty() {
+            println!("No rules created.");
+            return Ok(());
+        }
+
+        let mut new_rules: Vec<Rule> = Vec::new();
+        for group in selected {
+            let action = prompt_action(group)?;
+
+            // Keep rule names unique, as `rules check` warns about duplicates
+            let base_name = group.rule_name();
+            let mut name = base_name.clone();
+            let mut suffix = 2;
+            while rules
+                .items
+                .iter()
+                .chain(&new_rules)
+                .any(|r| r.name.eq_ignore_ascii_case(&name))
+            {
+                name = format!("{} ({})", base_name, suffix);
+                suffix += 1;
+            }
+
+            new_rules.push(group.rule(name, action));
+        }
+
+        println!("\n{}", serde_yaml::to_string(&new_rules)?);
+        let confirmed = Confirm::new(&format!(
+            "Add {} rule(s) to {}?",
+            new_rules.len(),
+            Rules::get_rules_path_str()?
+        ))
+        .with_default(true)
+        .prompt()?;
+        if !confirmed {
+            println!("No rules created.");
+            return Ok(());
+        }
+
+        let added = new_rules.len();
+        rules.items.extend(new_rules);
+        rules.save()?;
+        println!(
+            "✅ Added {} rule(s). Run 'mailsweep clean --dry-run' to see what they match.",
+            added
+        );
+        Ok(())
+    }
+}
+
+/// Ask what a generated rule should do with the messages of a group
+fn prompt_action(group: &Group) -> Result<RuleAction> {
+    let choice = Select::new(
+        &format!("Action for {}:", group.key),
+        vec!["archive", "mark_read", "move_to", "delete"],
+    )
+    .prompt()?;
+
+    Ok(match choice {
+        "archive" => RuleAction::Archive,
+        "mark_read" => RuleAction::MarkRead,
+        "delete" => RuleAction::Delete,
+        _ => {
+            let folder = Text::new("Folder to move to (e.g. \"Projects/Alpha\"):")
+                .with_validator(inquire::required!())
+                .prompt()?;
+            RuleAction::MoveTo(folder.trim().to_string())
+        }
+    })
+}
+
+/// Group messages by sender, sender domain and subject prefix, largest groups first.
+/// Keys are compared case-insensitively.
+fn group_messages(messages: &[Message], rules: &[&Rule]) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut index: HashMap<(GroupKind, String), usize> = HashMap::new();
+
+    for message in messages {
+        let covered = first_match(rules, message).is_some();
+
+        let address = if message.sender_address.is_empty() {
+            &message.sender
+        } else {
+            &message.sender_address
+        };
+        let keys = [
+            (GroupKind::Sender, Some(address.to_lowercase())),
+            (GroupKind::Domain, sender_domain(address)),
+            (GroupKind::SubjectPrefix, subject_prefix(&message.subject)),
+        ];
+
+        for (kind, key) in keys {
+            let Some(key) = key.filter(|k| !k.is_empty()) else {
+                continue;
+            };
+            let i = *index.entry((kind, key.to_lowercase())).or_insert_with(|| {
+                groups.push(Group {
+                    kind,
+                    key,
+                    count: 0,
+                    unread: 0,
+                    covered: 0,
+                });
+                groups.len() - 1
+            });
+
+            let group = &mut groups[i];
+            group.count += 1;
+            if !message.is_read {
+                group.unread += 1;
+            }
+            if covered {
+                group.covered += 1;
+            }
+        }
+    }
+
+    groups.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.key.to_lowercase().cmp(&b.key.to_lowercase()))
+    });
+    groups
+}
+
+/// Domain of an email address, in lowercase
+fn sender_domain(address: &str) -> Option<String> {
+    address
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim().to_lowercase())
+}
+
+/// A tag that starts a subject, like "[JIRA]" or "Invoice:", ignoring reply and forward
+/// prefixes. Only short prefixes without digits count, so "Meeting at 10:30" has none.
+fn subject_prefix(subject: &str) -> Option<String> {
+    let mut subject = subject.trim();
+    while let Some(prefix) = REPLY_PREFIXES.iter().find(|p| {
+        subject
+            .get(..p.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(p))
+    }) {
+        subject = subject[prefix.len()..].trim_start();
+    }
+
+    if subject.starts_with('[') {
+        let end = subject.find(']')?;
+        let tag = subject[1..end].trim();
+        return (!tag.is_empty() && tag.chars().count() <= 40).then(|| subject[..=end].to_string());
+    }
+
+    let (tag, _) = subject.split_once(':')?;
+    let words = tag.split_whitespace().count();
+    let plain = tag
+        .chars()
+        .all(|c| c.is_alphabetic() || c == ' ' || c == '-');
+    (plain && (1..=3).contains(&words)).then(|| format!("{}:", tag.trim()))
+}
+
+/// Shorten text to at most `width` characters
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let mut shortened: String = text.chars().take(width - 1).collect();
+        shortened.push('…');
+        shortened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sender: &str, subject: &str, is_read: bool) -> Message {
+        Message {
+            sender: sender.to_string(),
+            sender_address: sender.to_string(),
+            subject: subject.to_string(),
+            is_read,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_subject_prefix() {
+        assert_eq!(
+            subject_prefix("[JIRA] (PROJ-1) Bug").as_deref(),
+            Some("[JIRA]")
+        );
+        assert_eq!(
+            subject_prefix("RE: Fwd: [jira] Comment").as_deref(),
+            Some("[jira]")
+        );
+        assert_eq!(
+            subject_prefix("Invoice: March 2024").as_deref(),
+            Some("Invoice:")
+        );
+        assert_eq!(
+            subject_prefix("Build failed: main #42").as_deref(),
+            Some("Build failed:")
+        );
+        assert_eq!(subject_prefix("Meeting at 10:30"), None);
+        assert_eq!(subject_prefix("Re: Lunch?"), None);
+        assert_eq!(subject_prefix("[] Empty tag"), None);
+    }
+
+    #[test]
+    fn test_group_messages() {
+        let messages = vec![
+            message("jira@example.com", "[JIRA] One", false),
+            message("JIRA@example.com", "[JIRA] Two", false),
+            message("jira@example.com", "[Jira] Three", true),
+            message("boss@example.com", "Re: Plans", true),
+            message("news@other.org", "Weekly digest", false),
+        ];
+        let rules = Rules::parse("- name: Boss\n  sender_contains: [boss@]\n  action: mark_read\n")
+            .unwrap();
+        let rules: Vec<&Rule> = rules.items.iter().collect();
+
+        let groups = group_messages(&messages, &rules);
+        let find = |kind, key: &str| {
+            groups
+                .iter()
+                .find(|g| g.kind == kind && g.key == key)
+                .unwrap()
+        };
+
+        // Largest groups first
+        assert_eq!(groups[0].key, "example.com");
+        assert_eq!(groups[0].count, 4);
+        assert_eq!(groups[0].covered, 1);
+
+        let sender = find(GroupKind::Sender, "jira@example.com");
+        assert_eq!((sender.count, sender.unread), (3, 2));
+        assert_eq!(sender.unread_percent(), 66);
+
+        let prefix = find(GroupKind::SubjectPrefix, "[JIRA]");
+        assert_eq!(prefix.count, 3);
+
+        // Generated rules don't match look-alike senders
+        let rule = find(GroupKind::Domain, "other.org")
+            .rule("From other.org".to_string(), RuleAction::Archive);
+        assert_eq!(rule.sender_regex.to_vec(), vec![r"@other\.org>?$"]);
+        assert!(rule.matches(&messages[4]));
+        assert!(rule.matches(&message("News <news@other.org>", "Digest", false)));
+        assert!(!rule.matches(&message("news@other.org.evil.com", "Digest", false)));
+        assert!(!rule.matches(&message("news@another.org", "Digest", false)));
+
+        let rule = find(GroupKind::Sender, "jira@example.com")
+            .rule("From jira".to_string(), RuleAction::Delete);
+        assert_eq!(rule.sender_is.to_vec(), vec!["jira@example.com"]);
+        assert!(rule.matches(&messages[1]));
+        assert!(!rule.matches(&message("notjira@example.com", "[JIRA] One", false)));
+    }
+}
@@ -19,18 +19,21 @@ enum AuthCommands {
     /// Check authentication status
     Status,
 
+    /// List profiles that are logged in
+    List,
+
     /// Run diagnostic tests for authentication
     Debug,
 }
 
 impl AuthCommand {
     pub async fn execute(self) -> Result<()> {
-        let auth = Auth::new()?;
-
         match self.command {
-            AuthCommands::Login => auth.login().await,
-            AuthCommands::Logout => auth.logout(),
-            AuthCommands::Status => auth.check().await,
+            AuthCommands::Login => Auth::new()?.login().await,
+            AuthCommands::Logout => Auth::new()?.logout(),
+            AuthCommands::Status => Auth::new()?.check().await,
+            // Listing doesn't need the active profile's login
+            AuthCommands::List => Auth::list_profiles(),
             AuthCommands::Debug => crate::debug_auth::debug_auth()
                 .await
                 .map_err(|e| anyhow::anyhow!("{}", e)),
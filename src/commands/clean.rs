@@ -1,12 +1,15 @@
-use crate::auth::Auth;
-use crate::graph_client::{BatchOperation, GraphClient};
-use crate::rules::{RuleAction, Rules};
+use crate::backend::{self, FlagDates, ListOptions, Message, Store, folder_key};
+use crate::delta::DeltaLinks;
+use crate::journal::{Journal, JournalEntry};
+use crate::rules::{Rule, RuleAction, Rules, first_match};
 use anyhow::Result;
 use chrono::Utc;
-use clap::Args;
+use clap::{Args, ValueEnum};
 use inquire::Confirm;
+use serde::Serialize;
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use tabled::Tabled; // Keep only the Tabled derive
 use terminal_size::{Width as TermWidth, terminal_size};
 
@@ -26,94 +29,274 @@ struct MessageDisplay {
     received: String,
 }
 
-// Import Message from graph_client and use it directly
+// One matched message in machine-readable dry-run output
+#[derive(Serialize, Debug)]
+struct MatchRecord {
+    id: String,
+    sender: String,
+    subject: String,
+    received: String,
+    rule: String,
+    action: String,
+}
+
+/// Output format for listing matched messages
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct CleanCommand {
-    /// Maximum number of messages to process
-    #[arg(long)]
-    max_messages: Option<usize>,
+    /// Maximum number of messages to scan in total
+    #[arg(long, visible_alias = "max-messages")]
+    limit: Option<usize>,
+
+    /// Number of messages to fetch per request (1-1000)
+    #[arg(long, default_value_t = 50)]
+    page_size: usize,
 
     /// Process all matching messages without confirmation
     #[arg(long)]
     yes: bool,
+
+    /// Create folders used by move_to actions if they don't exist
+    #[arg(long)]
+    create_folders: bool,
+
+    /// Folder to clean, by well-known name (inbox, junk, sent, archive, deleted)
+    /// or display name path (e.g. "Projects/Alpha"), defaults to the inbox
+    #[arg(long)]
+    folder: Option<String>,
+
+    /// Also process messages in subfolders of the scanned folders
+    #[arg(long)]
+    recursive: bool,
+
+    /// Only process messages that are new or changed since the last incremental run
+    #[arg(long, conflicts_with = "limit")]
+    incremental: bool,
+
+    /// Clean a local Maildir instead of a Microsoft Graph mailbox or IMAP account
+    #[arg(long, value_name = "PATH")]
+    maildir: Option<PathBuf>,
+
+    /// Fetch all messages and match them locally instead of filtering on the server
+    #[arg(long)]
+    no_server_filter: bool,
+
+    /// Mailbox to clean, e.g. a shared mailbox (overrides the mailbox in the rules file)
+    #[arg(long)]
+    mailbox: Option<String>,
+
+    /// Show what would be done without changing any messages
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Output format for the matched messages (json and csv require --dry-run)
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
 }
 
 impl CleanCommand {
-    pub async fn execute(self) -> Result<()> {
-        // Load auth and rules
-        let auth = Auth::new()?;
-        let token = auth.ensure_valid_token().await.map_err(|_| {
-            anyhow::anyhow!("You are not authenticated. Please run 'mailsweep auth login' first.")
-        })?;
-        let rules = Rules::load()?;
-
-        // Create Microsoft Graph client
-        let graph_client = GraphClient::new(token.access_token);
+    /// Settings for scheduled runs: incremental scans and no confirmation prompt
+    pub fn unattended(&self) -> Result<Self> {
+        if self.limit.is_some() {
+            anyhow::bail!("--limit can't be used for scheduled runs, which are always incremental");
+        }
+        // Dry runs never advance the delta links, so every run would rescan the whole folder
+        if self.dry_run || self.output != OutputFormat::Table {
+            anyhow::bail!(
+                "--dry-run and --output can't be used for scheduled runs, which always apply actions"
+            );
+        }
 
-        // Default max messages per page (MS Graph API limit is 1000)
-        let per_page = self.max_messages.unwrap_or(50);
-        println!("Fetching messages from your inbox...");
+        Ok(Self {
+            yes: true,
+            incremental: true,
+            ..self.clone()
+        })
+    }
 
-        // If no rules are configured, prompt the user
-        if rules.items.is_empty() {
-            println!("⚠️ No rules configured. Use 'mailsweep rules edit' to add rules.");
+    /// Remember where incremental scans left off, once the run's messages have been dealt with
+    fn save_delta_links(
+        &self,
+        delta_links: &mut DeltaLinks,
+        new_links: Vec<(String, String)>,
+    ) -> Result<()> {
+        if !self.incremental || self.dry_run || new_links.is_empty() {
             return Ok(());
         }
 
-        // Get messages from inbox with pagination
-        let mut all_messages_json = Vec::new();
-        let mut next_link: Option<String>;
+        for (key, link) in new_links {
+            delta_links.set(key, link);
+        }
+        delta_links.save()
+    }
+
+    pub async fn execute(&self) -> Result<()> {
+        // Load rules
+        let rules = Rules::load()?;
+
+        // Refuse to run with broken rules (e.g. invalid regular expressions)
+        let validation_errors = rules.validate();
+        if !validation_errors.is_empty() {
+            anyhow::bail!(
+                "Rules have {} validation error(s). Run 'mailsweep rules check' for details.",
+                validation_errors.len()
+            );
+        }
+
+        let store = select_store(
+            self.maildir.clone(),
+            self.mailbox.clone(),
+            rules.mailbox.clone(),
+        )?;
+        let mailbox = match &store {
+            Store::Graph { mailbox } => mailbox.clone(),
+            _ => None,
+        };
+        let maildir = match &store {
+            Store::Maildir(path) => Some(path.clone()),
+            _ => None,
+        };
+        let imap_account = match &store {
+            Store::Imap(imap) => Some(imap.account()),
+            _ => None,
+        };
+
+        // Machine-readable output keeps stdout free of progress messages
+        let machine_output = self.output != OutputFormat::Table;
+        if machine_output && !self.dry_run {
+            anyhow::bail!("--output json and --output csv can only be used with --dry-run");
+        }
 
-        // First page
-        let (messages, next) = graph_client.fetch_messages_page(per_page, None).await?;
-        if !messages.is_empty() {
-            all_messages_json.extend(messages);
+        // MS Graph API limit is 1000 messages per page
+        if !(1..=1000).contains(&self.page_size) {
+            anyhow::bail!("--page-size must be between 1 and 1000");
+        }
+        if self.limit == Some(0) {
+            anyhow::bail!("--limit must be at least 1");
         }
-        next_link = next;
 
-        // Fetch subsequent pages if available
-        while let Some(link) = next_link {
-            let (messages, next) = graph_client
-                .fetch_messages_page(per_page, Some(&link))
-                .await?;
-            if !messages.is_empty() {
-                all_messages_json.extend(messages);
+        // If no rules are configured, prompt the user
+        if rules.items.is_empty() {
+            // Keep machine-readable output parseable, with no matches
+            if machine_output {
+                eprintln!("⚠️ No rules configured. Use 'mailsweep rules edit' to add rules.");
+                return print_match_records(&[], &self.output);
             }
-            next_link = next;
+            println!("⚠️ No rules configured. Use 'mailsweep rules edit' to add rules.");
+            return Ok(());
         }
 
-        if all_messages_json.is_empty() {
-            println!("No messages found in your inbox.");
+        // Work out which folders to scan and which rules apply in each
+        let scans = plan_folder_scans(&rules, self.folder.as_deref());
+        if scans.is_empty() && !machine_output {
+            println!("No rules apply to the selected folder.");
             return Ok(());
         }
 
+        // Open the selected mailbox
+        let backend = backend::open(&store).await?;
+        let mailbox_label = match store.label() {
+            Some(label) => format!(" of {}", label),
+            None => String::new(),
+        };
+
+        // Incremental runs continue from the delta links saved by the previous run
+        let mut delta_links = DeltaLinks::load()?;
+        let mut new_delta_links = Vec::new();
+
         // Process messages to find matches
         let mut messages = Vec::new();
+        let mut matched_ids = HashSet::new();
+        let mut scanned = 0;
+        let mut remaining = self.limit;
+
+        'scans: for scan in &scans {
+            let folder_id = backend.resolve_folder(&scan.folder, false).await?;
+
+            let mut folder_ids = vec![folder_id.clone()];
+            if self.recursive {
+                folder_ids.extend(backend.list_subfolders(&folder_id).await?);
+            }
+
+            if !machine_output {
+                println!("Fetching messages from {}{}...", scan.folder, mailbox_label);
+            }
+
+            for folder_id in &folder_ids {
+                let folder_messages = if self.incremental {
+                    let key = match &imap_account {
+                        Some(account) => {
+                            DeltaLinks::key(Some(&format!("imap:{}", account)), folder_id)
+                        }
+                        None => DeltaLinks::key(mailbox.as_deref(), folder_id),
+                    };
+                    let (folder_messages, delta_link) = backend
+                        .list_changed_messages(folder_id, delta_links.get(&key), self.page_size)
+                        .await?;
+                    if let Some(delta_link) = delta_link {
+                        new_delta_links.push((key, delta_link));
+                    }
+                    folder_messages
+                } else {
+                    let options = ListOptions {
+                        limit: remaining,
+                        page_size: self.page_size,
+                        // Lets the backend only fetch candidates for the folder's rules
+                        rules: if self.no_server_filter {
+                            &[]
+                        } else {
+                            &scan.rules
+                        },
+                    };
+                    backend.list_messages(folder_id, &options).await?
+                };
+                scanned += folder_messages.len();
+                remaining = remaining.map(|limit| limit - folder_messages.len());
+
+                for mut message in folder_messages {
+                    // A message reachable from several scans is only acted on once
+                    if matched_ids.contains(&message.id) {
+                        continue;
+                    }
+
+                    // The first rule for this folder that matches decides the actions
+                    if let Some(rule) = first_match(&scan.rules, &message) {
+                        message.matched_rule = Some(rule.name.clone());
+                        message.actions = rule.actions().to_vec();
+                    }
 
-        for msg_json in &all_messages_json {
-            let mut message = graph_client.parse_message(msg_json);
+                    // Only keep messages that matched a rule
+                    if message.matched_rule.is_some() {
+                        matched_ids.insert(message.id.clone());
+                        messages.push(message);
+                    }
+                }
 
-            // Check each rule
-            for rule in &rules.items {
-                // Use the Rule.matches method
-                if rule.matches(&message.sender, &message.subject) {
-                    message.matched_rule = Some(rule.name.clone());
-                    message.action = Some(rule.action.clone());
-                    break; // Stop processing rules for this message
+                if remaining == Some(0) {
+                    break 'scans;
                 }
             }
+        }
 
-            // Only keep messages that matched a rule
-            if message.matched_rule.is_some() {
-                messages.push(message);
+        if scanned == 0 && !machine_output {
+            if self.incremental {
+                println!("No new or changed messages found.");
+            } else {
+                println!("No messages found.");
             }
+            return self.save_delta_links(&mut delta_links, new_delta_links);
         }
 
         // Check if any messages matched rules
-        if messages.is_empty() {
+        if messages.is_empty() && !machine_output {
             println!("No messages matched your rules.");
-            return Ok(());
+            return self.save_delta_links(&mut delta_links, new_delta_links);
         }
 
         // First sort messages by rule name for grouping
@@ -128,225 +311,18 @@ impl CleanCommand {
             }
         });
 
-        // Create table data
-        let mut table_data = Vec::new();
-
-        for msg in &messages {
-            // Get a nice human-readable action name with emoji
-            let action_str = match msg.action.as_ref().unwrap() {
-                // Use fixed-width emojis with proper spacing
-                RuleAction::Archive => "📥 Archive ",
-                RuleAction::Delete => "🗑️ Delete  ",
-                RuleAction::MarkRead => "👁️ Mark Read",
-            };
-
-            // Format the received date as a relative time
-            let now = Utc::now();
-            let diff = now.signed_duration_since(msg.received_date);
-
-            let received_relative = if diff.num_days() > 0 {
-                format!("{} days ago", diff.num_days())
-            } else if diff.num_hours() > 0 {
-                format!("{} hours ago", diff.num_hours())
-            } else if diff.num_minutes() > 0 {
-                format!("{} minutes ago", diff.num_minutes())
-            } else {
-                "just now".to_string()
-            };
-
-            // Add colored action
-            let action_with_color = match msg.action.as_ref().unwrap() {
-                RuleAction::Archive => format!("\x1b[34m{}\x1b[0m", action_str),
-                RuleAction::Delete => format!("\x1b[31m{}\x1b[0m", action_str),
-                RuleAction::MarkRead => format!("\x1b[32m{}\x1b[0m", action_str),
-            };
-
-            table_data.push(MessageDisplay {
-                action: action_with_color,
-                sender: msg.sender.clone(),
-                subject: msg.subject.clone(),
-                received: received_relative,
-            });
-        }
-
-        // Calculate an appropriate width for the table based on terminal size
-        let term_width = match terminal_size() {
-            Some((TermWidth(w), _)) => {
-                // For very wide terminals, don't use the full width
-                if w > 200 {
-                    180
-                } else {
-                    max(80, w as usize - 5) // Leave minimal padding
-                }
-            }
-            None => 100, // Default width if terminal size can't be determined
-        };
-
-        // Display the count message before the table
-        println!("\n\x1b[1;36m{} matching messages:\x1b[0m\n", messages.len());
-
-        // Define fixed column widths
-        let action_width = 15; // Fixed width for action column
-        let received_width = 15; // Fixed width for received column
-
-        // Calculate dynamic widths based on percentage of available space
-        // Reserve space for spacing between columns (3 spaces between each column × 3 gaps)
-        let available_width = term_width - 9;
-
-        // For very wide terminals, use a reasonable width for the sender column
-        let sender_width = if term_width > 160 {
-            45 // Fixed width for very wide terminals
-        } else {
-            // Otherwise use a percentage of available width
-            (available_width as f32 * 0.3) as usize // 30% of available width
-        };
-
-        // Ensure subject gets remaining space but has a minimum width
-        let subject_width = max(
-            30,
-            available_width - action_width - received_width - sender_width,
-        );
-
-        // Create table borders with appropriate width
-        let header_border = "-".repeat(term_width);
-
-        // Print header with proper spacing and alignment
-        println!("{}", header_border);
-        println!(
-            "\x1b[1;34m{:<action_width$}\x1b[0m   \x1b[1;32m{:<sender_width$}\x1b[0m   \x1b[1;33m{:<subject_width$}\x1b[0m   \x1b[1;31m{:<received_width$}\x1b[0m",
-            "Action", "Sender", "Subject", "Received"
-        );
-        println!("{}", header_border);
-
-        // Track the current rule group to know when to print a group header
-        let mut current_rule: Option<&str> = None;
-
-        // Loop through messages to display them grouped by rule
-        for (i, msg) in messages.iter().enumerate() {
-            // Check if we're starting a new rule group
-            let rule_name = msg.matched_rule.as_ref().unwrap();
-
-            // If this is a new rule group or the first message
-            if current_rule.is_none() || current_rule != Some(rule_name) {
-                // Print the rule group header centered
-                let rule_display = format!(" {} ", rule_name);
-                let padding = term_width.saturating_sub(rule_display.len()) / 2;
-                let centered_header = format!(
-                    "{}{}{}",
-                    "·".repeat(padding),
-                    rule_display,
-                    "·".repeat(padding)
-                );
-                println!("\x1b[1;36m{}\x1b[0m", centered_header);
-
-                // Update current rule
-                current_rule = Some(rule_name);
-            }
-
-            // Get the corresponding table data row
-            let table_row = &table_data[i];
-
-            // Strip ANSI escape codes for width calculation
-            let mut action_visible = String::new();
-            let mut in_escape = false;
-
-            for c in table_row.action.chars() {
-                if c == '\x1b' {
-                    in_escape = true;
-                    continue;
-                }
-
-                if in_escape {
-                    if c == 'm' {
-                        in_escape = false;
-                    }
-                    continue;
-                }
-
-                action_visible.push(c);
-            }
-
-            // Account for emoji width (each emoji typically counts as 2 char width)
-            // Calculate padded action string
-            let mut action_padded = table_row.action.clone();
-
-            // Count emojis (simplistic approach - just counts emoji-like characters)
-            let emoji_count = action_visible
-                .chars()
-                .filter(|&c| {
-                    ('\u{1F300}'..='\u{1F6FF}').contains(&c)
-                        || ('\u{2600}'..='\u{26FF}').contains(&c)
-                })
-                .count();
-
-            // Adjust visible length to account for emoji width (each emoji is 1 char but often displays as 2 width)
-            let visible_len = action_visible.chars().count() + emoji_count;
-
-            let action_display_padding = action_width.saturating_sub(visible_len);
-            if action_display_padding > 0 {
-                action_padded.push_str(&" ".repeat(action_display_padding));
-            }
+        // Machine-readable dry runs print the matches and stop here
+        if machine_output {
+            return print_match_records(&messages, &self.output);
+        }
 
-            // Calculate display width for sender
-            let sender_chars = table_row.sender.chars().count();
-            let sender_display = if sender_chars > sender_width {
-                let mut shortened_sender = String::new();
+        print_messages_table(&messages);
 
-                for (i, c) in table_row.sender.chars().enumerate() {
-                    // Leave space for the ellipsis (3 chars)
-                    if i >= sender_width - 3 {
-                        break;
-                    }
-                    shortened_sender.push(c);
-                }
-                format!("{}...", shortened_sender)
-            } else {
-                format!("{:<sender_width$}", table_row.sender)
-            };
-
-            // Calculate display width for subject
-            let subject_chars = table_row.subject.chars().count();
-            let subject_display = if subject_chars > subject_width {
-                let mut shortened_subject = String::new();
-
-                for (i, c) in table_row.subject.chars().enumerate() {
-                    // Leave space for the ellipsis (3 chars)
-                    if i >= subject_width - 3 {
-                        break;
-                    }
-                    shortened_subject.push(c);
-                }
-                format!("{}...", shortened_subject)
-            } else {
-                format!("{:<subject_width$}", table_row.subject)
-            };
-
-            // Calculate display width for received
-            let received_chars = table_row.received.chars().count();
-            let received_display = if received_chars > received_width {
-                let mut shortened_received = String::new();
-
-                for (i, c) in table_row.received.chars().enumerate() {
-                    // Leave space for the ellipsis (3 chars)
-                    if i >= received_width - 3 {
-                        break;
-                    }
-                    shortened_received.push(c);
-                }
-                format!("{}...", shortened_received)
-            } else {
-                format!("{:<received_width$}", table_row.received)
-            };
-
-            // Print the row with fixed-width separators
-            println!(
-                "{}   {}   {}   {}",
-                action_padded, sender_display, subject_display, received_display
-            );
+        if self.dry_run {
+            println!("Dry run: no changes made.");
+            return Ok(());
         }
 
-        println!("{}\n", header_border);
-
         // Ask for confirmation unless --yes flag is used
         let proceed = if self.yes {
             true
@@ -367,75 +343,198 @@ impl CleanCommand {
         // Process the messages using batch requests
         println!("Processing messages...");
 
-        // Group messages by action type
-        let mut archive_messages = Vec::new();
-        let mut delete_messages = Vec::new();
-        let mut mark_read_messages = Vec::new();
-
-        for message in &messages {
-            match message.action.as_ref().unwrap() {
-                RuleAction::Archive => archive_messages.push(message),
-                RuleAction::Delete => delete_messages.push(message),
-                RuleAction::MarkRead => mark_read_messages.push(message),
-            }
-        }
+        // Record every applied action so the run can be undone
+        let journal = Journal::open()?;
+        let run_id = Journal::new_run_id();
 
         // Use batch requests to process messages in parallel
-        let mut batch_results = Vec::new();
-
-        // Process archive messages if any
-        if !archive_messages.is_empty() {
-            let result = graph_client
-                .process_messages_batch(&archive_messages, BatchOperation::Archive)
-                .await;
-
-            batch_results.push((result, "archive"));
-        }
-
-        // Process delete messages if any
-        if !delete_messages.is_empty() {
-            let result = graph_client
-                .process_messages_batch(&delete_messages, BatchOperation::Delete)
-                .await;
-
-            batch_results.push((result, "delete"));
-        }
-
-        // Process mark read messages if any
-        if !mark_read_messages.is_empty() {
-            let result = graph_client
-                .process_messages_batch(&mark_read_messages, BatchOperation::MarkRead)
-                .await;
-
-            batch_results.push((result, "mark read"));
-        }
-
-        // Collect results by action type
-        let mut action_counts = HashMap::new();
+        let mut action_counts: HashMap<String, usize> = HashMap::new();
         let mut failed = 0;
 
-        for (result, operation) in batch_results {
-            match result {
-                Ok(stats) => {
-                    // Add the successful operations to the counts
-                    match operation {
-                        "archive" => *action_counts.entry("archived").or_insert(0) += stats.0,
-                        "delete" => *action_counts.entry("deleted").or_insert(0) += stats.0,
-                        "mark read" => {
-                            *action_counts.entry("marked as read").or_insert(0) += stats.0
+        // Rules with several actions are applied in steps: first action of every message,
+        // then the second, and so on. Moves assign new IDs, so the messages are updated
+        // as they go, and a message whose action failed gets no further actions.
+        let mut current = messages.clone();
+        let mut active = vec![true; current.len()];
+
+        for step in 0.. {
+            // Group messages by action, keeping the order in which actions first appear
+            let mut groups: Vec<(RuleAction, Vec<usize>)> = Vec::new();
+            for (i, message) in current.iter().enumerate() {
+                let Some(action) = message.actions.get(step).filter(|_| active[i]) else {
+                    continue;
+                };
+                match groups.iter_mut().find(|(a, _)| a == action) {
+                    Some((_, group)) => group.push(i),
+                    None => groups.push((action.clone(), vec![i])),
+                }
+            }
+            if groups.is_empty() {
+                break;
+            }
+
+            for (action, indexes) in groups {
+                let label = match &action {
+                    RuleAction::Archive => "archived".to_string(),
+                    RuleAction::Delete => "deleted".to_string(),
+                    RuleAction::MarkRead => "marked as read".to_string(),
+                    RuleAction::MoveTo(path) => format!("moved to {}", path),
+                    RuleAction::AddCategories(categories) => {
+                        format!("categorized as {}", categories.join(", "))
+                    }
+                    RuleAction::RemoveCategories(categories) => {
+                        format!("removed from {}", categories.join(", "))
+                    }
+                    RuleAction::Flag => "flagged".to_string(),
+                    RuleAction::FlagDue(due) => format!("flagged, due {}", due),
+                    RuleAction::CompleteFlag => "marked complete".to_string(),
+                    RuleAction::ClearFlag => "unflagged".to_string(),
+                };
+                let group: Vec<&Message> = indexes.iter().map(|&i| &current[i]).collect();
+
+                // Folder the messages end up in, for moves
+                let mut destination = None;
+                // Categories the messages end up with, by message ID, for category actions
+                let mut updated_categories: HashMap<String, Vec<String>> = HashMap::new();
+                let result = match &action {
+                    RuleAction::Archive | RuleAction::MoveTo(_) => {
+                        let (path, create_missing) = match &action {
+                            RuleAction::MoveTo(path) => (path.as_str(), self.create_folders),
+                            _ => ("archive", true),
+                        };
+
+                        // Resolve the destination folder before moving anything
+                        match backend.resolve_folder(path, create_missing).await {
+                            Ok(folder_id) => {
+                                let result = backend.move_messages(&group, &folder_id).await;
+                                destination = Some(folder_id);
+                                result
+                            }
+                            Err(e) => {
+                                failed += group.len();
+                                eprintln!("Error resolving folder '{}': {}", path, e);
+                                for &i in &indexes {
+                                    active[i] = false;
+                                }
+                                continue;
+                            }
                         }
-                        _ => *action_counts.entry(operation).or_insert(0) += stats.0,
                     }
-
-                    // Report any failures by operation type
-                    if stats.1 > 0 {
-                        failed += stats.1;
-                        println!("  {} operation: {} failed", operation, stats.1);
+                    RuleAction::Delete => backend.delete_messages(&group).await,
+                    RuleAction::MarkRead => backend.mark_read(&group, true).await,
+                    RuleAction::AddCategories(categories)
+                    | RuleAction::RemoveCategories(categories) => {
+                        let (add, remove) = match &action {
+                            RuleAction::AddCategories(_) => (categories.as_slice(), &[][..]),
+                            _ => (&[][..], categories.as_slice()),
+                        };
+                        let updates: Vec<(&Message, Vec<String>)> = group
+                            .iter()
+                            .map(|m| (*m, backend::updated_categories(&m.categories, add, remove)))
+                            .collect();
+                        let result = backend.set_categories(&updates).await;
+                        updated_categories = updates
+                            .into_iter()
+                            .map(|(m, categories)| (m.id.clone(), categories))
+                            .collect();
+                        result
+                    }
+                    RuleAction::Flag
+                    | RuleAction::FlagDue(_)
+                    | RuleAction::CompleteFlag
+                    | RuleAction::ClearFlag => {
+                        // Due dates were checked when the rules were validated
+                        let due = match &action {
+                            RuleAction::FlagDue(due) => crate::rules::parse_due(due).ok(),
+                            _ => None,
+                        };
+                        let status = action.flag_status().unwrap_or_default();
+                        backend.set_flag(&group, status, due).await
+                    }
+                };
+
+                let result = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        failed += group.len();
+                        eprintln!("Error processing {} batch: {}", label, e);
+                        for &i in &indexes {
+                            active[i] = false;
+                        }
+                        continue;
                     }
+                };
+
+                // Add the successful operations to the counts
+                *action_counts.entry(label.clone()).or_insert(0) += result.succeeded.len();
+
+                let by_id: HashMap<&str, &Message> =
+                    group.iter().map(|m| (m.id.as_str(), *m)).collect();
+                let timestamp = Utc::now();
+                let entries: Vec<JournalEntry> = result
+                    .succeeded
+                    .iter()
+                    .filter_map(|(old_id, new_id)| {
+                        by_id.get(old_id.as_str()).map(|message| JournalEntry {
+                            run_id: run_id.clone(),
+                            timestamp,
+                            mailbox: mailbox.clone(),
+                            maildir: maildir.clone(),
+                            imap: imap_account.clone(),
+                            message_id: old_id.clone(),
+                            current_id: new_id.clone(),
+                            original_folder: message.folder_id.clone(),
+                            action: action.clone(),
+                            step,
+                            categories: updated_categories
+                                .contains_key(old_id)
+                                .then(|| message.categories.clone()),
+                            flag_status: action.flag_status().map(|_| message.flag_status),
+                            flag_dates: action.flag_status().and(message.flag_dates),
+                            is_read: (action == RuleAction::MarkRead).then_some(message.is_read),
+                            undone: false,
+                        })
+                    })
+                    .collect();
+
+                if let Err(e) = journal.append(&entries) {
+                    eprintln!("Warning: failed to write undo journal: {}", e);
+                }
+
+                // Report any failures by operation type
+                if result.failed > 0 {
+                    failed += result.failed;
+                    println!("  {} operation: {} failed", label, result.failed);
                 }
-                Err(e) => {
-                    failed += 1; // At least one failure
-                    eprintln!("Error processing {} batch: {}", operation, e);
+
+                // Later actions apply to the messages where they are now
+                let new_ids: HashMap<String, String> = result.succeeded.into_iter().collect();
+                for i in indexes {
+                    match new_ids.get(&current[i].id) {
+                        Some(new_id) => {
+                            if let Some(categories) = updated_categories.remove(&current[i].id) {
+                                current[i].categories = categories;
+                            }
+                            if let Some(status) = action.flag_status() {
+                                current[i].flag_status = status;
+                                current[i].flag_dates = match &action {
+                                    RuleAction::FlagDue(due) => crate::rules::parse_due(due)
+                                        .ok()
+                                        .map(|due| FlagDates::after_received(&current[i], due)),
+                                    RuleAction::ClearFlag => None,
+                                    _ => current[i].flag_dates,
+                                };
+                            }
+                            if action == RuleAction::MarkRead {
+                                current[i].is_read = true;
+                            }
+                            current[i].id = new_id.clone();
+                            if let Some(folder_id) = &destination {
+                                current[i].folder_id = folder_id.clone();
+                            }
+                        }
+                        None => active[i] = false,
+                    }
                 }
             }
         }
@@ -469,18 +568,480 @@ impl CleanCommand {
 
         println!("\nCompleted: {}", summary);
 
-        Ok(())
+        if total_processed > 0 {
+            println!(
+                "Run ID: {} (use 'mailsweep undo' to revert this run)",
+                run_id
+            );
+        }
+
+        // Failed messages would be skipped by the next incremental run, so rescan them
+        if failed > 0 {
+            if self.incremental {
+                println!(
+                    "Some actions failed; the next incremental run will rescan these folders."
+                );
+            }
+            return Ok(());
+        }
+
+        self.save_delta_links(&mut delta_links, new_delta_links)
+    }
+}
+
+/// Pick the mail store from the command line and the profile's settings: a local Maildir
+/// or an IMAP account takes the place of the Microsoft Graph mailbox
+pub(super) fn select_store(
+    maildir: Option<PathBuf>,
+    mailbox: Option<String>,
+    rules_mailbox: Option<String>,
+) -> Result<Store> {
+    let settings = crate::config::settings();
+    let store = match (maildir.or(settings.maildir.clone()), &settings.imap) {
+        // Journal entries need to find the Maildir again from any directory
+        (Some(path), _) => Store::Maildir(
+            path.canonicalize()
+                .map_err(|e| anyhow::anyhow!("Can't open Maildir '{}': {}", path.display(), e))?,
+        ),
+        (None, Some(imap)) => Store::Imap(imap.clone()),
+        (None, None) => Store::Graph {
+            mailbox: mailbox.clone().or(rules_mailbox),
+        },
+    };
+    if mailbox.is_some() && !matches!(store, Store::Graph { .. }) {
+        anyhow::bail!("--mailbox can only be used with Microsoft Graph mailboxes");
+    }
+    Ok(store)
+}
+
+/// A folder to scan, with the rules that apply to its messages in order
+pub(super) struct FolderScan<'a> {
+    pub folder: String,
+    pub rules: Vec<&'a Rule>,
+}
+
+/// Group rules by the folder they apply to. Rules without a folder apply to the
+/// inbox; when a folder is given explicitly, only rules for that folder are used
+/// (so rules without a folder only when it is the inbox).
+pub(super) fn plan_folder_scans<'a>(rules: &'a Rules, folder: Option<&str>) -> Vec<FolderScan<'a>> {
+    let first_folder = folder.unwrap_or("inbox");
+    let mut scans = vec![FolderScan {
+        folder: first_folder.to_string(),
+        rules: Vec::new(),
+    }];
+
+    for rule in &rules.items {
+        let rule_folder = rule.folder.as_deref().unwrap_or("inbox");
+        if folder.is_some() && folder_key(rule_folder) != folder_key(first_folder) {
+            continue;
+        }
+
+        match scans
+            .iter_mut()
+            .find(|scan| folder_key(&scan.folder) == folder_key(rule_folder))
+        {
+            Some(scan) => scan.rules.push(rule),
+            None => scans.push(FolderScan {
+                folder: rule_folder.to_string(),
+                rules: vec![rule],
+            }),
+        }
+    }
+
+    scans.retain(|scan| !scan.rules.is_empty());
+    scans
+}
+
+/// Print matched messages as a table grouped by rule
+fn print_messages_table(messages: &[Message]) {
+    // Create table data
+    let mut table_data = Vec::new();
+
+    for msg in messages {
+        // Format the received date as a relative time
+        let now = Utc::now();
+        let diff = now.signed_duration_since(msg.received_date);
+
+        let received_relative = if diff.num_days() > 0 {
+            format!("{} days ago", diff.num_days())
+        } else if diff.num_hours() > 0 {
+            format!("{} hours ago", diff.num_hours())
+        } else if diff.num_minutes() > 0 {
+            format!("{} minutes ago", diff.num_minutes())
+        } else {
+            "just now".to_string()
+        };
+
+        // Add colored actions, in the order they are applied
+        let action_with_color = msg
+            .actions
+            .iter()
+            .map(action_cell)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        table_data.push(MessageDisplay {
+            action: action_with_color,
+            sender: msg.sender.clone(),
+            subject: msg.subject.clone(),
+            received: received_relative,
+        });
+    }
+
+    // Calculate an appropriate width for the table based on terminal size
+    let term_width = match terminal_size() {
+        Some((TermWidth(w), _)) => {
+            // For very wide terminals, don't use the full width
+            if w > 200 {
+                180
+            } else {
+                max(80, w as usize - 5) // Leave minimal padding
+            }
+        }
+        None => 100, // Default width if terminal size can't be determined
+    };
+
+    // Display the count message before the table
+    println!("\n\x1b[1;36m{} matching messages:\x1b[0m\n", messages.len());
+
+    // Define fixed column widths
+    let action_width = 15; // Fixed width for action column
+    let received_width = 15; // Fixed width for received column
+
+    // Calculate dynamic widths based on percentage of available space
+    // Reserve space for spacing between columns (3 spaces between each column × 3 gaps)
+    let available_width = term_width - 9;
+
+    // For very wide terminals, use a reasonable width for the sender column
+    let sender_width = if term_width > 160 {
+        45 // Fixed width for very wide terminals
+    } else {
+        // Otherwise use a percentage of available width
+        (available_width as f32 * 0.3) as usize // 30% of available width
+    };
+
+    // Ensure subject gets remaining space but has a minimum width
+    let subject_width = max(
+        30,
+        available_width - action_width - received_width - sender_width,
+    );
+
+    // Create table borders with appropriate width
+    let header_border = "-".repeat(term_width);
+
+    // Print header with proper spacing and alignment
+    println!("{}", header_border);
+    println!(
+        "\x1b[1;34m{:<action_width$}\x1b[0m   \x1b[1;32m{:<sender_width$}\x1b[0m   \x1b[1;33m{:<subject_width$}\x1b[0m   \x1b[1;31m{:<received_width$}\x1b[0m",
+        "Action", "Sender", "Subject", "Received"
+    );
+    println!("{}", header_border);
+
+    // Track the current rule group to know when to print a group header
+    let mut current_rule: Option<&str> = None;
+
+    // Loop through messages to display them grouped by rule
+    for (i, msg) in messages.iter().enumerate() {
+        // Check if we're starting a new rule group
+        let rule_name = msg.matched_rule.as_ref().unwrap();
+
+        // If this is a new rule group or the first message
+        if current_rule.is_none() || current_rule != Some(rule_name) {
+            // Print the rule group header centered
+            let rule_display = format!(" {} ", rule_name);
+            let padding = term_width.saturating_sub(rule_display.len()) / 2;
+            let centered_header = format!(
+                "{}{}{}",
+                "·".repeat(padding),
+                rule_display,
+                "·".repeat(padding)
+            );
+            println!("\x1b[1;36m{}\x1b[0m", centered_header);
+
+            // Update current rule
+            current_rule = Some(rule_name);
+        }
+
+        // Get the corresponding table data row
+        let table_row = &table_data[i];
+
+        // Strip ANSI escape codes for width calculation
+        let mut action_visible = String::new();
+        let mut in_escape = false;
+
+        for c in table_row.action.chars() {
+            if c == '\x1b' {
+                in_escape = true;
+                continue;
+            }
+
+            if in_escape {
+                if c == 'm' {
+                    in_escape = false;
+                }
+                continue;
+            }
+
+            action_visible.push(c);
+        }
+
+        // Account for emoji width (each emoji typically counts as 2 char width)
+        // Calculate padded action string
+        let mut action_padded = table_row.action.clone();
+
+        // Count emojis (simplistic approach - just counts emoji-like characters)
+        let emoji_count = action_visible
+            .chars()
+            .filter(|&c| {
+                ('\u{1F300}'..='\u{1F6FF}').contains(&c) || ('\u{2600}'..='\u{26FF}').contains(&c)
+            })
+            .count();
+
+        // Adjust visible length to account for emoji width (each emoji is 1 char but often displays as 2 width)
+        let visible_len = action_visible.chars().count() + emoji_count;
+
+        let action_display_padding = action_width.saturating_sub(visible_len);
+        if action_display_padding > 0 {
+            action_padded.push_str(&" ".repeat(action_display_padding));
+        }
+
+        // Calculate display width for sender
+        let sender_chars = table_row.sender.chars().count();
+        let sender_display = if sender_chars > sender_width {
+            let mut shortened_sender = String::new();
+
+            for (i, c) in table_row.sender.chars().enumerate() {
+                // Leave space for the ellipsis (3 chars)
+                if i >= sender_width - 3 {
+                    break;
+                }
+                shortened_sender.push(c);
+            }
+            format!("{}...", shortened_sender)
+        } else {
+            format!("{:<sender_width$}", table_row.sender)
+        };
+
+        // Calculate display width for subject
+        let subject_chars = table_row.subject.chars().count();
+        let subject_display = if subject_chars > subject_width {
+            let mut shortened_subject = String::new();
+
+            for (i, c) in table_row.subject.chars().enumerate() {
+                // Leave space for the ellipsis (3 chars)
+                if i >= subject_width - 3 {
+                    break;
+                }
+                shortened_subject.push(c);
+            }
+            format!("{}...", shortened_subject)
+        } else {
+            format!("{:<subject_width$}", table_row.subject)
+        };
+
+        // Calculate display width for received
+        let received_chars = table_row.received.chars().count();
+        let received_display = if received_chars > received_width {
+            let mut shortened_received = String::new();
+
+            for (i, c) in table_row.received.chars().enumerate() {
+                // Leave space for the ellipsis (3 chars)
+                if i >= received_width - 3 {
+                    break;
+                }
+                shortened_received.push(c);
+            }
+            format!("{}...", shortened_received)
+        } else {
+            format!("{:<received_width$}", table_row.received)
+        };
+
+        // Print the row with fixed-width separators
+        println!(
+            "{}   {}   {}   {}",
+            action_padded, sender_display, subject_display, received_display
+        );
     }
+
+    println!("{}\n", header_border);
+}
+
+/// Colored label of an action for the table, with an emoji
+fn action_cell(action: &RuleAction) -> String {
+    // Get a nice human-readable action name with emoji
+    let action_str = match action {
+        // Use fixed-width emojis with proper spacing
+        RuleAction::Archive => "📥 Archive ".to_string(),
+        RuleAction::Delete => "🗑️ Delete  ".to_string(),
+        RuleAction::MarkRead => "👁️ Mark Read".to_string(),
+        RuleAction::MoveTo(path) => {
+            // Show the destination folder name, truncated to fit the column
+            let folder = path
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or(path);
+            format!("📁 {}", folder.chars().take(11).collect::<String>())
+        }
+        RuleAction::AddCategories(categories) => {
+            let names = categories.join(",");
+            format!("🏷️ +{}", names.chars().take(10).collect::<String>())
+        }
+        RuleAction::RemoveCategories(categories) => {
+            let names = categories.join(",");
+            format!("🏷️ -{}", names.chars().take(10).collect::<String>())
+        }
+        RuleAction::Flag | RuleAction::FlagDue(_) => "🚩 Flag     ".to_string(),
+        RuleAction::CompleteFlag => "✅ Complete ".to_string(),
+        RuleAction::ClearFlag => "🏳️ Unflag   ".to_string(),
+    };
+
+    match action {
+        RuleAction::Archive => format!("\x1b[34m{}\x1b[0m", action_str),
+        RuleAction::Delete => format!("\x1b[31m{}\x1b[0m", action_str),
+        RuleAction::MarkRead => format!("\x1b[32m{}\x1b[0m", action_str),
+        RuleAction::MoveTo(_) => format!("\x1b[35m{}\x1b[0m", action_str),
+        RuleAction::AddCategories(_) | RuleAction::RemoveCategories(_) => {
+            format!("\x1b[33m{}\x1b[0m", action_str)
+        }
+        RuleAction::Flag
+        | RuleAction::FlagDue(_)
+        | RuleAction::CompleteFlag
+        | RuleAction::ClearFlag => format!("\x1b[36m{}\x1b[0m", action_str),
+    }
+}
+
+/// Print matched messages in a machine-readable format
+fn print_match_records(messages: &[Message], format: &OutputFormat) -> Result<()> {
+    let records: Vec<MatchRecord> = messages
+        .iter()
+        .map(|msg| MatchRecord {
+            id: msg.id.clone(),
+            sender: msg.sender.clone(),
+            subject: msg.subject.clone(),
+            received: msg.received_date.to_rfc3339(),
+            rule: msg.matched_rule.clone().unwrap_or_default(),
+            action: msg
+                .actions
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+        .collect();
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&records)?);
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            writer.write_record(["id", "sender", "subject", "received", "rule", "action"])?;
+            for record in &records {
+                writer.write_record([
+                    &record.id,
+                    &record.sender,
+                    &record.subject,
+                    &record.received,
+                    &record.rule,
+                    &record.action,
+                ])?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Table => print_messages_table(messages),
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::rules::{PatternSet, Rule};
+    use crate::rules::PatternSet;
 
     // Use the Rule's matches method
     fn matches_rule(rule: &Rule, sender: &str, subject: &str) -> bool {
-        rule.matches(sender, subject)
+        let message = Message {
+            id: "1".to_string(),
+            subject: subject.to_string(),
+            sender: sender.to_string(),
+            received_date: Utc::now(),
+            ..Default::default()
+        };
+        rule.matches(&message)
+    }
+
+    #[test]
+    fn test_unattended() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            clean: CleanCommand,
+        }
+        let parse = |args: &[&str]| {
+            <Cli as clap::Parser>::parse_from(std::iter::once("watch").chain(args.iter().copied()))
+                .clean
+                .unattended()
+        };
+
+        let clean = parse(&["--folder", "junk"]).unwrap();
+        assert!(clean.yes && clean.incremental);
+
+        for args in [
+            &["--limit", "10"][..],
+            &["--dry-run"],
+            &["--dry-run", "--output", "json"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_plan_folder_scans() {
+        let rule = |name: &str, folder: Option<&str>| Rule {
+            name: name.to_string(),
+            subject_contains: PatternSet::with_patterns(vec!["x".to_string()]),
+            folder: folder.map(str::to_string),
+            ..Default::default()
+        };
+        let rules = Rules {
+            items: vec![
+                rule("Inbox rule", None),
+                rule("Junk rule", Some("Junk")),
+                rule("Junk rule 2", Some("junkemail")),
+                rule("Sent rule", Some("Sent Items")),
+            ],
+            ..Default::default()
+        };
+
+        // Unscoped rules go to the inbox; aliases of the same folder are grouped
+        let scans = plan_folder_scans(&rules, None);
+        let summary: Vec<(String, usize)> = scans
+            .iter()
+            .map(|scan| (scan.folder.clone(), scan.rules.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("inbox".to_string(), 1),
+                ("Junk".to_string(), 2),
+                ("Sent Items".to_string(), 1)
+            ]
+        );
+
+        // An explicit folder only uses the rules for that folder
+        let scans = plan_folder_scans(&rules, Some("junk email"));
+        assert_eq!(scans.len(), 1);
+        let names: Vec<&str> = scans[0].rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["Junk rule", "Junk rule 2"]);
+
+        // Unscoped rules only apply when that folder is the inbox
+        let scans = plan_folder_scans(&rules, Some("Inbox"));
+        assert_eq!(scans.len(), 1);
+        let names: Vec<&str> = scans[0].rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["Inbox rule"]);
+        assert!(plan_folder_scans(&rules, Some("Archive")).is_empty());
     }
 
     #[test]
@@ -490,7 +1051,8 @@ mod tests {
             name: "Sender rule".to_string(),
             sender_contains: PatternSet::with_patterns(vec!["example.com".to_string()]),
             subject_contains: PatternSet::new(),
-            action: RuleAction::Archive,
+            action: Some(RuleAction::Archive),
+            ..Default::default()
         };
 
         assert!(
@@ -507,7 +1069,8 @@ mod tests {
             name: "Subject rule".to_string(),
             sender_contains: PatternSet::new(),
             subject_contains: PatternSet::with_patterns(vec!["important".to_string()]),
-            action: RuleAction::MarkRead,
+            action: Some(RuleAction::MarkRead),
+            ..Default::default()
         };
 
         assert!(
@@ -524,7 +1087,8 @@ mod tests {
             name: "Combined rule".to_string(),
             sender_contains: PatternSet::with_patterns(vec!["newsletter".to_string()]),
             subject_contains: PatternSet::with_patterns(vec!["updates".to_string()]),
-            action: RuleAction::Delete,
+            action: Some(RuleAction::Delete),
+            ..Default::default()
         };
 
         assert!(
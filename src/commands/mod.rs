@@ -1,12 +1,18 @@
+mod analyze;
 mod auth;
 mod clean;
 mod completions;
 mod rules;
+mod undo;
+mod watch;
 
+pub use analyze::AnalyzeCommand;
 pub use auth::AuthCommand;
 pub use clean::CleanCommand;
 pub use completions::CompletionsCommand;
 pub use rules::RulesCommand;
+pub use undo::UndoCommand;
+pub use watch::WatchCommand;
 
 use clap::Subcommand;
 
@@ -21,6 +27,15 @@ pub enum Commands {
     /// Clean inbox based on configured rules
     Clean(CleanCommand),
 
+    /// Clean new messages on a schedule until stopped
+    Watch(WatchCommand),
+
+    /// Undo the actions applied by a previous clean run
+    Undo(UndoCommand),
+
+    /// Report top senders, domains and subject prefixes and suggest rules for them
+    Analyze(AnalyzeCommand),
+
     /// Generate shell completions
     Completions(CompletionsCommand),
 }
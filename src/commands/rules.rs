@@ -1,7 +1,14 @@
-use crate::rules::Rules;
+use crate::backend::{FlagStatus, Message};
+use crate::graph_client::MessageRule;
+use crate::outlook;
+use crate::rules::{Rule, Rules, first_match};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use clap::{Args, Subcommand};
 use inquire::Confirm;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::process::Command;
 
 #[derive(Debug, Args)]
@@ -32,31 +39,119 @@ enum RulesCommands {
     },
 
     /// Add a new rule
-    Add {
-        /// Name of the rule
-        #[arg(short, long)]
-        name: String,
+    Add(Box<AddRule>),
 
-        /// Action to take (archive, delete, mark_read)
-        #[arg(short, long)]
-        action: String,
+    /// Show which rule would apply to a message, without touching the mailbox
+    Test {
+        /// Message file (.eml) to test
+        #[arg(conflicts_with_all = ["sender", "subject"])]
+        file: Option<PathBuf>,
 
-        /// Sender patterns to match (can be specified multiple times)
+        /// Sender of a sample message, e.g. "News <news@example.com>"
         #[arg(long)]
-        sender: Vec<String>,
+        sender: Option<String>,
+
+        /// Subject of a sample message
+        #[arg(long)]
+        subject: Option<String>,
+
+        /// Folder the message is in, to use the rules for that folder (defaults to the inbox)
+        #[arg(long)]
+        folder: Option<String>,
+
+        /// Treat the message as read (it is unread otherwise)
+        #[arg(long)]
+        read: bool,
+
+        /// Outlook category of the message, can be given several times
+        #[arg(long = "category")]
+        categories: Vec<String>,
 
-        /// Subject patterns to match (can be specified multiple times)
+        /// Treat the message as flagged for follow-up
         #[arg(long)]
-        subject: Vec<String>,
+        flagged: bool,
+    },
+
+    /// Import the server-side inbox rules of Outlook into the rules file
+    ImportOutlook {
+        /// Mailbox to read the rules of, defaults to the rules file's mailbox or your own
+        #[arg(long)]
+        mailbox: Option<String>,
+
+        /// Show the converted rules without saving them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Create or update Outlook inbox rules from the rules that can run on the server
+    PushOutlook {
+        /// Mailbox to push the rules to, defaults to the rules file's mailbox or your own
+        #[arg(long)]
+        mailbox: Option<String>,
+
+        /// Show the changes without making them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Apply the changes without confirmation
+        #[arg(long)]
+        yes: bool,
     },
 }
 
+/// Options of `rules add`
+#[derive(Debug, Args)]
+struct AddRule {
+    /// Name of the rule
+    #[arg(short, long)]
+    name: String,
+
+    /// Action to take (archive, delete, mark_read, move_to, add_categories,
+    /// remove_categories, flag, complete_flag, clear_flag); repeat to apply several
+    /// actions in order
+    #[arg(short, long, required = true)]
+    action: Vec<String>,
+
+    /// Destination folder path for the move_to action (e.g. "Projects/Alpha")
+    #[arg(long)]
+    move_to: Option<String>,
+
+    /// Folder the rule applies to, like a rule's `folder` (defaults to the inbox)
+    #[arg(long)]
+    folder: Option<String>,
+
+    /// Categories for the add_categories and remove_categories actions (can be
+    /// specified multiple times)
+    #[arg(long)]
+    category: Vec<String>,
+
+    /// Due date for the flag action, relative to when the message was received (e.g. "+2d")
+    #[arg(long)]
+    due: Option<String>,
+
+    /// Sender patterns to match (can be specified multiple times)
+    #[arg(long)]
+    sender: Vec<String>,
+
+    /// Subject patterns to match (can be specified multiple times)
+    #[arg(long)]
+    subject: Vec<String>,
+
+    /// Sender patterns that exclude a message (can be specified multiple times)
+    #[arg(long)]
+    exclude_sender: Vec<String>,
+
+    /// Subject patterns that exclude a message (can be specified multiple times)
+    #[arg(long)]
+    exclude_subject: Vec<String>,
+}
+
 impl RulesCommand {
     pub async fn execute(self) -> Result<()> {
         match self.command {
             RulesCommands::Show => {
                 let rules = Rules::load()?;
-                println!("{}", serde_yaml::to_string(&rules.items)?);
+                println!("{}", rules.to_yaml()?);
                 Ok(())
             }
             RulesCommands::Edit => {
@@ -116,7 +211,7 @@ impl RulesCommand {
                 match std::fs::read_to_string(&rules_path) {
                     Ok(yaml_str) => {
                         // Parse the YAML
-                        match serde_yaml::from_str::<Rules>(&yaml_str) {
+                        match Rules::parse(&yaml_str) {
                             Ok(rules) => {
                                 // File exists and is valid YAML, now validate the content
                                 let validation_errors = rules.validate();
@@ -134,6 +229,14 @@ impl RulesCommand {
                                         );
                                         println!("Run 'mailsweep rules edit' to add rules.");
                                     }
+
+                                    let warnings = rules.lint();
+                                    if !warnings.is_empty() {
+                                        println!("\n⚠️ {} lint warning(s):", warnings.len());
+                                        for (i, warning) in warnings.iter().enumerate() {
+                                            println!("  {}. {}", i + 1, warning);
+                                        }
+                                    }
                                 } else {
                                     println!(
                                         "❌ Rules have {} validation error(s):",
@@ -195,12 +298,44 @@ impl RulesCommand {
 
                 Ok(())
             }
-            RulesCommands::Add {
-                name,
-                action,
+            RulesCommands::ImportOutlook { mailbox, dry_run } => {
+                import_outlook(mailbox, dry_run).await
+            }
+            RulesCommands::PushOutlook {
+                mailbox,
+                dry_run,
+                yes,
+            } => push_outlook(mailbox, dry_run, yes).await,
+            RulesCommands::Test {
+                file,
                 sender,
                 subject,
+                folder,
+                read,
+                categories,
+                flagged,
             } => {
+                let state = SampleState {
+                    read,
+                    categories,
+                    flagged,
+                };
+                test_message(file, sender, subject, folder, state)
+            }
+            RulesCommands::Add(add) => {
+                let AddRule {
+                    name,
+                    action,
+                    move_to,
+                    folder,
+                    category,
+                    due,
+                    sender,
+                    subject,
+                    exclude_sender,
+                    exclude_subject,
+                } = *add;
+
                 // Validate inputs
                 if name.trim().is_empty() {
                     anyhow::bail!("Rule name cannot be empty");
@@ -210,27 +345,77 @@ impl RulesCommand {
                     anyhow::bail!("At least one sender or subject pattern must be provided");
                 }
 
-                // Parse action
-                let action_lower = action.to_lowercase();
-                let rule_action = match action_lower.as_str() {
-                    "archive" => crate::rules::RuleAction::Archive,
-                    "delete" => crate::rules::RuleAction::Delete,
-                    "mark_read" | "markread" => crate::rules::RuleAction::MarkRead,
-                    _ => {
-                        anyhow::bail!(
-                            "Invalid action: '{}'. Must be one of: archive, delete, mark_read",
-                            action
-                        );
-                    }
-                };
+                // Parse actions
+                let mut rule_actions = Vec::new();
+                for action in &action {
+                    let rule_action = match action.to_lowercase().as_str() {
+                        "archive" => crate::rules::RuleAction::Archive,
+                        "delete" => crate::rules::RuleAction::Delete,
+                        "mark_read" | "markread" => crate::rules::RuleAction::MarkRead,
+                        "move_to" | "moveto" | "move" => match &move_to {
+                            Some(path) if !path.trim().is_empty() => {
+                                crate::rules::RuleAction::MoveTo(path.clone())
+                            }
+                            _ => anyhow::bail!("The move_to action requires --move-to <path>"),
+                        },
+                        "add_categories" | "remove_categories" => {
+                            if category.iter().all(|c| c.trim().is_empty()) {
+                                anyhow::bail!(
+                                    "The {} action requires --category <name>",
+                                    action.to_lowercase()
+                                );
+                            }
+                            let categories = category
+                                .iter()
+                                .map(|c| c.trim().to_string())
+                                .filter(|c| !c.is_empty())
+                                .collect();
+                            if action.eq_ignore_ascii_case("add_categories") {
+                                crate::rules::RuleAction::AddCategories(categories)
+                            } else {
+                                crate::rules::RuleAction::RemoveCategories(categories)
+                            }
+                        }
+                        "flag" => match &due {
+                            Some(due) => {
+                                crate::rules::parse_due(due).map_err(|e| anyhow::anyhow!(e))?;
+                                crate::rules::RuleAction::FlagDue(due.trim().to_string())
+                            }
+                            None => crate::rules::RuleAction::Flag,
+                        },
+                        "complete_flag" => crate::rules::RuleAction::CompleteFlag,
+                        "clear_flag" | "unflag" => crate::rules::RuleAction::ClearFlag,
+                        _ => {
+                            anyhow::bail!(
+                                "Invalid action: '{}'. Must be one of: archive, delete, mark_read, move_to, add_categories, remove_categories, flag, complete_flag, clear_flag",
+                                action
+                            );
+                        }
+                    };
+                    rule_actions.push(rule_action);
+                }
+
+                if rule_actions.iter().filter(|a| a.moves_message()).count() > 1 {
+                    anyhow::bail!("Only one of archive, delete and move_to can be used in a rule");
+                }
 
-                // Create the new rule
-                let new_rule = crate::rules::Rule {
+                // Create the new rule, using the single action form where possible
+                let mut new_rule = crate::rules::Rule {
                     name,
                     sender_contains: crate::rules::PatternSet::with_patterns(sender),
                     subject_contains: crate::rules::PatternSet::with_patterns(subject),
-                    action: rule_action,
+                    sender_not_contains: crate::rules::PatternSet::with_patterns(exclude_sender),
+                    subject_not_contains: crate::rules::PatternSet::with_patterns(exclude_subject),
+                    folder: folder
+                        .map(|folder| folder.trim().to_string())
+                        .filter(|folder| !folder.is_empty()),
+                    ..Default::default()
                 };
+                if rule_actions.len() == 1 {
+                    new_rule.action = rule_actions.pop();
+                } else {
+                    new_rule.actions = rule_actions;
+                }
 
                 // Load existing rules
                 let mut rules = crate::rules::Rules::load()?;
@@ -248,3 +433,402 @@ impl RulesCommand {
         }
     }
 }
+
+/// Convert the Outlook inbox rules and append them to the rules file
+async fn import_outlook(mailbox: Option<String>, dry_run: bool) -> Result<()> {
+    let mut rules = Rules::load()?;
+    let mailbox = mailbox.or_else(|| rules.mailbox.clone());
+    let client = crate::backend::graph_client(mailbox.as_deref()).await?;
+
+    let outlook_rules = client.list_message_rules().await?;
+    if outlook_rules.is_empty() {
+        println!("No inbox rules found in Outlook.");
+        return Ok(());
+    }
+
+    // Rules refer to folders by ID, mailsweep by path
+    let mut folder_paths = HashMap::new();
+    for folder_id in outlook::move_folder_ids(&outlook_rules) {
+        match client.folder_path(&folder_id).await {
+            Ok(path) => {
+                folder_paths.insert(folder_id, path);
+            }
+            Err(e) => tracing::warn!("Couldn't look up folder {}: {}", folder_id, e),
+        }
+    }
+
+    let mut imported = Vec::new();
+    let mut skipped = 0;
+    for outlook_rule in &outlook_rules {
+        let conversion = outlook::convert(outlook_rule, &folder_paths);
+        let name = &outlook_rule.display_name;
+
+        match conversion.rule {
+            Some(_)
+                if rules
+                    .items
+                    .iter()
+                    .chain(&imported)
+                    .any(|r| r.name.eq_ignore_ascii_case(name.trim())) =>
+            {
+                println!(
+                    "⏭️  Skipped '{}': a rule with this name already exists",
+                    name
+                );
+                skipped += 1;
+            }
+            Some(rule) => {
+                if conversion.unsupported.is_empty() {
+                    println!("✅ Imported '{}'", name);
+                } else {
+                    println!(
+                        "⚠️  Imported '{}' without: {}",
+                        name,
+                        conversion.unsupported.join(", ")
+                    );
+                }
+                imported.push(rule);
+            }
+            None => {
+                println!(
+                    "❌ Skipped '{}': {}",
+                    name,
+                    conversion.unsupported.join(", ")
+                );
+                skipped += 1;
+            }
+        }
+    }
+
+    println!("\n{} rule(s) imported, {} skipped", imported.len(), skipped);
+    if imported.is_empty() {
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("\n{}", serde_yaml::to_string(&imported)?);
+        println!("Dry run: the rules file was not changed.");
+        return Ok(());
+    }
+
+    rules.items.extend(imported);
+    rules.save()?;
+    println!("Rules saved to {}", Rules::get_rules_path_str()?);
+    Ok(())
+}
+
+/// Sync the rules that can be expressed as Outlook inbox rules to the server. Inbox rules
+/// created by earlier pushes are recognized by their tag and updated or deleted.
+async fn push_outlook(mailbox: Option<String>, dry_run: bool, yes: bool) -> Result<()> {
+    let rules = Rules::load()?;
+    let validation_errors = rules.validate();
+    if !validation_errors.is_empty() {
+        anyhow::bail!(
+            "Rules have {} validation error(s), run 'mailsweep rules check' for details",
+            validation_errors.len()
+        );
+    }
+
+    let mailbox = mailbox.or_else(|| rules.mailbox.clone());
+    let client = crate::backend::graph_client(mailbox.as_deref()).await?;
+
+    // Inbox rules from earlier pushes, by the name of their mailsweep rule. Extra rules
+    // with the same name can't be matched to a mailsweep rule and are deleted.
+    let mut pushed: HashMap<String, MessageRule> = HashMap::new();
+    let mut deletes: Vec<MessageRule> = Vec::new();
+    let mut last_own_sequence = 0;
+    for rule in client.list_message_rules().await? {
+        let Some(name) = outlook::pushed_rule_name(&rule).map(str::to_lowercase) else {
+            last_own_sequence = last_own_sequence.max(rule.sequence.unwrap_or_default());
+            continue;
+        };
+        match pushed.entry(name) {
+            Entry::Occupied(_) => deletes.push(rule),
+            Entry::Vacant(entry) => {
+                entry.insert(rule);
+            }
+        }
+    }
+
+    // Inbox rules move messages to folders by ID; folders that don't exist are reported
+    let mut folder_ids = HashMap::new();
+    for path in rules.items.iter().flat_map(outlook::destination_paths) {
+        if !folder_ids.contains_key(path)
+            && let Some(id) = client.resolve_folder_id(path).await?
+        {
+            folder_ids.insert(path.to_string(), id);
+        }
+    }
+
+    // Pushed rules run after the user's own inbox rules, in the order of the rules file.
+    // Client-side rules only run when mailsweep does, so a later rule on the server would
+    // take the messages they match first; such rules stay client-side too.
+    let mut client_side: Vec<&Rule> = Vec::new();
+    let mut exported_rules = Vec::new();
+    for rule in &rules.items {
+        if let Some(earlier) = client_side.iter().find(|earlier| earlier.may_overlap(rule)) {
+            println!(
+                "⚠️  '{}' isn't pushed: it could take messages the earlier client-side rule '{}' matches",
+                rule.name, earlier.name
+            );
+            client_side.push(rule);
+            continue;
+        }
+
+        let sequence = last_own_sequence + 1 + exported_rules.len() as i32;
+        match outlook::export(rule, sequence, &folder_ids) {
+            Ok(exported) => exported_rules.push((rule, exported)),
+            Err(reasons) => {
+                println!(
+                    "⚠️  '{}' only works client-side: {}",
+                    rule.name,
+                    reasons.join(", ")
+                );
+                client_side.push(rule);
+            }
+        }
+    }
+
+    // Pushed rules are found again by name, so it has to be unique
+    let mut names = HashSet::new();
+    for (rule, _) in &exported_rules {
+        if !names.insert(rule.name.trim().to_lowercase()) {
+            anyhow::bail!(
+                "Several rules are named '{}'. Rules pushed to Outlook need unique names.",
+                rule.name.trim()
+            );
+        }
+    }
+
+    let mut creates = Vec::new();
+    let mut updates = Vec::new();
+    let mut unchanged = 0;
+    for (rule, exported) in exported_rules {
+        match pushed.remove(&rule.name.trim().to_lowercase()) {
+            Some(current) if outlook::is_up_to_date(&current, &exported) => unchanged += 1,
+            Some(current) => {
+                println!("~ Update '{}'", rule.name);
+                updates.push((current.id.unwrap_or_default(), exported));
+            }
+            None => {
+                println!("+ Create '{}'", rule.name);
+                creates.push(exported);
+            }
+        }
+    }
+
+    // Pushed rules that were removed or can no longer run on the server
+    deletes.extend(pushed.into_values());
+    deletes.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+    for rule in &deletes {
+        println!("- Delete '{}'", rule.display_name);
+    }
+
+    println!(
+        "\n{} to create, {} to update, {} to delete, {} unchanged",
+        creates.len(),
+        updates.len(),
+        deletes.len(),
+        unchanged
+    );
+    if creates.is_empty() && updates.is_empty() && deletes.is_empty() {
+        println!("Outlook inbox rules are up to date.");
+        return Ok(());
+    }
+    if dry_run {
+        println!("Dry run: no inbox rules were changed.");
+        return Ok(());
+    }
+
+    let proceed = yes
+        || Confirm::new("Do you want to apply these changes to your Outlook inbox rules?")
+            .with_default(false)
+            .prompt()
+            .unwrap_or(false);
+    if !proceed {
+        println!("Operation cancelled. No changes made.");
+        return Ok(());
+    }
+
+    // Apply every change that can be applied, reporting the ones that fail
+    let mut applied = 0;
+    let mut failed = 0;
+    for rule in &creates {
+        match client.create_message_rule(rule).await {
+            Ok(_) => applied += 1,
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+    for (id, rule) in &updates {
+        match client.update_message_rule(id, rule).await {
+            Ok(()) => applied += 1,
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+    for rule in &deletes {
+        let Some(id) = &rule.id else {
+            continue;
+        };
+        match client.delete_message_rule(id).await {
+            Ok(()) => applied += 1,
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error: {} ({})", e, rule.display_name);
+            }
+        }
+    }
+
+    println!("\nCompleted: {} applied, {} failed", applied, failed);
+    if failed > 0 {
+        anyhow::bail!(
+            "{} change(s) to Outlook inbox rules failed, run 'mailsweep rules push-outlook' again to retry",
+            failed
+        );
+    }
+
+    println!("Outlook inbox rules updated.");
+    Ok(())
+}
+
+/// Mailbox state of a message tested with `rules test`, which neither a message file nor
+/// a sample sender and subject carry
+struct SampleState {
+    read: bool,
+    categories: Vec<String>,
+    flagged: bool,
+}
+
+/// Run the rules for a folder against a message file or a sample sender and subject, and
+/// show the rule `clean` would apply along with the other rules that match
+fn test_message(
+    file: Option<PathBuf>,
+    sender: Option<String>,
+    subject: Option<String>,
+    folder: Option<String>,
+    state: SampleState,
+) -> Result<()> {
+    let mut message = match file {
+        Some(path) => {
+            let raw = std::fs::read(&path)
+                .map_err(|e| anyhow::anyhow!("Can't read {}: {}", path.display(), e))?;
+            let parsed = mail_parser::MessageParser::default()
+                .parse(&raw)
+                .ok_or_else(|| anyhow::anyhow!("Can't parse message {}", path.display()))?;
+            let (sender, sender_address) = crate::maildir::sender(&parsed);
+
+            Message {
+                subject: parsed.subject().unwrap_or("(No subject)").to_string(),
+                sender,
+                sender_address,
+                received_date: parsed
+                    .date()
+                    .and_then(|date| DateTime::from_timestamp(date.to_timestamp(), 0))
+                    .unwrap_or_else(Utc::now),
+                has_attachments: parsed.attachment_count() > 0,
+                ..Default::default()
+            }
+        }
+        None if sender.is_none() && subject.is_none() => {
+            anyhow::bail!("Give a message file, or --sender and/or --subject")
+        }
+        None => {
+            let sender = sender.unwrap_or_default();
+            // "Name <address>" or a bare address
+            let sender_address = match sender.rsplit_once('<') {
+                Some((_, rest)) => rest.trim_end_matches('>').trim().to_string(),
+                None => sender.trim().to_string(),
+            };
+
+            Message {
+                subject: subject.unwrap_or_default(),
+                sender,
+                sender_address,
+                received_date: Utc::now(),
+                ..Default::default()
+            }
+        }
+    };
+
+    message.is_read = state.read;
+    message.categories = state.categories;
+    message.flag_status = if state.flagged {
+        FlagStatus::Flagged
+    } else {
+        FlagStatus::NotFlagged
+    };
+
+    let rules = Rules::load()?;
+    // Invalid rules are still tested, but clean would refuse to run them
+    let validation_errors = rules.validate();
+    if !validation_errors.is_empty() {
+        println!(
+            "⚠️ Rules have {} validation error(s), 'mailsweep clean' won't run until they are fixed:",
+            validation_errors.len()
+        );
+        for (i, error) in validation_errors.iter().enumerate() {
+            println!("  {}. {}", i + 1, error);
+        }
+        println!();
+    }
+
+    let folder = folder.unwrap_or_else(|| "inbox".to_string());
+    let scans = super::clean::plan_folder_scans(&rules, Some(&folder));
+    let rules_for_folder = scans
+        .first()
+        .map(|scan| scan.rules.clone())
+        .unwrap_or_default();
+
+    println!("From:    {}", message.sender);
+    println!("Subject: {}", message.subject);
+    println!(
+        "State:   {}, {}, {}",
+        if message.is_read { "read" } else { "unread" },
+        if message.flag_status == FlagStatus::Flagged {
+            "flagged"
+        } else {
+            "not flagged"
+        },
+        if message.categories.is_empty() {
+            "no categories".to_string()
+        } else {
+            format!("categories {}", message.categories.join(", "))
+        }
+    );
+    println!();
+
+    let Some(winner) = first_match(&rules_for_folder, &message) else {
+        println!(
+            "No rule for {} matches this message ({} rule(s) checked).",
+            folder,
+            rules_for_folder.len()
+        );
+        return Ok(());
+    };
+
+    let actions: Vec<String> = winner.actions().iter().map(|a| a.to_string()).collect();
+    println!("✅ '{}' applies: {}", winner.name, actions.join(", "));
+    println!("   Matched: {}", winner.explain_match(&message).join(", "));
+
+    let others: Vec<_> = rules_for_folder
+        .iter()
+        .filter(|rule| !std::ptr::eq(**rule, winner) && rule.matches(&message))
+        .collect();
+    if !others.is_empty() {
+        println!("\nAlso matching, but not applied since only the first matching rule is:");
+        for rule in others {
+            println!(
+                "   '{}': {}",
+                rule.name,
+                rule.explain_match(&message).join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,295 @@
+use crate::backend::{self, FlagStatus, Message, Store};
+use crate::journal::{Journal, JournalEntry};
+use crate::rules::RuleAction;
+use anyhow::Result;
+use chrono::{Local, Utc};
+use clap::Args;
+use inquire::Confirm;
+use std::collections::HashMap;
+
+/// How an applied action is reverted
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Revert<'a> {
+    /// Move the message back to the folder with this ID
+    MoveBack(&'a str),
+    MarkUnread,
+    RestoreCategories,
+    /// Set the flag back to this status
+    RestoreFlag(FlagStatus),
+}
+
+#[derive(Debug, Args)]
+pub struct UndoCommand {
+    /// ID (or ID prefix) of the run to undo, defaults to the most recent run
+    #[arg(long)]
+    run: Option<String>,
+
+    /// List recorded runs instead of undoing one
+    #[arg(long)]
+    list: bool,
+
+    /// Undo without confirmation
+    #[arg(long)]
+    yes: bool,
+}
+
+impl UndoCommand {
+    pub async fn execute(self) -> Result<()> {
+        let journal = Journal::open()?;
+        let runs = journal.runs()?;
+
+        if self.list {
+            if runs.is_empty() {
+                println!("No runs recorded yet.");
+                return Ok(());
+            }
+
+            println!(
+                "{:<38} {:<20} {:>8} {:>8}",
+                "Run", "Started", "Actions", "Undone"
+            );
+            for run in &runs {
+                println!(
+                    "{:<38} {:<20} {:>8} {:>8}",
+                    run.run_id,
+                    run.started
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    run.actions,
+                    run.undone
+                );
+            }
+            return Ok(());
+        }
+
+        // Pick the requested run, or the most recent one that still has something to undo
+        let run = match &self.run {
+            Some(id) => {
+                let matching: Vec<_> = runs.iter().filter(|r| r.run_id.starts_with(id)).collect();
+                match matching.as_slice() {
+                    [run] => *run,
+                    [] => anyhow::bail!("No run found with ID '{}'", id),
+                    _ => anyhow::bail!("Run ID '{}' is ambiguous, use more characters", id),
+                }
+            }
+            None => match runs.iter().rev().find(|r| r.actions > r.undone) {
+                Some(run) => run,
+                None => {
+                    println!("Nothing to undo.");
+                    return Ok(());
+                }
+            },
+        };
+
+        let entries = journal.pending_entries(&run.run_id)?;
+        if entries.is_empty() {
+            println!("Nothing to undo for run {}.", run.run_id);
+            return Ok(());
+        }
+
+        // Moved messages go back to their original folder, read messages become unread and
+        // categorized or flagged messages get their previous categories or flag back
+        let mut groups: Vec<(usize, Revert, Vec<&JournalEntry>)> = Vec::new();
+        let mut move_count = 0;
+        let mut unread_count = 0;
+        let mut categories_count = 0;
+        let mut flag_count = 0;
+        let mut skipped = 0;
+        // Messages that were already read before the run, which stay read
+        let mut already_read: Vec<&JournalEntry> = Vec::new();
+
+        for entry in &entries {
+            let revert = match entry.action {
+                RuleAction::MarkRead => {
+                    if entry.is_read == Some(true) {
+                        already_read.push(entry);
+                        continue;
+                    }
+                    unread_count += 1;
+                    Revert::MarkUnread
+                }
+                RuleAction::AddCategories(_) | RuleAction::RemoveCategories(_) => {
+                    categories_count += 1;
+                    Revert::RestoreCategories
+                }
+                RuleAction::Flag
+                | RuleAction::FlagDue(_)
+                | RuleAction::CompleteFlag
+                | RuleAction::ClearFlag => {
+                    flag_count += 1;
+                    Revert::RestoreFlag(entry.flag_status.unwrap_or_default())
+                }
+                RuleAction::Archive | RuleAction::Delete | RuleAction::MoveTo(_) => {
+                    if entry.original_folder.is_empty() || entry.current_id.is_empty() {
+                        skipped += 1;
+                        continue;
+                    }
+                    move_count += 1;
+                    Revert::MoveBack(entry.original_folder.as_str())
+                }
+            };
+
+            match groups
+                .iter_mut()
+                .find(|(step, r, _)| *step == entry.step && *r == revert)
+            {
+                Some((_, _, group)) => group.push(entry),
+                None => groups.push((entry.step, revert, vec![entry])),
+            }
+        }
+
+        // Rules with several actions are undone in reverse order
+        groups.sort_by_key(|(step, _, _)| std::cmp::Reverse(*step));
+
+        println!(
+            "Run {} from {}:",
+            run.run_id,
+            run.started
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+        println!(
+            "  {} message(s) to move back to their original folder",
+            move_count
+        );
+        println!("  {} message(s) to mark as unread", unread_count);
+        if categories_count > 0 {
+            println!(
+                "  {} message(s) to restore the categories of",
+                categories_count
+            );
+        }
+        if flag_count > 0 {
+            println!("  {} message(s) to restore the flag of", flag_count);
+        }
+        if !already_read.is_empty() {
+            println!(
+                "  {} message(s) stay read, as they were read before the run",
+                already_read.len()
+            );
+        }
+        if skipped > 0 {
+            println!(
+                "  {} message(s) can't be restored (original folder or new location unknown)",
+                skipped
+            );
+        }
+
+        let proceed = self.yes
+            || Confirm::new("Do you want to undo this run?")
+                .with_default(false)
+                .prompt()
+                .unwrap_or(false);
+
+        if !proceed {
+            println!("Undo cancelled. No changes made.");
+            return Ok(());
+        }
+
+        // All entries of a run belong to the same mailbox
+        let first = &entries[0];
+        let store = match (&first.maildir, &first.imap) {
+            (Some(path), _) => Store::Maildir(path.clone()),
+            (None, Some(account)) => match &crate::config::settings().imap {
+                Some(imap) if imap.account() == *account => Store::Imap(imap.clone()),
+                _ => anyhow::bail!(
+                    "Run {} was made on IMAP account {}, which isn't configured in this profile",
+                    run.run_id,
+                    account
+                ),
+            },
+            (None, None) => Store::Graph {
+                mailbox: first.mailbox.clone(),
+            },
+        };
+        let backend = backend::open(&store).await?;
+
+        // Nothing to revert for messages that were already read
+        let timestamp = Utc::now();
+        let unchanged: Vec<JournalEntry> = already_read
+            .into_iter()
+            .map(|entry| JournalEntry {
+                timestamp,
+                undone: true,
+                ..entry.clone()
+            })
+            .collect();
+        journal.append(&unchanged)?;
+
+        let mut restored = 0;
+        let mut failed = 0;
+
+        // Where messages are after undoing later actions, by their ID before those actions
+        let mut relocated: HashMap<String, String> = HashMap::new();
+
+        for (_, revert, group) in groups {
+            let messages: Vec<Message> = group
+                .iter()
+                .map(|entry| Message {
+                    id: relocated
+                        .get(&entry.current_id)
+                        .unwrap_or(&entry.current_id)
+                        .clone(),
+                    flag_dates: entry.flag_dates,
+                    ..Default::default()
+                })
+                .collect();
+            let message_refs: Vec<&Message> = messages.iter().collect();
+
+            let result = match revert {
+                Revert::MoveBack(folder) => backend.move_messages(&message_refs, folder).await,
+                Revert::MarkUnread => backend.mark_read(&message_refs, false).await,
+                Revert::RestoreCategories => {
+                    let updates: Vec<(&Message, Vec<String>)> = message_refs
+                        .iter()
+                        .zip(&group)
+                        .map(|(message, entry)| {
+                            (*message, entry.categories.clone().unwrap_or_default())
+                        })
+                        .collect();
+                    backend.set_categories(&updates).await
+                }
+                Revert::RestoreFlag(status) => backend.set_flag(&message_refs, status, None).await,
+            };
+
+            match result {
+                Ok(result) => {
+                    let by_id: HashMap<&str, &JournalEntry> = messages
+                        .iter()
+                        .zip(&group)
+                        .map(|(message, entry)| (message.id.as_str(), *entry))
+                        .collect();
+                    let timestamp = Utc::now();
+                    let undone: Vec<JournalEntry> = result
+                        .succeeded
+                        .iter()
+                        .filter_map(|(old_id, new_id)| {
+                            by_id.get(old_id.as_str()).map(|entry| JournalEntry {
+                                timestamp,
+                                current_id: new_id.clone(),
+                                undone: true,
+                                ..(*entry).clone()
+                            })
+                        })
+                        .collect();
+
+                    for entry in &undone {
+                        relocated.insert(entry.message_id.clone(), entry.current_id.clone());
+                    }
+
+                    journal.append(&undone)?;
+                    restored += result.succeeded.len();
+                    failed += result.failed;
+                }
+                Err(e) => {
+                    failed += group.len();
+                    eprintln!("Error undoing batch: {}", e);
+                }
+            }
+        }
+
+        println!("\nUndone: {} restored, {} failed", restored, failed);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::commands::CleanCommand;
+use anyhow::Result;
+use clap::Args;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Debug, Args)]
+pub struct WatchCommand {
+    /// Time between runs (e.g. "30s", "5m", "1h")
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    #[command(flatten)]
+    clean: CleanCommand,
+}
+
+impl WatchCommand {
+    pub async fn execute(self) -> Result<()> {
+        if self.interval.is_zero() {
+            anyhow::bail!("--interval must be greater than zero");
+        }
+
+        // Every run is incremental and applies actions without asking
+        let clean = self.clean.unattended()?;
+
+        // A signal received during a run is picked up once the run has finished
+        let shutdown = Arc::new(Notify::new());
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                wait_for_shutdown_signal().await;
+                shutdown.notify_one();
+            }
+        });
+
+        tracing::info!(
+            "Watching for new messages every {}",
+            humantime::format_duration(self.interval)
+        );
+
+        let mut cycle: u64 = 0;
+        loop {
+            cycle += 1;
+            tracing::info!("Starting run #{}", cycle);
+
+            // Each run authenticates again, refreshing the access token when it has expired,
+            // and reloads the rules so edits take effect without a restart
+            match clean.execute().await {
+                Ok(()) => tracing::info!("Run #{} finished", cycle),
+                Err(e) => tracing::error!("Run #{} failed: {:#}", cycle, e),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = shutdown.notified() => break,
+            }
+        }
+
+        tracing::info!("Shutting down");
+        Ok(())
+    }
+}
+
+/// Wait for Ctrl-C, or SIGTERM on Unix
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Can't listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
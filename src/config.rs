@@ -1,7 +1,161 @@
 use anyhow::Result;
+use serde::Deserialize;
 use std::env;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Name of the default profile, whose files live directly in the config directory
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Profile selected for this process (see `set_profile`)
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Microsoft Graph API endpoint
+pub const DEFAULT_GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+
+/// Microsoft identity platform (Azure AD v2.0) endpoint for the common tenant
+pub const DEFAULT_LOGIN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0";
+
+/// Settings loaded for this process (see `load_settings`)
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Optional settings from `config.yaml` in the profile directory
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Base URL of the Graph API (env: MAILSWEEP_GRAPH_URL)
+    pub graph_url: String,
+    /// Base URL of the OAuth2 endpoints, without `/authorize`, `/token` or
+    /// `/devicecode` (env: MAILSWEEP_LOGIN_URL)
+    pub login_url: String,
+    /// Local Maildir to clean instead of a Microsoft Graph mailbox
+    pub maildir: Option<PathBuf>,
+    /// IMAP account to clean instead of a Microsoft Graph mailbox
+    pub imap: Option<ImapSettings>,
+}
+
+/// Connection settings and credentials of an IMAP account
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImapSettings {
+    pub host: String,
+    /// Defaults to 993, or 143 without TLS
+    pub port: Option<u16>,
+    pub username: String,
+    /// Password or app password (env: MAILSWEEP_IMAP_PASSWORD)
+    pub password: Option<String>,
+    /// Shell command printing the password, e.g. to read it from a password manager
+    pub password_command: Option<String>,
+    /// Connect over TLS; only disable this for servers on localhost
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+impl ImapSettings {
+    pub fn port(&self) -> u16 {
+        match (self.port, self.tls) {
+            (Some(port), _) => port,
+            (None, true) => 993,
+            (None, false) => 143,
+        }
+    }
+
+    /// Account name used in messages and the undo journal, e.g. `jane@imap.example.com`
+    pub fn account(&self) -> String {
+        format!("{}@{}", self.username, self.host)
+    }
+}
+
+impl Settings {
+    /// Scope for a Microsoft Graph permission in the cloud of `graph_url`, e.g.
+    /// `https://graph.microsoft.us/Mail.ReadWrite`
+    pub fn graph_scope(&self, permission: &str) -> String {
+        let resource = reqwest::Url::parse(&self.graph_url)
+            .map(|url| url.origin().ascii_serialization())
+            .unwrap_or_else(|_| self.graph_url.clone());
+        format!("{}/{}", resource, permission)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            graph_url: DEFAULT_GRAPH_URL.to_string(),
+            login_url: DEFAULT_LOGIN_URL.to_string(),
+            maildir: None,
+            imap: None,
+        }
+    }
+}
+
+/// Load the settings of the active profile. Environment variables take precedence
+/// over `config.yaml`. Must be called after `set_profile`.
+pub fn load_settings() -> Result<()> {
+    // Reading the settings doesn't create the profile's directory
+    let path = get_profile_dir_for(current_profile())?.join("config.yaml");
+    let mut settings: Settings = if path.exists() {
+        serde_yaml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow::anyhow!("Invalid settings in {}: {}", path.display(), e))?
+    } else {
+        Settings::default()
+    };
+
+    if settings.maildir.is_some() && settings.imap.is_some() {
+        anyhow::bail!(
+            "Invalid settings in {}: maildir and imap can't both be set",
+            path.display()
+        );
+    }
+
+    if let Ok(url) = env::var("MAILSWEEP_GRAPH_URL") {
+        settings.graph_url = url;
+    }
+    if let Ok(url) = env::var("MAILSWEEP_LOGIN_URL") {
+        settings.login_url = url;
+    }
+
+    settings.graph_url = settings.graph_url.trim_end_matches('/').to_string();
+    settings.login_url = settings.login_url.trim_end_matches('/').to_string();
+
+    SETTINGS
+        .set(settings)
+        .map_err(|_| anyhow::anyhow!("Settings have already been loaded"))
+}
+
+/// Get the settings of this process, or the defaults if none were loaded
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// Select the profile used for profile-specific files (token cache, rules, journal).
+/// Must be called before any of those files are accessed.
+pub fn set_profile(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    if !valid {
+        anyhow::bail!(
+            "Invalid profile name '{}': use letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+
+    PROFILE
+        .set(name.to_string())
+        .map_err(|_| anyhow::anyhow!("Profile has already been selected"))
+}
+
+/// Get the name of the active profile
+pub fn current_profile() -> &'static str {
+    PROFILE.get().map(String::as_str).unwrap_or(DEFAULT_PROFILE)
+}
 
 /// Get the application config directory path
 pub fn get_app_config_dir() -> Result<PathBuf> {
@@ -46,10 +200,51 @@ pub fn get_app_config_dir() -> Result<PathBuf> {
     Ok(config_dir)
 }
 
-/// Get the path to a configuration file
-pub fn get_config_file_path(filename: &str) -> Result<PathBuf> {
+/// Get the directory holding the files of a profile, which may not exist yet. The
+/// default profile uses the config directory itself, other profiles use
+/// `profiles/<name>` inside it.
+pub fn get_profile_dir_for(profile: &str) -> Result<PathBuf> {
     let config_dir = get_app_config_dir()?;
-    Ok(config_dir.join(filename))
+    if profile == DEFAULT_PROFILE {
+        return Ok(config_dir);
+    }
+
+    Ok(config_dir.join("profiles").join(profile))
+}
+
+/// Get the directory holding the files of the active profile, creating it if needed
+pub fn get_profile_dir() -> Result<PathBuf> {
+    let profile_dir = get_profile_dir_for(current_profile())?;
+    if !profile_dir.exists() {
+        fs::create_dir_all(&profile_dir)?;
+    }
+
+    Ok(profile_dir)
+}
+
+/// List all known profiles, starting with the default profile
+pub fn list_profiles() -> Result<Vec<String>> {
+    let mut profiles = vec![DEFAULT_PROFILE.to_string()];
+
+    let profiles_dir = get_app_config_dir()?.join("profiles");
+    if profiles_dir.exists() {
+        let mut names: Vec<String> = fs::read_dir(&profiles_dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name != DEFAULT_PROFILE)
+            .collect();
+        names.sort();
+        profiles.extend(names);
+    }
+
+    Ok(profiles)
+}
+
+/// Get the path to a configuration file of the active profile
+pub fn get_config_file_path(filename: &str) -> Result<PathBuf> {
+    let profile_dir = get_profile_dir()?;
+    Ok(profile_dir.join(filename))
 }
 
 /// Get the path to a configuration file as a string
@@ -64,11 +259,32 @@ pub fn place_config_file(filename: &str) -> Result<PathBuf> {
     let path = get_config_file_path(filename)?;
 
     // Ensure the parent directory exists
-    if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
+    if let Some(parent) = path.parent()
+        && !parent.exists()
+    {
+        fs::create_dir_all(parent)?;
     }
 
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graph_scope() {
+        let settings = Settings {
+            graph_url: "https://graph.microsoft.us/v1.0".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.graph_scope("Mail.ReadWrite"),
+            "https://graph.microsoft.us/Mail.ReadWrite"
+        );
+        assert_eq!(
+            Settings::default().graph_scope("User.Read"),
+            "https://graph.microsoft.com/User.Read"
+        );
+    }
+}
@@ -4,10 +4,10 @@ use std::process::{Command, Stdio};
 
 pub async fn debug_auth() -> Result<(), Box<dyn std::error::Error>> {
     let client_id = "0cadb66e-6914-4a9f-8058-3ba6e5cb58d8";
-    let auth_url = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string();
-    let token_url = "https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string();
-    let device_auth_url =
-        "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode".to_string();
+    let login_url = &crate::config::settings().login_url;
+    let auth_url = format!("{}/authorize", login_url);
+    let token_url = format!("{}/token", login_url);
+    let device_auth_url = format!("{}/devicecode", login_url);
 
     println!("Debug: Creating client with:");
     println!("Debug: - Client ID: {}", client_id);
@@ -21,16 +21,12 @@ pub async fn debug_auth() -> Result<(), Box<dyn std::error::Error>> {
         AuthUrl::new(auth_url)?,
         Some(TokenUrl::new(token_url)?),
     )
-    .set_device_authorization_url(DeviceAuthorizationUrl::new(device_auth_url)?);
+    .set_device_authorization_url(DeviceAuthorizationUrl::new(device_auth_url.clone())?);
 
     println!("Debug: Client created successfully");
 
     // Test device code flow
-    let scopes = vec![
-        "https://graph.microsoft.com/Mail.ReadWrite", // Includes Mail.Read capabilities
-        "offline_access",
-        "User.Read",
-    ];
+    let scopes = crate::auth::scopes();
 
     println!("Debug: Requesting device code with scopes: {:?}", scopes);
 
@@ -39,7 +35,7 @@ pub async fn debug_auth() -> Result<(), Box<dyn std::error::Error>> {
         .arg("-v") // Verbose output
         .arg("-X")
         .arg("POST")
-        .arg("https://login.microsoftonline.com/common/oauth2/v2.0/devicecode")
+        .arg(&device_auth_url)
         .arg("-H")
         .arg("Content-Type: application/x-www-form-urlencoded")
         .arg("-d")
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Delta links saved by incremental `clean` runs, one per mailbox folder
+pub struct DeltaLinks {
+    path: PathBuf,
+    links: BTreeMap<String, String>,
+}
+
+impl DeltaLinks {
+    pub fn load() -> Result<Self> {
+        let path = crate::config::place_config_file("delta_links.json")?;
+        Self::load_from(path)
+    }
+
+    /// Load delta links from a specific path
+    pub fn load_from(path: PathBuf) -> Result<Self> {
+        let links = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self { path, links })
+    }
+
+    /// Key identifying a folder of a mailbox (`None` for the signed-in user's mailbox)
+    pub fn key(mailbox: Option<&str>, folder_id: &str) -> String {
+        format!("{}/{}", mailbox.unwrap_or("me").to_lowercase(), folder_id)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.links.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: String, link: String) {
+        self.links.insert(key, link);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.links)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_links_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("mailsweep-delta-{}.json", uuid::Uuid::new_v4()));
+
+        let mut links = DeltaLinks::load_from(path.clone()).unwrap();
+        assert_eq!(links.get(&DeltaLinks::key(None, "inbox")), None);
+
+        links.set(
+            DeltaLinks::key(None, "inbox"),
+            "https://graph/delta?token=1".to_string(),
+        );
+        links.set(
+            DeltaLinks::key(Some("Support@Contoso.com"), "inbox"),
+            "https://graph/delta?token=2".to_string(),
+        );
+        links.save().unwrap();
+
+        // Links are kept per mailbox
+        let links = DeltaLinks::load_from(path.clone()).unwrap();
+        assert_eq!(links.get("me/inbox"), Some("https://graph/delta?token=1"));
+        assert_eq!(
+            links.get(&DeltaLinks::key(Some("support@contoso.com"), "inbox")),
+            Some("https://graph/delta?token=2")
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
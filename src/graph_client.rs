@@ -1,10 +1,31 @@
+use crate::backend::{
+    BatchResult, FlagDates, FlagStatus, ListOptions, MailBackend, Message, well_known_folder,
+};
+use crate::rules::Rule;
 use anyhow::Result;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 
-/// Url constants for Microsoft Graph API
-pub const GRAPH_BASE_URL: &str = "https://graph.microsoft.com/v1.0";
+/// Message properties requested from Microsoft Graph
+const MESSAGE_FIELDS: &str =
+    "id,subject,from,receivedDateTime,parentFolderId,isRead,hasAttachments,categories,flag";
+
+/// Maximum number of attempts for throttled or temporarily failing requests
+const MAX_ATTEMPTS: u32 = 6;
+
+/// Base delay for exponential backoff between retries
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound for a computed backoff delay
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Upper bound for a server-provided Retry-After, so a bogus header can't stall a run
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
 
 /// UserInfo returned from Microsoft Graph
 #[derive(Debug, Deserialize)]
@@ -13,32 +34,101 @@ pub struct UserInfo {
     pub display_name: String,
 }
 
-/// Structure representing an email message
-#[derive(Debug, Clone)]
-pub struct Message {
+/// Error for a delta link the server no longer accepts, after which the folder has to be
+/// synced from the start
+#[derive(Debug)]
+struct SyncStateExpired(String);
+
+impl std::fmt::Display for SyncStateExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Delta link expired: {}", self.0)
+    }
+}
+
+impl std::error::Error for SyncStateExpired {}
+
+/// Error for a folder that doesn't exist, as opposed to one that couldn't be looked up
+#[derive(Debug)]
+struct FolderNotFound(String);
+
+impl std::fmt::Display for FolderNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FolderNotFound {}
+
+/// A page of a delta query
+#[derive(Debug, Default)]
+pub struct DeltaPage {
+    pub messages: Vec<Value>,
+    /// Link to the next page of the current sync
+    pub next_link: Option<String>,
+    /// Link to start the next sync from, only present on the last page
+    pub delta_link: Option<String>,
+}
+
+/// Mail folder returned from Microsoft Graph
+#[derive(Debug, Clone, Deserialize)]
+pub struct MailFolder {
     pub id: String,
-    pub subject: String,
-    pub sender: String,
-    pub received_date: DateTime<Utc>,
-    pub matched_rule: Option<String>,
-    pub action: Option<crate::rules::RuleAction>,
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+    #[serde(rename = "parentFolderId")]
+    #[serde(default)]
+    pub parent_folder_id: Option<String>,
+}
+
+/// Server-side inbox rule (messageRule) returned from Microsoft Graph
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRule {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub display_name: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<i32>,
+    #[serde(default)]
+    pub is_enabled: bool,
+    /// Conditions as Graph messageRulePredicates, keyed by predicate name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<serde_json::Map<String, Value>>,
+    /// Exceptions, in the same form as the conditions
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exceptions: Option<serde_json::Map<String, Value>>,
+    /// Actions as Graph messageRuleActions, keyed by action name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actions: Option<serde_json::Map<String, Value>>,
 }
 
-/// Operations that can be performed on messages
-#[derive(Debug, Clone, Copy)]
-pub enum BatchOperation {
-    Archive,
+/// Operations that can be performed on messages in a `$batch` request
+#[derive(Debug, Clone)]
+enum BatchOperation {
     Delete,
     MarkRead,
+    /// Move to the folder with the given ID
+    Move(String),
+    MarkUnread,
+    /// Replace the categories, given per message ID
+    SetCategories(HashMap<String, Vec<String>>),
+    /// Set the follow-up flag, with a due date relative to when the message was received
+    SetFlag(FlagStatus, Option<chrono::Duration>),
 }
 
-/// Result of a batch operation (success_count, failure_count)
-pub type BatchResult = (usize, usize);
-
 /// Client for interacting with Microsoft Graph API
 pub struct GraphClient {
     client: reqwest::Client,
+    /// Base URL of the Graph API (see `config::Settings`)
+    base_url: String,
     access_token: String,
+    /// Path of the mailbox to operate on: "/me" or "/users/{upn}"
+    mailbox_path: String,
 }
 
 impl GraphClient {
@@ -46,18 +136,75 @@ impl GraphClient {
     pub fn new(access_token: String) -> Self {
         Self {
             client: reqwest::Client::new(),
+            base_url: crate::config::settings().graph_url.clone(),
             access_token,
+            mailbox_path: "/me".to_string(),
+        }
+    }
+
+    /// Operate on another user's or a shared mailbox the signed-in user has access to
+    pub fn with_mailbox(mut self, mailbox: Option<&str>) -> Self {
+        if let Some(mailbox) = mailbox.map(str::trim).filter(|m| !m.is_empty()) {
+            self.mailbox_path = user_path(mailbox);
+        }
+        self
+    }
+
+    /// Send a request, retrying throttled (429) and unavailable (503/504) responses
+    /// as well as connection errors with exponential backoff. The request is rebuilt
+    /// for every attempt by calling `build`.
+    async fn send_with_retry<F>(&self, build: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let can_retry = attempt + 1 < MAX_ATTEMPTS;
+
+            match build().send().await {
+                Ok(response) if can_retry && is_retryable_status(response.status().as_u16()) => {
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(parse_retry_after);
+                    let delay = retry_delay(attempt, retry_after);
+
+                    tracing::warn!(
+                        "Microsoft Graph returned HTTP {}, retrying in {:.1}s",
+                        response.status(),
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if can_retry && (e.is_connect() || e.is_timeout()) => {
+                    let delay = retry_delay(attempt, None);
+
+                    tracing::warn!(
+                        "Request to Microsoft Graph failed ({}), retrying in {:.1}s",
+                        e,
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            attempt += 1;
         }
     }
 
     /// Get the authenticated user's information
     pub async fn get_user_info(&self) -> Result<UserInfo> {
-        let url = format!("{}/me", GRAPH_BASE_URL);
+        let url = format!("{}/me", self.base_url);
         let response = self
-            .client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+            .send_with_retry(|| {
+                self.client
+                    .get(&url)
+                    .header("Authorization", format!("Bearer {}", self.access_token))
+            })
             .await
             .map_err(|e| anyhow::anyhow!("Failed to fetch user info: {}", e))?;
 
@@ -82,47 +229,419 @@ impl GraphClient {
         Ok(user_info)
     }
 
-    /// Fetch a page of messages from the inbox
-    pub async fn fetch_messages_page(
+    /// Fetch a page of messages from a folder, optionally narrowed by an OData `$filter`
+    async fn fetch_messages_page(
         &self,
+        folder_id: &str,
         per_page: usize,
+        filter: Option<&str>,
         next_link: Option<&str>,
     ) -> Result<(Vec<Value>, Option<String>)> {
         let url = if let Some(link) = next_link {
             link.to_string()
         } else {
-            format!(
-                "{}/me/mailFolders/inbox/messages?$top={}&$select=id,subject,from,receivedDateTime",
-                GRAPH_BASE_URL, per_page
-            )
+            let mut url = reqwest::Url::parse(&format!(
+                "{}{}/mailFolders/{}/messages",
+                self.base_url, self.mailbox_path, folder_id
+            ))?;
+            url.query_pairs_mut()
+                .append_pair("$top", &per_page.to_string())
+                .append_pair("$select", MESSAGE_FIELDS);
+            if let Some(filter) = filter {
+                url.query_pairs_mut().append_pair("$filter", filter);
+            }
+            url.to_string()
+        };
+
+        let data = self.get_messages_json(&url, None).await?;
+        let messages = data["value"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected response format"))?
+            .clone();
+
+        // Check for @odata.nextLink for pagination
+        let next_link = data["@odata.nextLink"].as_str().map(|s| s.to_string());
+
+        Ok((messages, next_link))
+    }
+
+    /// Fetch a page of messages that are new or changed in a folder, using a delta query.
+    /// Without a link the query starts from scratch; otherwise pass the next link of the
+    /// previous page, or a delta link saved from an earlier run.
+    async fn fetch_delta_page(
+        &self,
+        folder_id: &str,
+        per_page: usize,
+        link: Option<&str>,
+    ) -> Result<DeltaPage> {
+        let url = match link {
+            Some(link) => link.to_string(),
+            None => {
+                let mut url = reqwest::Url::parse(&format!(
+                    "{}{}/mailFolders/{}/messages/delta",
+                    self.base_url, self.mailbox_path, folder_id
+                ))?;
+                url.query_pairs_mut().append_pair("$select", MESSAGE_FIELDS);
+                url.to_string()
+            }
         };
 
+        // Delta queries don't support $top, the page size is a preference instead
+        let data = self.get_messages_json(&url, Some(per_page)).await?;
+        let messages = data["value"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected response format"))?
+            .iter()
+            // Messages deleted or moved out of the folder since the last sync
+            .filter(|message| message.get("@removed").is_none())
+            .cloned()
+            .collect();
+
+        Ok(DeltaPage {
+            messages,
+            next_link: data["@odata.nextLink"].as_str().map(|s| s.to_string()),
+            delta_link: data["@odata.deltaLink"].as_str().map(|s| s.to_string()),
+        })
+    }
+
+    /// GET a page of messages, optionally asking for a maximum page size
+    async fn get_messages_json(&self, url: &str, max_page_size: Option<usize>) -> Result<Value> {
         let response = self
-            .client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+            .send_with_retry(|| {
+                let request = self
+                    .client
+                    .get(url)
+                    .header("Authorization", format!("Bearer {}", self.access_token));
+                match max_page_size {
+                    Some(size) => request.header("Prefer", format!("odata.maxpagesize={}", size)),
+                    None => request,
+                }
+            })
             .await?;
 
         if !response.status().is_success() {
+            let status = response.status();
             let error_text = response.text().await?;
+            if status == reqwest::StatusCode::GONE
+                || error_text.contains("syncStateNotFound")
+                || error_text.contains("resyncRequired")
+            {
+                return Err(SyncStateExpired(error_text).into());
+            }
             anyhow::bail!("Failed to fetch messages: {}", error_text);
         }
 
-        let data: Value = response.json().await?;
-        let messages = data["value"]
-            .as_array()
-            .ok_or_else(|| anyhow::anyhow!("Unexpected response format"))?
-            .clone();
+        Ok(response.json().await?)
+    }
 
-        // Check for @odata.nextLink for pagination
-        let next_link = data["@odata.nextLink"].as_str().map(|s| s.to_string());
+    /// List the child folders of a folder, or the top-level folders if no parent is given
+    async fn list_child_folders(&self, parent_id: Option<&str>) -> Result<Vec<MailFolder>> {
+        let mut url = match parent_id {
+            Some(id) => format!(
+                "{}{}/mailFolders/{}/childFolders?$top=100&$select=id,displayName",
+                self.base_url, self.mailbox_path, id
+            ),
+            None => format!(
+                "{}{}/mailFolders?$top=100&$select=id,displayName",
+                self.base_url, self.mailbox_path
+            ),
+        };
+        let mut folders = Vec::new();
 
-        Ok((messages, next_link))
+        loop {
+            let response = self
+                .send_with_retry(|| {
+                    self.client
+                        .get(&url)
+                        .header("Authorization", format!("Bearer {}", self.access_token))
+                })
+                .await?;
+
+            if !response.status().is_success() {
+                let error_text = response.text().await?;
+                anyhow::bail!("Failed to list mail folders: {}", error_text);
+            }
+
+            let data: Value = response.json().await?;
+            let page: Vec<MailFolder> = serde_json::from_value(data["value"].clone())
+                .map_err(|e| anyhow::anyhow!("Unexpected folder response format: {}", e))?;
+            folders.extend(page);
+
+            match data["@odata.nextLink"].as_str() {
+                Some(link) => url = link.to_string(),
+                None => break,
+            }
+        }
+
+        Ok(folders)
+    }
+
+    /// Create a folder under the given parent, or at the top level if no parent is given
+    async fn create_folder(&self, parent_id: Option<&str>, name: &str) -> Result<MailFolder> {
+        let url = match parent_id {
+            Some(id) => format!(
+                "{}{}/mailFolders/{}/childFolders",
+                self.base_url, self.mailbox_path, id
+            ),
+            None => format!("{}{}/mailFolders", self.base_url, self.mailbox_path),
+        };
+
+        let response = self
+            .send_with_retry(|| {
+                self.client
+                    .post(&url)
+                    .header("Authorization", format!("Bearer {}", self.access_token))
+                    .json(&serde_json::json!({ "displayName": name }))
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to create folder '{}': {}", name, error_text);
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// Resolve a folder path such as "Projects/Alpha" to a folder ID by walking
+    /// display names from the top-level folders down. Missing folders are
+    /// created when `create_missing` is set.
+    async fn resolve_folder_path(&self, path: &str, create_missing: bool) -> Result<String> {
+        let mut segments: Vec<&str> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+        let mut parent_id: Option<String> = None;
+
+        // A leading well-known folder name (inbox, junk, ...) is used as-is
+        if let Some(name) = segments.first().and_then(|s| well_known_folder(s)) {
+            parent_id = Some(name.to_string());
+            segments.remove(0);
+        }
+
+        for segment in segments {
+            let folders = self.list_child_folders(parent_id.as_deref()).await?;
+            let existing = folders
+                .into_iter()
+                .find(|f| f.display_name.to_lowercase() == segment.to_lowercase());
+
+            let folder = match existing {
+                Some(folder) => folder,
+                None if create_missing => self.create_folder(parent_id.as_deref(), segment).await?,
+                None => {
+                    return Err(FolderNotFound(format!(
+                        "Folder '{}' not found (resolving '{}'). Use --create-folders to create it.",
+                        segment, path
+                    ))
+                    .into());
+                }
+            };
+
+            parent_id = Some(folder.id);
+        }
+
+        parent_id.ok_or_else(|| anyhow::anyhow!("Folder path cannot be empty"))
+    }
+
+    /// Path of display names from the top-level folders down to a folder, e.g.
+    /// "Inbox/Receipts", as accepted by `resolve_folder`
+    pub async fn folder_path(&self, folder_id: &str) -> Result<String> {
+        let root = self.get_folder("msgfolderroot").await?;
+        let mut segments = Vec::new();
+        let mut folder = self.get_folder(folder_id).await?;
+
+        loop {
+            segments.push(folder.display_name);
+            match folder.parent_folder_id {
+                Some(parent_id) if parent_id != root.id => {
+                    folder = self.get_folder(&parent_id).await?;
+                }
+                _ => break,
+            }
+        }
+
+        segments.reverse();
+        Ok(segments.join("/"))
+    }
+
+    /// Resolve an existing folder path to the folder's actual ID, or `None` if the folder
+    /// doesn't exist. `resolve_folder` returns well-known names as they are, which inbox
+    /// rules don't accept.
+    pub async fn resolve_folder_id(&self, path: &str) -> Result<Option<String>> {
+        let folder_id = match self.resolve_folder_path(path, false).await {
+            Ok(folder_id) => folder_id,
+            Err(e) if e.is::<FolderNotFound>() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match self.get_folder(&folder_id).await {
+            Ok(folder) => Ok(Some(folder.id)),
+            Err(e) if e.is::<FolderNotFound>() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get a folder by ID or well-known name
+    async fn get_folder(&self, folder_id: &str) -> Result<MailFolder> {
+        let url = format!(
+            "{}{}/mailFolders/{}?$select=id,displayName,parentFolderId",
+            self.base_url, self.mailbox_path, folder_id
+        );
+        let response = self
+            .send_with_retry(|| {
+                self.client
+                    .get(&url)
+                    .header("Authorization", format!("Bearer {}", self.access_token))
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await?;
+            // Well-known folders like the archive don't exist in every mailbox
+            if status == reqwest::StatusCode::NOT_FOUND {
+                return Err(FolderNotFound(format!(
+                    "Folder '{}' not found: {}",
+                    folder_id, error_text
+                ))
+                .into());
+            }
+            anyhow::bail!("Failed to get folder '{}': {}", folder_id, error_text);
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// List the server-side rules of the inbox, in the order Outlook applies them
+    pub async fn list_message_rules(&self) -> Result<Vec<MessageRule>> {
+        let mut url = format!(
+            "{}{}/mailFolders/inbox/messageRules",
+            self.base_url, self.mailbox_path
+        );
+        let mut rules = Vec::new();
+
+        loop {
+            let response = self
+                .send_with_retry(|| {
+                    self.client
+                        .get(&url)
+                        .header("Authorization", format!("Bearer {}", self.access_token))
+                })
+                .await?;
+
+            if !response.status().is_success() {
+                let error_text = response.text().await?;
+                anyhow::bail!("Failed to list inbox rules: {}", error_text);
+            }
+
+            let data: Value = response.json().await?;
+            let page: Vec<MessageRule> = serde_json::from_value(data["value"].clone())
+                .map_err(|e| anyhow::anyhow!("Unexpected inbox rule response format: {}", e))?;
+            rules.extend(page);
+
+            match data["@odata.nextLink"].as_str() {
+                Some(link) => url = link.to_string(),
+                None => break,
+            }
+        }
+
+        rules.sort_by_key(|rule| rule.sequence.unwrap_or(i32::MAX));
+        Ok(rules)
+    }
+
+    /// Create a server-side inbox rule
+    pub async fn create_message_rule(&self, rule: &MessageRule) -> Result<MessageRule> {
+        let url = format!(
+            "{}{}/mailFolders/inbox/messageRules",
+            self.base_url, self.mailbox_path
+        );
+        let response = self
+            .send_with_retry(|| {
+                self.client
+                    .post(&url)
+                    .header("Authorization", format!("Bearer {}", self.access_token))
+                    .json(rule)
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!(
+                "Failed to create inbox rule '{}': {}",
+                rule.display_name,
+                error_text
+            );
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// Replace the settings of a server-side inbox rule
+    pub async fn update_message_rule(&self, id: &str, rule: &MessageRule) -> Result<()> {
+        let url = format!(
+            "{}{}/mailFolders/inbox/messageRules/{}",
+            self.base_url, self.mailbox_path, id
+        );
+        let response = self
+            .send_with_retry(|| {
+                self.client
+                    .patch(&url)
+                    .header("Authorization", format!("Bearer {}", self.access_token))
+                    .json(rule)
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!(
+                "Failed to update inbox rule '{}': {}",
+                rule.display_name,
+                error_text
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Delete a server-side inbox rule
+    pub async fn delete_message_rule(&self, id: &str) -> Result<()> {
+        let url = format!(
+            "{}{}/mailFolders/inbox/messageRules/{}",
+            self.base_url, self.mailbox_path, id
+        );
+        let response = self
+            .send_with_retry(|| {
+                self.client
+                    .delete(&url)
+                    .header("Authorization", format!("Bearer {}", self.access_token))
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to delete inbox rule: {}", error_text);
+        }
+
+        Ok(())
+    }
+
+    /// IDs of all folders below a folder, breadth first
+    async fn list_descendant_folders(&self, folder_id: &str) -> Result<Vec<String>> {
+        let mut descendants = Vec::new();
+        let mut queue = VecDeque::from([folder_id.to_string()]);
+
+        while let Some(parent_id) = queue.pop_front() {
+            for folder in self.list_child_folders(Some(&parent_id)).await? {
+                descendants.push(folder.id.clone());
+                queue.push_back(folder.id);
+            }
+        }
+
+        Ok(descendants)
     }
 
     /// Convert raw JSON message data to a Message struct
-    pub fn parse_message(&self, msg_json: &Value) -> Message {
+    fn parse_message(&self, msg_json: &Value) -> Message {
         let id = msg_json["id"].as_str().unwrap_or("unknown").to_string();
         let subject = msg_json["subject"]
             .as_str()
@@ -139,7 +658,7 @@ impl GraphClient {
         let sender = if sender_name != sender_email {
             format!("{} <{}>", sender_name, sender_email)
         } else {
-            sender_email
+            sender_email.clone()
         };
 
         // Parse received date
@@ -153,117 +672,557 @@ impl GraphClient {
             Utc::now()
         };
 
+        let folder_id = msg_json["parentFolderId"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
         Message {
             id,
             subject,
             sender,
+            sender_address: sender_email,
             received_date,
+            is_read: msg_json["isRead"].as_bool().unwrap_or(false),
+            has_attachments: msg_json["hasAttachments"].as_bool().unwrap_or(false),
+            categories: msg_json["categories"]
+                .as_array()
+                .map(|categories| {
+                    categories
+                        .iter()
+                        .filter_map(|c| c.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            flag_status: serde_json::from_value(msg_json["flag"]["flagStatus"].clone())
+                .unwrap_or_default(),
+            flag_dates: parse_graph_date_time(&msg_json["flag"]["startDateTime"])
+                .zip(parse_graph_date_time(&msg_json["flag"]["dueDateTime"]))
+                .map(|(start, due)| FlagDates { start, due }),
+            folder_id,
             matched_rule: None,
-            action: None,
+            actions: Vec::new(),
         }
     }
 
     /// Process a batch of messages with the same operation type
-    pub async fn process_messages_batch(
+    async fn process_messages_batch(
         &self,
         messages: &[&Message],
         operation: BatchOperation,
     ) -> Result<BatchResult> {
         // MS Graph allows up to 20 requests in a single batch
         const BATCH_SIZE: usize = 20;
-        let mut succeeded = 0;
-        let mut failed = 0;
+        let mut result = BatchResult::default();
 
         // Process messages in batches of BATCH_SIZE
         for chunk in messages.chunks(BATCH_SIZE) {
-            let mut batch_requests = Vec::new();
-
-            // Create batch requests
-            for (i, message) in chunk.iter().enumerate() {
-                let request_id = format!("{}", i + 1); // 1-based request IDs
-                let (method, url, body) = match operation {
-                    BatchOperation::Archive => {
-                        let url = format!("/me/messages/{}/move", message.id);
-                        let body = serde_json::json!({
-                            "destinationId": "archive"
-                        });
-                        ("POST", url, Some(body))
-                    }
-                    BatchOperation::Delete => {
-                        let url = format!("/me/messages/{}", message.id);
-                        ("DELETE", url, None)
+            // Requests still to be sent, keyed by their 1-based batch request ID
+            let mut pending: Vec<(String, &Message)> = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, message)| (format!("{}", i + 1), *message))
+                .collect();
+            let mut attempt = 0;
+
+            while !pending.is_empty() {
+                let batch_requests: Vec<Value> = pending
+                    .iter()
+                    .map(|(request_id, message)| {
+                        self.batch_request(request_id, message, &operation)
+                    })
+                    .collect();
+
+                // Create the batch request
+                let batch_payload = serde_json::json!({
+                    "requests": batch_requests
+                });
+
+                // Send the batch request
+                let url = format!("{}/$batch", self.base_url);
+                let response = self
+                    .send_with_retry(|| {
+                        self.client
+                            .post(&url)
+                            .header("Authorization", format!("Bearer {}", self.access_token))
+                            .header("Content-Type", "application/json")
+                            .json(&batch_payload)
+                    })
+                    .await?;
+
+                if !response.status().is_success() {
+                    let error_text = response.text().await?;
+                    anyhow::bail!("Failed to process batch request: {}", error_text);
+                }
+
+                // Process batch response
+                let batch_response: Value = response.json().await?;
+                let responses = batch_response["responses"]
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid batch response format"))?;
+
+                // Sub-requests that were throttled and should be sent again
+                let can_retry = attempt + 1 < MAX_ATTEMPTS;
+                let mut throttled = Vec::new();
+                let mut retry_after: Option<Duration> = None;
+
+                // Count successes and failures
+                for response in responses {
+                    let status = response["status"].as_u64().unwrap_or(500);
+
+                    let request_id = response["id"].as_str().unwrap_or_default();
+
+                    if (200..300).contains(&status) {
+                        if let Some((_, message)) = pending.iter().find(|(id, _)| id == request_id)
+                        {
+                            // Moves and updates return the message, including its (new) ID
+                            let new_id = response["body"]["id"].as_str().unwrap_or(&message.id);
+                            result
+                                .succeeded
+                                .push((message.id.clone(), new_id.to_string()));
+                        }
+                    } else if can_retry && is_retryable_status(status as u16) {
+                        if let Some(entry) = pending.iter().find(|(id, _)| id == request_id) {
+                            throttled.push(entry.clone());
+                        }
+
+                        // Wait as long as the most demanding sub-request asks for
+                        let requested = response["headers"]["Retry-After"]
+                            .as_str()
+                            .and_then(parse_retry_after);
+                        retry_after = retry_after.max(requested);
+                    } else {
+                        result.failed += 1;
+                        let error = response["body"]["error"]["message"]
+                            .as_str()
+                            .unwrap_or("Unknown error");
+                        eprintln!(
+                            "Error in batch request: Status {}, Message: {}",
+                            status, error
+                        );
                     }
-                    BatchOperation::MarkRead => {
-                        let url = format!("/me/messages/{}", message.id);
-                        let body = serde_json::json!({
-                            "isRead": true
-                        });
-                        ("PATCH", url, Some(body))
+                }
+
+                pending = throttled;
+
+                if !pending.is_empty() {
+                    let delay = retry_delay(attempt, retry_after);
+                    tracing::warn!(
+                        "{} batch request(s) throttled, retrying in {:.1}s",
+                        pending.len(),
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Build a single `$batch` sub-request applying `operation` to `message`
+    fn batch_request(
+        &self,
+        request_id: &str,
+        message: &Message,
+        operation: &BatchOperation,
+    ) -> Value {
+        let (method, url, body) = match operation {
+            BatchOperation::Move(folder_id) => {
+                let url = format!("{}/messages/{}/move", self.mailbox_path, message.id);
+                let body = serde_json::json!({
+                    "destinationId": folder_id
+                });
+                ("POST", url, Some(body))
+            }
+            BatchOperation::Delete => {
+                // Move to Deleted Items rather than DELETE, so the new ID is known for undo
+                let url = format!("{}/messages/{}/move", self.mailbox_path, message.id);
+                let body = serde_json::json!({
+                    "destinationId": "deleteditems"
+                });
+                ("POST", url, Some(body))
+            }
+            BatchOperation::MarkRead => {
+                let url = format!("{}/messages/{}", self.mailbox_path, message.id);
+                let body = serde_json::json!({
+                    "isRead": true
+                });
+                ("PATCH", url, Some(body))
+            }
+            BatchOperation::MarkUnread => {
+                let url = format!("{}/messages/{}", self.mailbox_path, message.id);
+                let body = serde_json::json!({
+                    "isRead": false
+                });
+                ("PATCH", url, Some(body))
+            }
+            BatchOperation::SetCategories(categories) => {
+                let url = format!("{}/messages/{}", self.mailbox_path, message.id);
+                let body = serde_json::json!({
+                    "categories": categories.get(&message.id).cloned().unwrap_or_default()
+                });
+                ("PATCH", url, Some(body))
+            }
+            BatchOperation::SetFlag(status, due) => {
+                let url = format!("{}/messages/{}", self.mailbox_path, message.id);
+                let mut flag = serde_json::json!({
+                    "flagStatus": status
+                });
+                let dates = match due {
+                    Some(due) if *status == FlagStatus::Flagged => {
+                        Some(FlagDates::after_received(message, *due))
                     }
+                    Some(_) => None,
+                    None => message.flag_dates,
                 };
+                // Graph requires a start date along with the due date
+                if let (FlagStatus::Flagged | FlagStatus::Complete, Some(dates)) = (status, dates) {
+                    flag["startDateTime"] = graph_date_time(dates.start);
+                    flag["dueDateTime"] = graph_date_time(dates.due);
+                }
+                ("PATCH", url, Some(serde_json::json!({ "flag": flag })))
+            }
+        };
 
-                let mut request = serde_json::json!({
-                    "id": request_id,
-                    "method": method,
-                    "url": url,
-                    "headers": {
-                        "Content-Type": "application/json"
-                    }
-                });
+        let mut request = serde_json::json!({
+            "id": request_id,
+            "method": method,
+            "url": url,
+            "headers": {
+                "Content-Type": "application/json"
+            }
+        });
 
-                if let Some(body_json) = body {
-                    request["body"] = body_json;
-                }
+        if let Some(body_json) = body {
+            request["body"] = body_json;
+        }
+
+        request
+    }
+
+    /// Fetch messages from a folder with pagination, stopping once `limit` messages are fetched
+    async fn fetch_all_messages(
+        &self,
+        folder_id: &str,
+        filter: Option<&str>,
+        options: &ListOptions<'_>,
+    ) -> Result<Vec<Value>> {
+        let mut all_messages_json = Vec::new();
+        let mut next_link: Option<String> = None;
 
-                batch_requests.push(request);
+        loop {
+            // Don't request more than we still need on the first page
+            let per_page = match options.limit {
+                Some(limit) => options.page_size.min(limit - all_messages_json.len()),
+                None => options.page_size,
+            };
+
+            let (messages, next) = self
+                .fetch_messages_page(folder_id, per_page, filter, next_link.as_deref())
+                .await?;
+            all_messages_json.extend(messages);
+
+            if let Some(limit) = options.limit
+                && all_messages_json.len() >= limit
+            {
+                all_messages_json.truncate(limit);
+                break;
+            }
+
+            // Fetch subsequent pages if available
+            match next {
+                Some(link) => next_link = Some(link),
+                None => break,
             }
+        }
 
-            // Create the batch request
-            let batch_payload = serde_json::json!({
-                "requests": batch_requests
-            });
+        Ok(all_messages_json)
+    }
 
-            // Send the batch request
-            let url = format!("{}/$batch", GRAPH_BASE_URL);
-            let response = self
-                .client
-                .post(&url)
-                .header("Authorization", format!("Bearer {}", self.access_token))
-                .header("Content-Type", "application/json")
-                .json(&batch_payload)
-                .send()
+    /// Fetch the messages of a folder that changed since `link` (or all of them without a link).
+    /// Returns the messages and the delta link to continue from next time.
+    async fn fetch_all_changes(
+        &self,
+        folder_id: &str,
+        link: Option<&str>,
+        page_size: usize,
+    ) -> Result<(Vec<Value>, Option<String>)> {
+        let mut all_messages_json = Vec::new();
+        let mut link = link.map(str::to_string);
+
+        loop {
+            let page = self
+                .fetch_delta_page(folder_id, page_size, link.as_deref())
                 .await?;
+            all_messages_json.extend(page.messages);
 
-            if !response.status().is_success() {
-                let error_text = response.text().await?;
-                anyhow::bail!("Failed to process batch request: {}", error_text);
+            match page.next_link {
+                Some(next_link) => link = Some(next_link),
+                None => return Ok((all_messages_json, page.delta_link)),
             }
+        }
+    }
+}
 
-            // Process batch response
-            let batch_response: Value = response.json().await?;
-            let responses = batch_response["responses"]
-                .as_array()
-                .ok_or_else(|| anyhow::anyhow!("Invalid batch response format"))?;
-
-            // Count successes and failures
-            for response in responses {
-                let status = response["status"].as_u64().unwrap_or(500);
-
-                if (200..300).contains(&status) {
-                    succeeded += 1;
-                } else {
-                    failed += 1;
-                    let error = response["body"]["error"]["message"]
-                        .as_str()
-                        .unwrap_or("Unknown error");
-                    eprintln!(
-                        "Error in batch request: Status {}, Message: {}",
-                        status, error
-                    );
-                }
+#[async_trait]
+impl MailBackend for GraphClient {
+    async fn resolve_folder(&self, path: &str, create_missing: bool) -> Result<String> {
+        self.resolve_folder_path(path, create_missing).await
+    }
+
+    async fn list_subfolders(&self, folder_id: &str) -> Result<Vec<String>> {
+        self.list_descendant_folders(folder_id).await
+    }
+
+    async fn list_messages(&self, folder_id: &str, options: &ListOptions) -> Result<Vec<Message>> {
+        // Only fetch candidates for the rules when they can be filtered server-side
+        let filter = candidate_filter(options.rules);
+
+        let messages_json = match self
+            .fetch_all_messages(folder_id, filter.as_deref(), options)
+            .await
+        {
+            Ok(messages_json) => messages_json,
+            // Graph rejects some filters as too complex, so fall back to matching everything locally
+            Err(e) if filter.is_some() => {
+                tracing::warn!("Server-side filter failed, fetching all messages: {}", e);
+                self.fetch_all_messages(folder_id, None, options).await?
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(messages_json
+            .iter()
+            .map(|msg_json| self.parse_message(msg_json))
+            .collect())
+    }
+
+    async fn list_changed_messages(
+        &self,
+        folder_id: &str,
+        sync_state: Option<&str>,
+        page_size: usize,
+    ) -> Result<(Vec<Message>, Option<String>)> {
+        let (messages_json, delta_link) = match self
+            .fetch_all_changes(folder_id, sync_state, page_size)
+            .await
+        {
+            Ok(result) => result,
+            // Saved delta links expire eventually, so start a new sync. Other errors are
+            // passed on, so an outage doesn't turn every run into a full scan.
+            Err(e) if sync_state.is_some() && e.is::<SyncStateExpired>() => {
+                tracing::warn!("Delta link rejected, rescanning the folder: {}", e);
+                self.fetch_all_changes(folder_id, None, page_size).await?
             }
+            Err(e) => return Err(e),
+        };
+
+        let messages = messages_json
+            .iter()
+            .map(|msg_json| self.parse_message(msg_json))
+            .collect();
+        Ok((messages, delta_link))
+    }
+
+    async fn move_messages(&self, messages: &[&Message], folder_id: &str) -> Result<BatchResult> {
+        self.process_messages_batch(messages, BatchOperation::Move(folder_id.to_string()))
+            .await
+    }
+
+    async fn delete_messages(&self, messages: &[&Message]) -> Result<BatchResult> {
+        self.process_messages_batch(messages, BatchOperation::Delete)
+            .await
+    }
+
+    async fn mark_read(&self, messages: &[&Message], read: bool) -> Result<BatchResult> {
+        let operation = if read {
+            BatchOperation::MarkRead
+        } else {
+            BatchOperation::MarkUnread
+        };
+        self.process_messages_batch(messages, operation).await
+    }
+
+    async fn set_categories(&self, updates: &[(&Message, Vec<String>)]) -> Result<BatchResult> {
+        let messages: Vec<&Message> = updates.iter().map(|(message, _)| *message).collect();
+        let categories = updates
+            .iter()
+            .map(|(message, categories)| (message.id.clone(), categories.clone()))
+            .collect();
+        self.process_messages_batch(&messages, BatchOperation::SetCategories(categories))
+            .await
+    }
+
+    async fn set_flag(
+        &self,
+        messages: &[&Message],
+        status: FlagStatus,
+        due: Option<chrono::Duration>,
+    ) -> Result<BatchResult> {
+        self.process_messages_batch(messages, BatchOperation::SetFlag(status, due))
+            .await
+    }
+}
+
+/// A date and time in the `dateTimeTimeZone` form Graph uses for flags
+fn graph_date_time(date: DateTime<Utc>) -> Value {
+    serde_json::json!({
+        "dateTime": date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        "timeZone": "UTC"
+    })
+}
+
+/// Parse a `dateTimeTimeZone` value as returned by Graph, which uses UTC by default
+fn parse_graph_date_time(value: &Value) -> Option<DateTime<Utc>> {
+    let date =
+        chrono::NaiveDateTime::parse_from_str(value["dateTime"].as_str()?, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()?;
+    Some(date.and_utc())
+}
+
+/// Combined `$filter` for a set of rules, or `None` if any rule needs every message
+fn candidate_filter(rules: &[&Rule]) -> Option<String> {
+    let now = Utc::now();
+    let filters = rules
+        .iter()
+        .map(|rule| rule.graph_filter(now))
+        .collect::<Option<Vec<String>>>()?;
+
+    match filters.as_slice() {
+        [] => None,
+        [filter] => Some(filter.clone()),
+        _ => Some(
+            filters
+                .iter()
+                .map(|filter| format!("({})", filter))
+                .collect::<Vec<_>>()
+                .join(" or "),
+        ),
+    }
+}
+
+/// Whether a response status indicates throttling or a temporary outage
+fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 503 | 504)
+}
+
+/// Parse a Retry-After value given either in seconds or as an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.with_timezone(&Utc).signed_duration_since(Utc::now());
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Delay before the next attempt: the server's Retry-After (capped) if given,
+/// otherwise exponential backoff with full jitter
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(MAX_RETRY_AFTER);
+    }
+
+    let backoff = BASE_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY);
+    let jitter_ms = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64);
+
+    Duration::from_millis(jitter_ms)
+}
+
+/// Path of a user's mailbox, with the user principal name percent-encoded so that
+/// characters like `#`, `/` or `?` stay part of the path segment
+fn user_path(mailbox: &str) -> String {
+    let mut url = reqwest::Url::parse("http://localhost/users").expect("valid URL");
+    url.path_segments_mut()
+        .expect("URL with a path")
+        .push(mailbox);
+    url.path().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::PatternSet;
+
+    #[test]
+    fn test_retry_timing() {
+        // Retry-After in seconds and as an HTTP date
+        assert_eq!(parse_retry_after("7"), Some(Duration::from_secs(7)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO),
+            "Dates in the past mean no wait"
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+
+        // Retry-After takes precedence over backoff
+        assert_eq!(
+            retry_delay(3, Some(Duration::from_secs(30))),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(86400))),
+            MAX_RETRY_AFTER,
+            "Retry-After is capped"
+        );
+
+        // Backoff with jitter stays within the exponential bound and the cap
+        for attempt in 0..10 {
+            let delay = retry_delay(attempt, None);
+            let bound = BASE_RETRY_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(MAX_RETRY_DELAY);
+            assert!(delay <= bound);
         }
 
-        Ok((succeeded, failed))
+        assert!(is_retryable_status(429));
+        assert!(is_retryable_status(503));
+        assert!(!is_retryable_status(404));
+    }
+
+    #[test]
+    fn test_user_path() {
+        assert_eq!(user_path("shared@contoso.com"), "/users/shared@contoso.com");
+        assert_eq!(
+            user_path("a#b/c?d%e f@contoso.com"),
+            "/users/a%23b%2Fc%3Fd%25e%20f@contoso.com"
+        );
+    }
+
+    #[test]
+    fn test_candidate_filter() {
+        let unread = Rule {
+            name: "Unread".to_string(),
+            is_read: Some(false),
+            ..Default::default()
+        };
+        let attachments = Rule {
+            name: "Attachments".to_string(),
+            has_attachments: Some(true),
+            ..Default::default()
+        };
+        let subject = Rule {
+            name: "Subject".to_string(),
+            subject_contains: PatternSet::with_patterns(vec!["x".to_string()]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            candidate_filter(&[&unread]).as_deref(),
+            Some("isRead eq false")
+        );
+        assert_eq!(
+            candidate_filter(&[&unread, &attachments]).as_deref(),
+            Some("(isRead eq false) or (hasAttachments eq true)")
+        );
+        // One rule that needs every message disables the filter
+        assert_eq!(candidate_filter(&[&unread, &subject]), None);
     }
 }
@@ -0,0 +1,640 @@
+use crate::backend::{
+    BatchResult, FlagStatus, ListOptions, MailBackend, Message, well_known_folder, well_known_names,
+};
+use crate::config::ImapSettings;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use imap::types::{Fetch, Flag, NameAttribute};
+use mail_parser::{MessageParser, MimeHeaders};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Folder ID of the inbox, whose name IMAP servers treat case-insensitively
+const INBOX: &str = "INBOX";
+
+/// Attributes fetched for every listed message. `BODY.PEEK` leaves the \Seen flag alone.
+const FETCH_QUERY: &str = "(UID FLAGS INTERNALDATE BODY.PEEK[HEADER])";
+
+/// How long to wait for the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A connection to the server, over TLS or not
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+type Session = imap::Session<Box<dyn Stream>>;
+
+/// A folder on the server
+struct Folder {
+    name: String,
+    /// Lowercased attributes, including special-use attributes such as `\junk` (RFC 6154)
+    attributes: Vec<String>,
+}
+
+/// An IMAP account, such as Fastmail or a Dovecot server.
+///
+/// Folder IDs are the folder names on the server (`INBOX`, `Projects/Alpha`) and message
+/// IDs are the folder name followed by the message's UID (`Projects/Alpha/42`).
+pub struct Imap {
+    /// The `imap` crate is blocking, so commands run on tokio's blocking thread pool
+    session: Arc<Mutex<Session>>,
+    /// Separator of nested folder names, e.g. `/` or `.`
+    delimiter: String,
+    /// Whether the server supports the MOVE extension (RFC 6851)
+    supports_move: bool,
+    /// Whether the server supports UID EXPUNGE (RFC 4315)
+    supports_uidplus: bool,
+}
+
+impl Imap {
+    /// Connect and log in to the account
+    pub async fn connect(settings: &ImapSettings) -> Result<Self> {
+        let settings = settings.clone();
+        tokio::task::spawn_blocking(move || Self::connect_blocking(&settings)).await?
+    }
+
+    fn connect_blocking(settings: &ImapSettings) -> Result<Self> {
+        let password = password(settings)?;
+
+        let tcp = TcpStream::connect((settings.host.as_str(), settings.port())).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't connect to {}:{}: {}",
+                settings.host,
+                settings.port(),
+                e
+            )
+        })?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+
+        let stream: Box<dyn Stream> = if settings.tls {
+            let connector = native_tls::TlsConnector::new()?;
+            let tls = connector.connect(&settings.host, tcp).map_err(|e| {
+                anyhow::anyhow!("TLS handshake with {} failed: {}", settings.host, e)
+            })?;
+            Box::new(tls)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut client = imap::Client::new(stream);
+        client.read_greeting()?;
+        let mut session = client
+            .login(&settings.username, &password)
+            .map_err(|(e, _)| {
+                anyhow::anyhow!("IMAP login to {} failed: {}", settings.account(), e)
+            })?;
+
+        let capabilities = session.capabilities()?;
+        let supports_move = capabilities.has_str("MOVE");
+        let supports_uidplus = capabilities.has_str("UIDPLUS");
+        drop(capabilities);
+
+        // Listing the empty name returns the hierarchy delimiter
+        let delimiter = session
+            .list(Some(""), Some(""))?
+            .iter()
+            .find_map(|name| name.delimiter().map(str::to_string))
+            .unwrap_or_else(|| "/".to_string());
+
+        Ok(Self {
+            session: Arc::new(Mutex::new(session)),
+            delimiter,
+            supports_move,
+            supports_uidplus,
+        })
+    }
+
+    /// Run blocking commands on the session
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Session) -> Result<T> + Send + 'static,
+    {
+        let session = self.session.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut session = session
+                .lock()
+                .map_err(|_| anyhow::anyhow!("IMAP session is unusable after an earlier error"))?;
+            f(&mut session)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl MailBackend for Imap {
+    async fn resolve_folder(&self, path: &str, create_missing: bool) -> Result<String> {
+        let segments: Vec<String> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        let delimiter = self.delimiter.clone();
+        let path = path.to_string();
+
+        self.run(move |session| {
+            let folders = list_folders(session)?;
+            let find = |name: &str| {
+                folders
+                    .iter()
+                    .find(|folder| folder.name.to_lowercase() == name.to_lowercase())
+                    .map(|folder| folder.name.clone())
+            };
+
+            let name = match segments.as_slice() {
+                [] => anyhow::bail!("Folder path cannot be empty"),
+                [segment] if well_known_folder(segment) == Some("inbox") => {
+                    return Ok(INBOX.to_string());
+                }
+                [first, rest @ ..] if well_known_folder(first) == Some("inbox") => {
+                    format!("{}{}{}", INBOX, delimiter, rest.join(&delimiter))
+                }
+                [segment] if well_known_folder(segment).is_some() => {
+                    let well_known = well_known_folder(segment).unwrap();
+
+                    // Prefer the folder the server marks for this use
+                    if let Some(attribute) = special_use(well_known)
+                        && let Some(folder) = folders
+                            .iter()
+                            .find(|folder| folder.attributes.iter().any(|a| a == attribute))
+                    {
+                        return Ok(folder.name.clone());
+                    }
+
+                    let names = well_known_names(well_known);
+                    if let Some(existing) = names.iter().find_map(|name| find(name)) {
+                        return Ok(existing);
+                    }
+                    names[0].to_string()
+                }
+                _ => segments.join(&delimiter),
+            };
+
+            match find(&name) {
+                Some(existing) => Ok(existing),
+                None if create_missing => {
+                    session.create(&name)?;
+                    // Clients only show subscribed folders by default
+                    if let Err(e) = session.subscribe(&name) {
+                        tracing::warn!("Can't subscribe to folder {}: {}", name, e);
+                    }
+                    Ok(name)
+                }
+                None => anyhow::bail!(
+                    "Folder '{}' not found (resolving '{}'). Use --create-folders to create it.",
+                    name,
+                    path
+                ),
+            }
+        })
+        .await
+    }
+
+    async fn list_subfolders(&self, folder_id: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}{}", folder_id, self.delimiter);
+
+        self.run(move |session| {
+            Ok(list_folders(session)?
+                .into_iter()
+                .map(|folder| folder.name)
+                .filter(|name| name.starts_with(&prefix))
+                .collect())
+        })
+        .await
+    }
+
+    async fn list_messages(&self, folder_id: &str, options: &ListOptions) -> Result<Vec<Message>> {
+        let folder_id = folder_id.to_string();
+        let limit = options.limit;
+        let page_size = options.page_size;
+
+        self.run(move |session| {
+            session.examine(&folder_id)?;
+
+            // Messages added to the folder later have higher UIDs
+            let mut uids: Vec<u32> = session.uid_search("ALL")?.into_iter().collect();
+            uids.sort_unstable_by(|a, b| b.cmp(a));
+            if let Some(limit) = limit {
+                uids.truncate(limit);
+            }
+
+            fetch_messages(session, &folder_id, &uids, page_size)
+        })
+        .await
+    }
+
+    /// Only messages added since the last run count as changed. The sync state is the
+    /// folder's UIDVALIDITY and the highest UID seen.
+    async fn list_changed_messages(
+        &self,
+        folder_id: &str,
+        sync_state: Option<&str>,
+        page_size: usize,
+    ) -> Result<(Vec<Message>, Option<String>)> {
+        let folder_id = folder_id.to_string();
+        let sync_state = sync_state.map(str::to_string);
+
+        self.run(move |session| {
+            let mailbox = session.examine(&folder_id)?;
+            let validity = mailbox.uid_validity.unwrap_or(0);
+
+            // A new UIDVALIDITY means the UIDs were reassigned, so start over
+            let last_uid = sync_state
+                .as_deref()
+                .and_then(|state| state.split_once(':'))
+                .filter(|(state_validity, _)| state_validity.parse() == Ok(validity))
+                .and_then(|(_, uid)| uid.parse::<u32>().ok());
+
+            let query = match last_uid {
+                Some(uid) => format!("UID {}:*", uid + 1),
+                None => "ALL".to_string(),
+            };
+            // "n:*" also matches the highest UID when it is below n
+            let mut uids: Vec<u32> = session
+                .uid_search(query)?
+                .into_iter()
+                .filter(|&uid| last_uid.is_none_or(|last| uid > last))
+                .collect();
+            uids.sort_unstable_by(|a, b| b.cmp(a));
+
+            let highest = uids.first().copied().or(last_uid).unwrap_or(0);
+            let messages = fetch_messages(session, &folder_id, &uids, page_size)?;
+
+            Ok((messages, Some(format!("{}:{}", validity, highest))))
+        })
+        .await
+    }
+
+    async fn move_messages(&self, messages: &[&Message], folder_id: &str) -> Result<BatchResult> {
+        let (by_folder, mut result) = group_by_folder(messages);
+        let target = folder_id.to_string();
+        let supports_move = self.supports_move;
+        let supports_uidplus = self.supports_uidplus;
+
+        self.run(move |session| {
+            for (folder, uids) in by_folder {
+                let moved = move_uids(
+                    session,
+                    &folder,
+                    &uids,
+                    &target,
+                    supports_move,
+                    supports_uidplus,
+                );
+
+                match moved {
+                    Ok(new_uids) => {
+                        for (uid, new_uid) in uids.iter().zip(new_uids) {
+                            result.succeeded.push((
+                                format!("{}/{}", folder, uid),
+                                new_uid
+                                    .map(|new_uid| format!("{}/{}", target, new_uid))
+                                    .unwrap_or_default(),
+                            ));
+                        }
+                    }
+                    Err(e) => {
+                        result.failed += uids.len();
+                        eprintln!("Error moving messages from {}: {}", folder, e);
+                    }
+                }
+            }
+
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn delete_messages(&self, messages: &[&Message]) -> Result<BatchResult> {
+        // Move to the trash folder rather than expunging, so runs can be undone
+        let trash = self.resolve_folder("deleted", true).await?;
+        self.move_messages(messages, &trash).await
+    }
+
+    async fn mark_read(&self, messages: &[&Message], read: bool) -> Result<BatchResult> {
+        let (by_folder, mut result) = group_by_folder(messages);
+        let query = if read {
+            "+FLAGS.SILENT (\\Seen)"
+        } else {
+            "-FLAGS.SILENT (\\Seen)"
+        };
+
+        self.run(move |session| {
+            for (folder, uids) in by_folder {
+                let stored = session
+                    .select(&folder)
+                    .and_then(|_| session.uid_store(uid_set(&uids), query));
+
+                match stored {
+                    Ok(_) => {
+                        for uid in &uids {
+                            let id = format!("{}/{}", folder, uid);
+                            result.succeeded.push((id.clone(), id));
+                        }
+                    }
+                    Err(e) => {
+                        result.failed += uids.len();
+                        eprintln!("Error updating messages in {}: {}", folder, e);
+                    }
+                }
+            }
+
+            Ok(result)
+        })
+        .await
+    }
+}
+
+/// Password of the account, from MAILSWEEP_IMAP_PASSWORD, the settings or `password_command`
+fn password(settings: &ImapSettings) -> Result<String> {
+    if let Ok(password) = std::env::var("MAILSWEEP_IMAP_PASSWORD") {
+        return Ok(password);
+    }
+    if let Some(password) = &settings.password {
+        return Ok(password.clone());
+    }
+
+    let Some(command) = &settings.password_command else {
+        anyhow::bail!(
+            "No password for IMAP account {}. Set password or password_command in config.yaml.",
+            settings.account()
+        );
+    };
+
+    let output = if cfg!(target_os = "windows") {
+        Command::new("cmd").args(["/C", command]).output()?
+    } else {
+        Command::new("sh").args(["-c", command]).output()?
+    };
+    if !output.status.success() {
+        anyhow::bail!(
+            "password_command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8(output.stdout)?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+/// All selectable folders on the server
+fn list_folders(session: &mut Session) -> Result<Vec<Folder>> {
+    let names = session.list(Some(""), Some("*"))?;
+
+    Ok(names
+        .iter()
+        .filter(|name| !name.attributes().contains(&NameAttribute::NoSelect))
+        .map(|name| Folder {
+            name: name.name().to_string(),
+            attributes: name
+                .attributes()
+                .iter()
+                .filter_map(|attribute| match attribute {
+                    NameAttribute::Custom(attribute) => Some(attribute.to_lowercase()),
+                    _ => None,
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+/// Special-use attribute (RFC 6154) of a well-known folder
+fn special_use(well_known: &str) -> Option<&'static str> {
+    match well_known {
+        "junkemail" => Some("\\junk"),
+        "sentitems" => Some("\\sent"),
+        "archive" => Some("\\archive"),
+        "deleteditems" => Some("\\trash"),
+        "drafts" => Some("\\drafts"),
+        _ => None,
+    }
+}
+
+/// Fetch and parse messages of the selected folder, newest first
+fn fetch_messages(
+    session: &mut Session,
+    folder_id: &str,
+    uids: &[u32],
+    page_size: usize,
+) -> Result<Vec<Message>> {
+    let mut messages = Vec::with_capacity(uids.len());
+
+    for chunk in uids.chunks(page_size) {
+        let fetches = session.uid_fetch(uid_set(chunk), FETCH_QUERY)?;
+        for fetch in fetches.iter() {
+            match parse_message(folder_id, fetch) {
+                Ok(message) => messages.push(message),
+                Err(e) => tracing::warn!("Skipping message in {}: {}", folder_id, e),
+            }
+        }
+    }
+
+    messages.sort_by_key(|message| std::cmp::Reverse(message.received_date));
+    Ok(messages)
+}
+
+fn parse_message(folder_id: &str, fetch: &Fetch) -> Result<Message> {
+    let uid = fetch
+        .uid
+        .ok_or_else(|| anyhow::anyhow!("FETCH response without UID"))?;
+    let header = fetch.header().unwrap_or_default();
+    let parsed = MessageParser::default()
+        .parse_headers(header)
+        .ok_or_else(|| anyhow::anyhow!("Can't parse the header of message {}", uid))?;
+
+    let (sender, sender_address) = crate::maildir::sender(&parsed);
+
+    // INTERNALDATE is when the server received the message
+    let received_date = fetch
+        .internal_date()
+        .map(|date| date.with_timezone(&Utc))
+        .or_else(|| {
+            parsed
+                .date()
+                .and_then(|date| DateTime::from_timestamp(date.to_timestamp(), 0))
+        })
+        .unwrap_or_else(Utc::now);
+
+    // Only the header is fetched, so attachments are assumed for multipart/mixed messages
+    let has_attachments = parsed.content_type().is_some_and(|content_type| {
+        content_type.ctype().eq_ignore_ascii_case("multipart")
+            && content_type
+                .subtype()
+                .is_some_and(|subtype| subtype.eq_ignore_ascii_case("mixed"))
+    });
+
+    Ok(Message {
+        id: format!("{}/{}", folder_id, uid),
+        subject: parsed.subject().unwrap_or("(No subject)").to_string(),
+        sender,
+        sender_address,
+        received_date,
+        is_read: fetch.flags().contains(&Flag::Seen),
+        has_attachments,
+        categories: Vec::new(),
+        flag_status: if fetch.flags().contains(&Flag::Flagged) {
+            FlagStatus::Flagged
+        } else {
+            FlagStatus::NotFlagged
+        },
+        flag_dates: None,
+        folder_id: folder_id.to_string(),
+        matched_rule: None,
+        actions: Vec::new(),
+    })
+}
+
+/// Group message UIDs by folder, in ascending order. Invalid IDs are counted as failures.
+fn group_by_folder(messages: &[&Message]) -> (BTreeMap<String, Vec<u32>>, BatchResult) {
+    let mut by_folder: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut result = BatchResult::default();
+
+    for message in messages {
+        match parse_id(&message.id) {
+            Some((folder, uid)) => by_folder.entry(folder.to_string()).or_default().push(uid),
+            None => {
+                result.failed += 1;
+                eprintln!(
+                    "Error processing message {}: invalid IMAP message ID",
+                    message.id
+                );
+            }
+        }
+    }
+
+    for uids in by_folder.values_mut() {
+        uids.sort_unstable();
+    }
+
+    (by_folder, result)
+}
+
+/// Move messages from `folder` to `target`, returning their new UIDs in the same order
+/// where the server reports them (servers with UIDPLUS do)
+fn move_uids(
+    session: &mut Session,
+    folder: &str,
+    uids: &[u32],
+    target: &str,
+    supports_move: bool,
+    supports_uidplus: bool,
+) -> Result<Vec<Option<u32>>> {
+    session.select(folder)?;
+
+    let set = uid_set(uids);
+    let response = if supports_move {
+        session.run_command_and_read_response(format!("UID MOVE {} {}", set, quote(target)))?
+    } else {
+        // Without UIDPLUS, EXPUNGE removes every message marked as deleted, not just these
+        if !supports_uidplus && !session.uid_search("DELETED")?.is_empty() {
+            anyhow::bail!(
+                "{} has messages marked as deleted, which moving would remove permanently on a server without MOVE or UIDPLUS. Expunge them first.",
+                folder
+            );
+        }
+
+        let response =
+            session.run_command_and_read_response(format!("UID COPY {} {}", set, quote(target)))?;
+        session.uid_store(&set, "+FLAGS.SILENT (\\Deleted)")?;
+        if supports_uidplus {
+            session.uid_expunge(&set)?;
+        } else {
+            session.expunge()?;
+        }
+        response
+    };
+
+    // Without a COPYUID response the new UIDs are unknown; guessing them from UIDNEXT
+    // breaks when mail is delivered at the same time
+    let mapping = copy_uids(&String::from_utf8_lossy(&response)).unwrap_or_default();
+    Ok(uids.iter().map(|uid| mapping.get(uid).copied()).collect())
+}
+
+/// Map old to new UIDs from a `[COPYUID validity old-set new-set]` response code
+fn copy_uids(response: &str) -> Option<HashMap<u32, u32>> {
+    let start = response.find("[COPYUID ")? + "[COPYUID ".len();
+    let end = start + response[start..].find(']')?;
+    let mut parts = response[start..end].split_whitespace().skip(1);
+
+    let old = parse_uid_set(parts.next()?)?;
+    let new = parse_uid_set(parts.next()?)?;
+    if old.len() != new.len() {
+        return None;
+    }
+
+    Some(old.into_iter().zip(new).collect())
+}
+
+/// Expand a UID set such as `1:3,7` in the order given
+fn parse_uid_set(set: &str) -> Option<Vec<u32>> {
+    let mut uids = Vec::new();
+
+    for part in set.split(',') {
+        match part.split_once(':') {
+            Some((first, last)) => {
+                let (first, last): (u32, u32) = (first.parse().ok()?, last.parse().ok()?);
+                if first <= last {
+                    uids.extend(first..=last);
+                } else {
+                    uids.extend((last..=first).rev());
+                }
+            }
+            None => uids.push(part.parse().ok()?),
+        }
+    }
+
+    Some(uids)
+}
+
+fn uid_set(uids: &[u32]) -> String {
+    uids.iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Quote a folder name for use in a raw command
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Split a message ID into its folder and UID
+fn parse_id(id: &str) -> Option<(&str, u32)> {
+    let (folder, uid) = id.rsplit_once('/')?;
+    if folder.is_empty() {
+        return None;
+    }
+    Some((folder, uid.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_and_uid_sets() {
+        assert_eq!(parse_id("INBOX/42"), Some(("INBOX", 42)));
+        assert_eq!(parse_id("Projects/Alpha/7"), Some(("Projects/Alpha", 7)));
+        assert_eq!(parse_id("INBOX"), None);
+        assert_eq!(parse_id("/7"), None);
+
+        assert_eq!(parse_uid_set("1:3,7"), Some(vec![1, 2, 3, 7]));
+        assert_eq!(parse_uid_set("x"), None);
+
+        let mapping = copy_uids("* OK [COPYUID 1700 4,6:7 10:12] Moved\r\n").unwrap();
+        assert_eq!(mapping.get(&4), Some(&10));
+        assert_eq!(mapping.get(&7), Some(&12));
+        assert!(copy_uids("* 3 EXPUNGE\r\n").is_none());
+
+        assert_eq!(quote("Old \"stuff\""), "\"Old \\\"stuff\\\"\"");
+    }
+}
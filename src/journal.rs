@@ -0,0 +1,229 @@
+use crate::backend::{FlagDates, FlagStatus};
+use crate::rules::RuleAction;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A single action applied to a message during a clean run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// ID shared by all entries written during the same run
+    pub run_id: String,
+    pub timestamp: DateTime<Utc>,
+    /// Mailbox the message belongs to, if not the signed-in user's
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<String>,
+    /// Maildir the message belongs to, for runs on a local Maildir
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maildir: Option<PathBuf>,
+    /// IMAP account the message belongs to, for runs on an IMAP server
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imap: Option<String>,
+    /// Message ID before the action was applied
+    pub message_id: String,
+    /// Message ID after the action was applied (moves assign a new ID)
+    pub current_id: String,
+    /// ID of the folder the message was in before the action
+    pub original_folder: String,
+    pub action: RuleAction,
+    /// Position of the action among the actions of its rule, for rules with several
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_first_step")]
+    pub step: usize,
+    /// Categories the message had before a category action, so they can be restored
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub categories: Option<Vec<String>>,
+    /// Flag status the message had before a flag action, so it can be restored
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flag_status: Option<FlagStatus>,
+    /// Start and due date the flag had before a flag action
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flag_dates: Option<FlagDates>,
+    /// Whether the message was read before a mark_read action, so only unread ones are reverted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_read: Option<bool>,
+    /// Set on entries appended by `mailsweep undo` once the action was reverted
+    #[serde(default)]
+    pub undone: bool,
+}
+
+fn is_first_step(step: &usize) -> bool {
+    *step == 0
+}
+
+/// Summary of a recorded run
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub started: DateTime<Utc>,
+    pub actions: usize,
+    pub undone: usize,
+}
+
+/// Append-only journal of applied actions, stored as JSON lines in the config dir
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn open() -> Result<Self> {
+        let path = crate::config::place_config_file("journal.jsonl")?;
+        Ok(Self::at(path))
+    }
+
+    /// Use a journal file at a specific path
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Generate a new run ID
+    pub fn new_run_id() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    /// Append entries to the journal
+    pub fn append(&self, entries: &[JournalEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Read all entries, skipping lines that can't be parsed
+    pub fn entries(&self) -> Result<Vec<JournalEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = fs::read_to_string(&self.path)?;
+        Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Summarize recorded runs, oldest first
+    pub fn runs(&self) -> Result<Vec<RunSummary>> {
+        let mut runs: Vec<RunSummary> = Vec::new();
+
+        for entry in self.entries()? {
+            let index = match runs.iter().position(|r| r.run_id == entry.run_id) {
+                Some(index) => index,
+                None => {
+                    runs.push(RunSummary {
+                        run_id: entry.run_id.clone(),
+                        started: entry.timestamp,
+                        actions: 0,
+                        undone: 0,
+                    });
+                    runs.len() - 1
+                }
+            };
+
+            if entry.undone {
+                runs[index].undone += 1;
+            } else {
+                runs[index].actions += 1;
+            }
+        }
+
+        Ok(runs)
+    }
+
+    /// Entries of a run that haven't been undone yet
+    pub fn pending_entries(&self, run_id: &str) -> Result<Vec<JournalEntry>> {
+        let entries: Vec<JournalEntry> = self
+            .entries()?
+            .into_iter()
+            .filter(|e| e.run_id == run_id)
+            .collect();
+
+        // A message has one entry per action applied to it
+        let undone: HashSet<(&str, usize)> = entries
+            .iter()
+            .filter(|e| e.undone)
+            .map(|e| (e.message_id.as_str(), e.step))
+            .collect();
+
+        Ok(entries
+            .iter()
+            .filter(|e| !e.undone && !undone.contains(&(e.message_id.as_str(), e.step)))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(run_id: &str, message_id: &str, undone: bool) -> JournalEntry {
+        JournalEntry {
+            run_id: run_id.to_string(),
+            timestamp: Utc::now(),
+            mailbox: None,
+            maildir: None,
+            imap: None,
+            message_id: message_id.to_string(),
+            current_id: format!("{}-moved", message_id),
+            original_folder: "inbox".to_string(),
+            action: RuleAction::Archive,
+            step: 0,
+            categories: None,
+            flag_status: None,
+            flag_dates: None,
+            is_read: None,
+            undone,
+        }
+    }
+
+    #[test]
+    fn test_pending_entries() {
+        let path =
+            std::env::temp_dir().join(format!("mailsweep-journal-{}.jsonl", Journal::new_run_id()));
+        let journal = Journal::at(path.clone());
+
+        journal
+            .append(&[entry("run-1", "a", false), entry("run-1", "b", false)])
+            .unwrap();
+        journal.append(&[entry("run-2", "c", false)]).unwrap();
+        journal.append(&[entry("run-1", "a", true)]).unwrap();
+
+        // Only the entry that hasn't been undone is still pending
+        let pending = journal.pending_entries("run-1").unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message_id, "b");
+
+        let runs = journal.runs().unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].actions, runs[0].undone), (2, 1));
+        assert_eq!(runs[1].run_id, "run-2");
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,535 @@
+use crate::backend::{
+    BatchResult, FlagStatus, ListOptions, MailBackend, Message, well_known_folder, well_known_names,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mail_parser::MessageParser;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Folder ID of the top-level Maildir
+const INBOX: &str = "INBOX";
+
+/// A local mail store in Maildir++ layout, as used by Dovecot, offlineimap and mbsync
+/// (with `SubFolders Maildir++`). The top-level Maildir is the inbox; other folders are
+/// `.Name` directories, with `.` separating nested folders (`.Projects.Alpha`).
+///
+/// Folder IDs are folder names (`INBOX`, `Projects.Alpha`) and message IDs are the folder
+/// name followed by the file's path in the folder (`Projects.Alpha/cur/1700000000.1.host:2,S`).
+pub struct Maildir {
+    root: PathBuf,
+}
+
+impl Maildir {
+    pub fn open(path: &Path) -> Result<Self> {
+        if !path.join("cur").is_dir() || !path.join("new").is_dir() {
+            anyhow::bail!(
+                "'{}' is not a Maildir (missing cur or new directory)",
+                path.display()
+            );
+        }
+
+        Ok(Self {
+            root: path.to_path_buf(),
+        })
+    }
+
+    fn folder_dir(&self, folder_id: &str) -> PathBuf {
+        if folder_id == INBOX {
+            self.root.clone()
+        } else {
+            self.root.join(format!(".{}", folder_id))
+        }
+    }
+
+    /// Names of all folders except the inbox
+    fn folder_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_prefix('.')
+                && !name.is_empty()
+                && entry.path().join("cur").is_dir()
+            {
+                names.push(name.to_string());
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// Find an existing folder by name, ignoring case
+    fn find_folder(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .folder_names()?
+            .into_iter()
+            .find(|existing| existing.to_lowercase() == name.to_lowercase()))
+    }
+
+    /// Create a folder with its cur, new and tmp directories
+    fn create_folder(&self, name: &str) -> Result<String> {
+        let dir = self.folder_dir(name);
+        for subdir in ["cur", "new", "tmp"] {
+            fs::create_dir_all(dir.join(subdir))?;
+        }
+        // Marks the directory as a Maildir++ folder for Dovecot
+        fs::write(dir.join("maildirfolder"), "")?;
+
+        Ok(name.to_string())
+    }
+
+    /// Read and parse a message file. Only the headers are read unless `full` is set,
+    /// which is needed to tell whether the message has attachments.
+    fn read_message(
+        &self,
+        folder_id: &str,
+        subdir: &str,
+        file_name: &str,
+        full: bool,
+    ) -> Result<Message> {
+        let path = self.folder_dir(folder_id).join(subdir).join(file_name);
+        let raw = if full {
+            fs::read(&path)?
+        } else {
+            read_headers(&path)?
+        };
+        let parser = MessageParser::default();
+        let parsed = if full {
+            parser.parse(&raw)
+        } else {
+            parser.parse_headers(&raw)
+        }
+        .ok_or_else(|| anyhow::anyhow!("Can't parse message {}", path.display()))?;
+
+        let (sender, sender_email) = sender(&parsed);
+
+        // The Date header is set by the sender, so use the date the receiving server
+        // added in the topmost Received header, or else the time the file was delivered
+        let received_date = parsed
+            .received()
+            .and_then(|received| received.date.as_ref())
+            .and_then(|date| DateTime::from_timestamp(date.to_timestamp(), 0))
+            .or_else(|| {
+                fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .map(DateTime::<Utc>::from)
+            })
+            .unwrap_or_else(Utc::now);
+
+        Ok(Message {
+            id: format!("{}/{}/{}", folder_id, subdir, file_name),
+            subject: parsed.subject().unwrap_or("(No subject)").to_string(),
+            sender,
+            sender_address: sender_email,
+            received_date,
+            is_read: flags(file_name).contains('S'),
+            has_attachments: parsed.attachment_count() > 0,
+            categories: Vec::new(),
+            flag_status: if flags(file_name).contains('F') {
+                FlagStatus::Flagged
+            } else {
+                FlagStatus::NotFlagged
+            },
+            flag_dates: None,
+            folder_id: folder_id.to_string(),
+            matched_rule: None,
+            actions: Vec::new(),
+        })
+    }
+
+    /// Rename message files, collecting the old and new IDs of the ones that succeeded.
+    /// `target` maps a message ID to its new folder, subdirectory and file name.
+    fn rename_messages<F>(&self, messages: &[&Message], target: F) -> BatchResult
+    where
+        F: Fn(&str, &str, &str) -> (String, String, String),
+    {
+        let mut result = BatchResult::default();
+
+        for message in messages {
+            let renamed = parse_id(&message.id).and_then(|(folder, subdir, file_name)| {
+                let (new_folder, new_subdir, new_file_name) = target(folder, subdir, file_name);
+                let from = self.folder_dir(folder).join(subdir).join(file_name);
+                let to = self
+                    .folder_dir(&new_folder)
+                    .join(&new_subdir)
+                    .join(&new_file_name);
+
+                if from != to {
+                    if to.exists() {
+                        anyhow::bail!("{} already exists", to.display());
+                    }
+                    fs::rename(&from, &to)?;
+                }
+
+                Ok(format!("{}/{}/{}", new_folder, new_subdir, new_file_name))
+            });
+
+            match renamed {
+                Ok(new_id) => result.succeeded.push((message.id.clone(), new_id)),
+                Err(e) => {
+                    result.failed += 1;
+                    eprintln!("Error processing message {}: {}", message.id, e);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl MailBackend for Maildir {
+    async fn resolve_folder(&self, path: &str, create_missing: bool) -> Result<String> {
+        let segments: Vec<&str> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let name = match segments.as_slice() {
+            [] => anyhow::bail!("Folder path cannot be empty"),
+            [segment] if well_known_folder(segment) == Some("inbox") => {
+                return Ok(INBOX.to_string());
+            }
+            // Folders below the inbox are top-level folders in Maildir++
+            [first, rest @ ..] if well_known_folder(first) == Some("inbox") => rest.join("."),
+            [segment] if well_known_folder(segment).is_some() => {
+                let names = well_known_names(well_known_folder(segment).unwrap());
+                for name in names {
+                    if let Some(existing) = self.find_folder(name)? {
+                        return Ok(existing);
+                    }
+                }
+                names[0].to_string()
+            }
+            _ => segments.join("."),
+        };
+
+        match self.find_folder(&name)? {
+            Some(existing) => Ok(existing),
+            None if create_missing => self.create_folder(&name),
+            None => anyhow::bail!(
+                "Folder '{}' not found (resolving '{}'). Use --create-folders to create it.",
+                name,
+                path
+            ),
+        }
+    }
+
+    async fn list_subfolders(&self, folder_id: &str) -> Result<Vec<String>> {
+        // Maildir++ has no folders below the inbox
+        if folder_id == INBOX {
+            return Ok(Vec::new());
+        }
+
+        let prefix = format!("{}.", folder_id);
+        Ok(self
+            .folder_names()?
+            .into_iter()
+            .filter(|name| name.starts_with(&prefix))
+            .collect())
+    }
+
+    async fn list_messages(&self, folder_id: &str, options: &ListOptions) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        // Attachments are only found by reading the whole message
+        let full = options
+            .rules
+            .iter()
+            .any(|rule| rule.has_attachments.is_some());
+
+        for subdir in ["new", "cur"] {
+            let dir = self.folder_dir(folder_id).join(subdir);
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if file_name.starts_with('.') || !entry.path().is_file() {
+                    continue;
+                }
+
+                match self.read_message(folder_id, subdir, &file_name, full) {
+                    Ok(message) => messages.push(message),
+                    Err(e) => tracing::warn!("Skipping message {}: {}", file_name, e),
+                }
+            }
+        }
+
+        // Newest first, like Microsoft Graph
+        messages.sort_by_key(|message| std::cmp::Reverse(message.received_date));
+        if let Some(limit) = options.limit {
+            messages.truncate(limit);
+        }
+
+        Ok(messages)
+    }
+
+    async fn move_messages(&self, messages: &[&Message], folder_id: &str) -> Result<BatchResult> {
+        if !self.folder_dir(folder_id).join("cur").is_dir() {
+            anyhow::bail!("Folder '{}' does not exist", folder_id);
+        }
+
+        Ok(self.rename_messages(messages, |_, subdir, file_name| {
+            (
+                folder_id.to_string(),
+                subdir.to_string(),
+                file_name.to_string(),
+            )
+        }))
+    }
+
+    async fn delete_messages(&self, messages: &[&Message]) -> Result<BatchResult> {
+        // Move to the trash folder rather than removing the files, so runs can be undone
+        let trash = self.resolve_folder("deleted", true).await?;
+        self.move_messages(messages, &trash).await
+    }
+
+    async fn mark_read(&self, messages: &[&Message], read: bool) -> Result<BatchResult> {
+        Ok(self.rename_messages(messages, |folder, subdir, file_name| {
+            // Messages that have been seen belong in cur
+            let subdir = if read { "cur" } else { subdir };
+            (
+                folder.to_string(),
+                subdir.to_string(),
+                with_flag(file_name, 'S', read),
+            )
+        }))
+    }
+}
+
+/// Read the header section of a message file, up to and including the first blank line
+fn read_headers(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut headers = Vec::new();
+    loop {
+        let start = headers.len();
+        if reader.read_until(b'\n', &mut headers)? == 0 {
+            break;
+        }
+        if matches!(&headers[start..], b"\n" | b"\r\n") {
+            break;
+        }
+    }
+
+    Ok(headers)
+}
+
+/// Sender of a parsed message for display ("Name <address>") and its address
+pub fn sender(message: &mail_parser::Message) -> (String, String) {
+    let from = message.from().and_then(|from| from.first());
+    let address = from
+        .and_then(|addr| addr.address())
+        .unwrap_or("unknown")
+        .to_string();
+    let display = match from.and_then(|addr| addr.name()) {
+        Some(name) if name != address => format!("{} <{}>", name, address),
+        _ => address.clone(),
+    };
+
+    (display, address)
+}
+
+/// Split a message ID into its folder, subdirectory and file name
+fn parse_id(id: &str) -> Result<(&str, &str, &str)> {
+    let mut parts = id.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(folder), Some(subdir @ ("cur" | "new")), Some(file_name))
+            if !folder.is_empty()
+                && !folder.contains("..")
+                && !file_name.is_empty()
+                && !file_name.contains('/') =>
+        {
+            Ok((folder, subdir, file_name))
+        }
+        _ => anyhow::bail!("Invalid Maildir message ID '{}'", id),
+    }
+}
+
+/// Flags of a message file name (the part after ":2,")
+fn flags(file_name: &str) -> &str {
+    file_name
+        .rsplit_once(":2,")
+        .map(|(_, flags)| flags)
+        .unwrap_or("")
+}
+
+/// File name with a flag set or cleared, keeping the flags sorted as Maildir requires
+fn with_flag(file_name: &str, flag: char, set: bool) -> String {
+    let base = file_name
+        .rsplit_once(":2,")
+        .map(|(base, _)| base)
+        .unwrap_or(file_name);
+
+    let mut flags: Vec<char> = flags(file_name).chars().filter(|&c| c != flag).collect();
+    if set {
+        flags.push(flag);
+    }
+    flags.sort_unstable();
+
+    format!("{}:2,{}", base, flags.into_iter().collect::<String>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver(
+        root: &Path,
+        folder: &str,
+        subdir: &str,
+        file_name: &str,
+        from: &str,
+        subject: &str,
+    ) {
+        let dir = if folder == INBOX {
+            root.to_path_buf()
+        } else {
+            root.join(format!(".{}", folder))
+        };
+        fs::write(
+            dir.join(subdir).join(file_name),
+            format!(
+                "From: {}\r\nSubject: {}\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000\r\n\r\nHello\r\n",
+                from, subject
+            ),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maildir_operations() {
+        let root = tempfile::tempdir().unwrap();
+        for subdir in ["cur", "new", "tmp"] {
+            fs::create_dir_all(root.path().join(subdir)).unwrap();
+        }
+        deliver(
+            root.path(),
+            INBOX,
+            "new",
+            "1.a.host",
+            "Shop <shop@example.com>",
+            "Sale",
+        );
+        deliver(
+            root.path(),
+            INBOX,
+            "cur",
+            "2.b.host:2,S",
+            "boss@example.com",
+            "Meeting",
+        );
+
+        let maildir = Maildir::open(root.path()).unwrap();
+        let options = ListOptions {
+            limit: None,
+            page_size: 50,
+            rules: &[],
+        };
+
+        let messages = maildir.list_messages(INBOX, &options).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        let sale = messages.iter().find(|m| m.subject == "Sale").unwrap();
+        assert_eq!(sale.sender, "Shop <shop@example.com>");
+        assert_eq!(sale.sender_address, "shop@example.com");
+        assert!(!sale.is_read);
+        let meeting = messages.iter().find(|m| m.subject == "Meeting").unwrap();
+        assert!(meeting.is_read);
+
+        // Marking as read moves the message to cur and sets the S flag
+        let result = maildir.mark_read(&[sale], true).await.unwrap();
+        assert_eq!(
+            result.succeeded,
+            vec![(
+                "INBOX/new/1.a.host".to_string(),
+                "INBOX/cur/1.a.host:2,S".to_string()
+            )]
+        );
+
+        // Custom folders are only created when asked to, and "inbox" is the top-level Maildir
+        assert!(
+            maildir
+                .resolve_folder("Projects/Alpha", false)
+                .await
+                .is_err()
+        );
+        let alpha = maildir
+            .resolve_folder("Projects/Alpha", true)
+            .await
+            .unwrap();
+        assert_eq!(alpha, "Projects.Alpha");
+        assert_eq!(maildir.resolve_folder("inbox", false).await.unwrap(), INBOX);
+
+        // Deleting creates the Trash folder, which is then found by its well-known name
+        let result = maildir.delete_messages(&[meeting]).await.unwrap();
+        assert_eq!(result.succeeded[0].1, "Trash/cur/2.b.host:2,S");
+        assert!(root.path().join(".Trash/cur/2.b.host:2,S").is_file());
+        assert_eq!(
+            maildir.resolve_folder("trash", false).await.unwrap(),
+            "Trash"
+        );
+
+        assert_eq!(with_flag("1.a.host:2,FS", 'S', false), "1.a.host:2,F");
+        assert_eq!(with_flag("1.a.host:2,T", 'S', true), "1.a.host:2,ST");
+    }
+
+    #[tokio::test]
+    async fn test_received_date_and_attachments() {
+        let root = tempfile::tempdir().unwrap();
+        for subdir in ["cur", "new", "tmp"] {
+            fs::create_dir_all(root.path().join(subdir)).unwrap();
+        }
+        // An old Date header doesn't make a message old, the Received header does
+        fs::write(
+            root.path().join("new/1.a.host"),
+            "Received: from mx.example.com by mail.local; Tue, 2 Jan 2024 10:00:00 +0000\r\n\
+             From: a@example.com\r\nSubject: Report\r\nDate: Mon, 1 Jan 2001 10:00:00 +0000\r\n\
+             Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+             --b\r\nContent-Type: text/plain\r\n\r\nSee attached\r\n\
+             --b\r\nContent-Type: application/pdf\r\nContent-Disposition: attachment; filename=r.pdf\r\n\r\nJVBERi0=\r\n\
+             --b--\r\n",
+        )
+        .unwrap();
+        deliver(root.path(), INBOX, "new", "2.b.host", "b@example.com", "Hi");
+
+        let maildir = Maildir::open(root.path()).unwrap();
+        let options = ListOptions {
+            limit: None,
+            page_size: 50,
+            rules: &[],
+        };
+        let messages = maildir.list_messages(INBOX, &options).await.unwrap();
+        let report = messages.iter().find(|m| m.subject == "Report").unwrap();
+        assert_eq!(
+            report.received_date,
+            DateTime::parse_from_rfc3339("2024-01-02T10:00:00Z").unwrap()
+        );
+        // Only the headers were read
+        assert!(!report.has_attachments);
+        // Without a Received header, the file's delivery time counts
+        let hi = messages.iter().find(|m| m.subject == "Hi").unwrap();
+        assert!(Utc::now() - hi.received_date < chrono::Duration::hours(1));
+
+        let attachments = crate::rules::Rule {
+            name: "Attachments".to_string(),
+            has_attachments: Some(true),
+            ..Default::default()
+        };
+        let options = ListOptions {
+            limit: None,
+            page_size: 50,
+            rules: &[&attachments],
+        };
+        let messages = maildir.list_messages(INBOX, &options).await.unwrap();
+        let report = messages.iter().find(|m| m.subject == "Report").unwrap();
+        assert!(report.has_attachments);
+    }
+}
@@ -1,8 +1,14 @@
 mod auth;
+mod backend;
 mod commands;
 mod config;
 mod debug_auth;
+mod delta;
 mod graph_client;
+mod imap;
+mod journal;
+mod maildir;
+mod outlook;
 mod rules;
 
 use clap::Parser;
@@ -16,23 +22,38 @@ use commands::Commands;
     version
 )]
 struct Cli {
+    /// Profile to use, each with its own login and rules
+    #[arg(long, global = true, env = "MAILSWEEP_PROFILE")]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    // Initialize logging, on stderr so it doesn't mix with machine-readable output
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
     // Parse command line arguments
     let cli = Cli::parse();
 
+    // Select the profile before any profile-specific files are accessed
+    if let Some(profile) = &cli.profile {
+        config::set_profile(profile)?;
+    }
+    config::load_settings()?;
+
     // Execute the specified command
     match cli.command {
         Commands::Auth(cmd) => cmd.execute().await,
         Commands::Rules(cmd) => cmd.execute().await,
         Commands::Clean(cmd) => cmd.execute().await,
+        Commands::Watch(cmd) => cmd.execute().await,
+        Commands::Undo(cmd) => cmd.execute().await,
+        Commands::Analyze(cmd) => cmd.execute().await,
         Commands::Completions(cmd) => cmd.execute(),
     }
 }
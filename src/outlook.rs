@@ -0,0 +1,514 @@
+use crate::backend::folder_key;
+use crate::graph_client::MessageRule;
+use crate::rules::{PatternSet, REGEX_PREFIX, Rule, RuleAction};
+use serde_json::{Map, Value, json};
+use std::collections::{BTreeMap, HashMap};
+
+/// Prefix of the names of inbox rules created by `rules push-outlook`, marking them as
+/// managed by mailsweep
+pub const TAG: &str = "[mailsweep] ";
+
+/// Name of the mailsweep rule an inbox rule was pushed from, if it was
+pub fn pushed_rule_name(outlook: &MessageRule) -> Option<&str> {
+    outlook.display_name.strip_prefix(TAG)
+}
+
+/// Result of converting an Outlook inbox rule to a mailsweep rule
+#[derive(Debug, Default)]
+pub struct Conversion {
+    /// The converted rule, or `None` if the Outlook rule can't be represented
+    pub rule: Option<Rule>,
+    /// Conditions, exceptions and actions that couldn't be converted
+    pub unsupported: Vec<String>,
+}
+
+/// IDs of the folders the rules move messages to, to look up their paths
+pub fn move_folder_ids(rules: &[MessageRule]) -> Vec<String> {
+    let mut ids: Vec<String> = rules
+        .iter()
+        .filter_map(|rule| rule.actions.as_ref()?.get("moveToFolder")?.as_str())
+        .map(str::to_string)
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Convert an Outlook inbox rule, given the paths of the folders it moves messages to.
+///
+/// Dropping a condition or exception would make the rule match more messages than it
+/// does in Outlook, so rules with any that can't be converted are skipped. Actions that
+/// can't be converted are left out, as long as at least one action remains.
+pub fn convert(outlook: &MessageRule, folder_paths: &HashMap<String, String>) -> Conversion {
+    let mut conversion = Conversion::default();
+    let mut rule = Rule {
+        name: outlook.display_name.trim().to_string(),
+        ..Default::default()
+    };
+    let mut skip = false;
+
+    for (name, value) in set_fields(outlook.conditions.as_ref()) {
+        match name {
+            "senderContains" => rule.sender_contains = literal_patterns(value),
+            "subjectContains" => rule.subject_contains = literal_patterns(value),
+            "fromAddresses" => rule.sender_is = addresses(value),
+            "hasAttachments" => rule.has_attachments = Some(true),
+            _ => {
+                conversion.unsupported.push(format!("condition {}", name));
+                skip = true;
+            }
+        }
+    }
+
+    for (name, value) in set_fields(outlook.exceptions.as_ref()) {
+        match name {
+            "senderContains" => rule.sender_not_contains = literal_patterns(value),
+            "subjectContains" => rule.subject_not_contains = literal_patterns(value),
+            _ => {
+                conversion.unsupported.push(format!("exception {}", name));
+                skip = true;
+            }
+        }
+    }
+
+    let mut actions = Vec::new();
+    let mut move_action = None;
+    let mut stops = false;
+    for (name, value) in set_fields(outlook.actions.as_ref()) {
+        match name {
+            "stopProcessingRules" => stops = true,
+            "markAsRead" => actions.push(RuleAction::MarkRead),
+            "assignCategories" => actions.push(RuleAction::AddCategories(
+                patterns(value).patterns().map(str::to_string).collect(),
+            )),
+            "moveToFolder" => match value.as_str().and_then(|id| folder_paths.get(id)) {
+                Some(path) if move_action.is_none() => {
+                    move_action = Some(RuleAction::MoveTo(path.clone()))
+                }
+                Some(_) => conversion
+                    .unsupported
+                    .push("action moveToFolder (the rule already deletes)".to_string()),
+                None => conversion
+                    .unsupported
+                    .push("action moveToFolder (folder not found)".to_string()),
+            },
+            "delete" => match move_action {
+                None => move_action = Some(RuleAction::Delete),
+                Some(_) => conversion
+                    .unsupported
+                    .push("action delete (the rule already moves)".to_string()),
+            },
+            _ => conversion.unsupported.push(format!("action {}", name)),
+        }
+    }
+    // The move goes last, so the other actions apply to the message first
+    actions.extend(move_action);
+
+    if pushed_rule_name(outlook).is_some() {
+        conversion
+            .unsupported
+            .insert(0, "created by mailsweep rules push-outlook".to_string());
+        skip = true;
+    } else if !outlook.is_enabled {
+        conversion
+            .unsupported
+            .insert(0, "rule is disabled in Outlook".to_string());
+        skip = true;
+    }
+    if rule.sender_contains.is_empty()
+        && rule.subject_contains.is_empty()
+        && rule.sender_is.is_empty()
+        && rule.has_attachments.is_none()
+        && !skip
+    {
+        conversion
+            .unsupported
+            .push("no conditions (mailsweep rules need at least one)".to_string());
+        skip = true;
+    }
+    if actions.is_empty() && !skip {
+        conversion
+            .unsupported
+            .push("no actions mailsweep can apply".to_string());
+        skip = true;
+    }
+
+    // mailsweep always stops at the first matching rule, so later rules no longer apply
+    if !stops && !skip {
+        conversion.unsupported.push(
+            "later rules also applying (mailsweep stops at the first matching rule)".to_string(),
+        );
+    }
+
+    if !skip {
+        if actions.len() == 1 {
+            rule.action = actions.pop();
+        } else {
+            rule.actions = actions;
+        }
+        conversion.rule = Some(rule);
+    }
+
+    conversion
+}
+
+/// Folder paths the rule moves messages to ("archive" for the archive action), whose
+/// IDs `export` needs
+pub fn destination_paths(rule: &Rule) -> Vec<&str> {
+    rule.actions()
+        .iter()
+        .filter_map(|action| match action {
+            RuleAction::Archive => Some("archive"),
+            RuleAction::MoveTo(path) => Some(path.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Convert a mailsweep rule to a tagged Outlook inbox rule, given the IDs of the folders
+/// it moves messages to (see `destination_paths`). Returns the reasons the rule only
+/// works client-side if it can't be expressed as an inbox rule.
+pub fn export(
+    rule: &Rule,
+    sequence: i32,
+    folder_ids: &HashMap<String, String>,
+) -> Result<MessageRule, Vec<String>> {
+    let mut reasons = Vec::new();
+
+    let uses_regex = [
+        &rule.sender_contains,
+        &rule.subject_contains,
+        &rule.sender_not_contains,
+        &rule.subject_not_contains,
+    ]
+    .iter()
+    .any(|set| set.patterns().any(|p| p.starts_with(REGEX_PREFIX)));
+    if uses_regex || !rule.sender_regex.is_empty() || !rule.subject_regex.is_empty() {
+        reasons.push("regular expressions".to_string());
+    }
+    if rule.older_than.is_some() || rule.newer_than.is_some() {
+        reasons.push("older_than/newer_than".to_string());
+    }
+    if rule.is_read.is_some() {
+        reasons.push("is_read".to_string());
+    }
+    if rule.is_flagged.is_some() {
+        reasons.push("is_flagged".to_string());
+    }
+    if rule.has_attachments == Some(false) {
+        reasons.push("has_attachments: false".to_string());
+    }
+    if let Some(folder) = &rule.folder
+        && folder_key(folder) != "inbox"
+    {
+        reasons.push(format!("folder {}", folder));
+    }
+
+    let mut conditions = Map::new();
+    insert_patterns(&mut conditions, "senderContains", &rule.sender_contains);
+    insert_patterns(&mut conditions, "subjectContains", &rule.subject_contains);
+    insert_patterns(&mut conditions, "categories", &rule.has_category);
+    if !rule.sender_is.is_empty() {
+        let recipients: Vec<Value> = rule
+            .sender_is
+            .patterns()
+            .map(|address| json!({ "emailAddress": { "address": address } }))
+            .collect();
+        conditions.insert("fromAddresses".to_string(), Value::Array(recipients));
+    }
+    if rule.has_attachments == Some(true) {
+        conditions.insert("hasAttachments".to_string(), Value::Bool(true));
+    }
+
+    let mut exceptions = Map::new();
+    insert_patterns(&mut exceptions, "senderContains", &rule.sender_not_contains);
+    insert_patterns(
+        &mut exceptions,
+        "subjectContains",
+        &rule.subject_not_contains,
+    );
+
+    // Like mailsweep, stop at the first matching rule
+    let mut actions = Map::new();
+    actions.insert("stopProcessingRules".to_string(), Value::Bool(true));
+    for action in rule.actions() {
+        match action {
+            RuleAction::MarkRead => {
+                actions.insert("markAsRead".to_string(), Value::Bool(true));
+            }
+            RuleAction::Delete => {
+                actions.insert("delete".to_string(), Value::Bool(true));
+            }
+            RuleAction::AddCategories(categories) => {
+                actions.insert("assignCategories".to_string(), json!(categories));
+            }
+            RuleAction::Archive | RuleAction::MoveTo(_) => {
+                let path = match action {
+                    RuleAction::MoveTo(path) => path.as_str(),
+                    _ => "archive",
+                };
+                match folder_ids.get(path) {
+                    Some(id) => {
+                        actions.insert("moveToFolder".to_string(), Value::String(id.clone()));
+                    }
+                    None => reasons.push(format!("folder '{}' not found", path)),
+                }
+            }
+            _ => reasons.push(action.to_string()),
+        }
+    }
+
+    if !reasons.is_empty() {
+        return Err(reasons);
+    }
+
+    Ok(MessageRule {
+        id: None,
+        display_name: format!("{}{}", TAG, rule.name.trim()),
+        sequence: Some(sequence),
+        is_enabled: true,
+        conditions: Some(conditions),
+        exceptions: (!exceptions.is_empty()).then_some(exceptions),
+        actions: Some(actions),
+    })
+}
+
+/// Whether an inbox rule on the server already has the settings of an exported rule
+pub fn is_up_to_date(current: &MessageRule, exported: &MessageRule) -> bool {
+    current.is_enabled == exported.is_enabled
+        && current.sequence == exported.sequence
+        && normalized(current.conditions.as_ref()) == normalized(exported.conditions.as_ref())
+        && normalized(current.exceptions.as_ref()) == normalized(exported.exceptions.as_ref())
+        && normalized(current.actions.as_ref()) == normalized(exported.actions.as_ref())
+}
+
+/// Set fields of a predicates or actions object, with recipients reduced to their
+/// lowercased addresses, as Graph adds display names to them
+fn normalized(fields: Option<&Map<String, Value>>) -> BTreeMap<&str, Value> {
+    set_fields(fields)
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match name {
+                "fromAddresses" => addresses(value)
+                    .patterns()
+                    .map(|address| Value::String(address.to_lowercase()))
+                    .collect(),
+                _ => value.clone(),
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+fn insert_patterns(fields: &mut Map<String, Value>, name: &str, patterns: &PatternSet) {
+    if !patterns.is_empty() {
+        fields.insert(
+            name.to_string(),
+            json!(patterns.patterns().collect::<Vec<_>>()),
+        );
+    }
+}
+
+/// Fields of a predicates or actions object that are set, in a stable order.
+/// Graph returns unset fields as null, false or empty lists.
+fn set_fields(fields: Option<&Map<String, Value>>) -> Vec<(&str, &Value)> {
+    let mut set: Vec<(&str, &Value)> = fields
+        .into_iter()
+        .flatten()
+        .filter(|(name, value)| {
+            !name.starts_with('@')
+                && match value {
+                    Value::Null | Value::Bool(false) => false,
+                    Value::Array(items) => !items.is_empty(),
+                    Value::Object(object) => !object.is_empty(),
+                    _ => true,
+                }
+        })
+        .map(|(name, value)| (name.as_str(), value))
+        .collect();
+    set.sort_by_key(|(name, _)| *name);
+    set
+}
+
+/// Strings of a JSON list
+fn patterns(value: &Value) -> PatternSet {
+    PatternSet::with_patterns(
+        value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+    )
+}
+
+/// Strings of a JSON list as patterns that match them literally. Outlook's values are
+/// plain text, so ones that look like regular expressions to mailsweep are escaped.
+fn literal_patterns(value: &Value) -> PatternSet {
+    PatternSet::with_patterns(
+        patterns(value)
+            .patterns()
+            .map(|pattern| {
+                if pattern.starts_with(REGEX_PREFIX) {
+                    format!("{}{}", REGEX_PREFIX, regex::escape(pattern))
+                } else {
+                    pattern.to_string()
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Email addresses of a JSON list of Graph recipients
+fn addresses(value: &Value) -> PatternSet {
+    PatternSet::with_patterns(
+        value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r["emailAddress"]["address"].as_str().map(str::to_string))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn outlook_rule(value: Value) -> MessageRule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_convert() {
+        let folders = HashMap::from([("folder-1".to_string(), "Inbox/Receipts".to_string())]);
+
+        let receipts = outlook_rule(json!({
+            "displayName": "Receipts",
+            "sequence": 1,
+            "isEnabled": true,
+            "conditions": { "senderContains": ["shop"], "subjectContains": ["receipt"] },
+            "exceptions": { "subjectContains": ["refund"] },
+            "actions": {
+                "markAsRead": true,
+                "moveToFolder": "folder-1",
+                "forwardTo": [{ "emailAddress": { "address": "me@example.com" } }],
+                "stopProcessingRules": true
+            }
+        }));
+        let conversion = convert(&receipts, &folders);
+        let rule = conversion.rule.unwrap();
+        assert_eq!(rule.sender_contains.to_vec(), vec!["shop"]);
+        assert_eq!(rule.subject_contains.to_vec(), vec!["receipt"]);
+        assert_eq!(rule.subject_not_contains.to_vec(), vec!["refund"]);
+        assert_eq!(
+            rule.actions(),
+            [
+                RuleAction::MarkRead,
+                RuleAction::MoveTo("Inbox/Receipts".to_string())
+            ]
+        );
+        assert_eq!(conversion.unsupported, vec!["action forwardTo"]);
+
+        // A condition that can't be converted would widen the rule, so it's skipped
+        let body = outlook_rule(json!({
+            "displayName": "Body",
+            "isEnabled": true,
+            "conditions": { "senderContains": ["x"], "bodyContains": ["y"], "isAutomaticReply": false },
+            "actions": { "delete": true }
+        }));
+        let conversion = convert(&body, &folders);
+        assert!(conversion.rule.is_none());
+        assert_eq!(conversion.unsupported, vec!["condition bodyContains"]);
+
+        let from = outlook_rule(json!({
+            "displayName": "From",
+            "isEnabled": true,
+            "conditions": { "fromAddresses": [{ "emailAddress": { "address": "ceo@example.com" } }] },
+            "actions": { "delete": true, "moveToFolder": "folder-1" }
+        }));
+        let conversion = convert(&from, &folders);
+        assert_eq!(conversion.rule.unwrap().actions(), [RuleAction::Delete]);
+        // Without stopProcessingRules, later Outlook rules apply too
+        assert_eq!(
+            conversion.unsupported,
+            vec![
+                "action moveToFolder (the rule already deletes)",
+                "later rules also applying (mailsweep stops at the first matching rule)"
+            ]
+        );
+
+        // Outlook's text that looks like a regular expression to mailsweep stays literal
+        let replies = outlook_rule(json!({
+            "displayName": "Invoice replies",
+            "isEnabled": true,
+            "conditions": { "subjectContains": ["re: invoice (2024)"] },
+            "actions": { "markAsRead": true, "stopProcessingRules": true }
+        }));
+        let conversion = convert(&replies, &folders);
+        assert!(conversion.unsupported.is_empty());
+        let rule = conversion.rule.unwrap();
+        assert_eq!(
+            rule.subject_contains.to_vec(),
+            vec![r"re:re: invoice \(2024\)"]
+        );
+        let message = |subject: &str| crate::backend::Message {
+            subject: subject.to_string(),
+            ..Default::default()
+        };
+        assert!(rule.matches(&message("RE: Invoice (2024) #12")));
+        assert!(!rule.matches(&message("re: invoice 2024")));
+
+        assert_eq!(
+            move_folder_ids(&[receipts, body, from]),
+            vec!["folder-1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let rules = crate::rules::Rules::parse(
+            "- name: Receipts\n  sender_is: [Shop@Example.com]\n  subject_not_contains: [refund]\n  actions:\n    - mark_read\n    - move_to: Finance/Receipts\n- name: Old CI\n  sender_contains: [re:^ci@]\n  older_than: 7d\n  action: delete\n",
+        )
+        .unwrap();
+        let folders = HashMap::from([("Finance/Receipts".to_string(), "folder-1".to_string())]);
+
+        assert_eq!(destination_paths(&rules.items[0]), vec!["Finance/Receipts"]);
+        let exported = export(&rules.items[0], 1, &folders).unwrap();
+        assert_eq!(exported.display_name, "[mailsweep] Receipts");
+        assert_eq!(pushed_rule_name(&exported), Some("Receipts"));
+        assert_eq!(
+            serde_json::to_value(&exported).unwrap(),
+            json!({
+                "displayName": "[mailsweep] Receipts",
+                "sequence": 1,
+                "isEnabled": true,
+                "conditions": { "fromAddresses": [{ "emailAddress": { "address": "Shop@Example.com" } }] },
+                "exceptions": { "subjectContains": ["refund"] },
+                "actions": { "markAsRead": true, "moveToFolder": "folder-1", "stopProcessingRules": true }
+            })
+        );
+
+        // Graph adds names to recipients and returns unset fields, which don't count as changes
+        let mut current = exported.clone();
+        current.id = Some("r1".to_string());
+        let conditions = current.conditions.as_mut().unwrap();
+        conditions["fromAddresses"] =
+            json!([{ "emailAddress": { "name": "Shop", "address": "shop@example.com" } }]);
+        conditions.insert("bodyContains".to_string(), Value::Null);
+        assert!(is_up_to_date(&current, &exported));
+        current.sequence = Some(2);
+        assert!(!is_up_to_date(&current, &exported));
+
+        // A missing folder, regular expressions and age conditions only work client-side
+        assert_eq!(
+            export(&rules.items[0], 1, &HashMap::new()).unwrap_err(),
+            vec!["folder 'Finance/Receipts' not found"]
+        );
+        assert_eq!(
+            export(&rules.items[1], 2, &folders).unwrap_err(),
+            vec!["regular expressions", "older_than/newer_than"]
+        );
+    }
+}
@@ -1,7 +1,14 @@
+use crate::backend::{FlagStatus, Message, folder_key};
 use anyhow::Result;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Prefix that marks an entry in a `*_contains` list as a regular expression
+pub const REGEX_PREFIX: &str = "re:";
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Rule {
@@ -12,61 +19,539 @@ pub struct Rule {
     #[serde(default)]
     #[serde(skip_serializing_if = "PatternSet::is_empty")]
     pub subject_contains: PatternSet,
-    pub action: RuleAction,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "PatternSet::is_empty")]
+    pub sender_regex: PatternSet,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "PatternSet::is_empty")]
+    pub subject_regex: PatternSet,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "PatternSet::is_empty")]
+    pub sender_not_contains: PatternSet,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "PatternSet::is_empty")]
+    pub subject_not_contains: PatternSet,
+    /// Only match messages from one of these exact email addresses
+    #[serde(default)]
+    #[serde(skip_serializing_if = "PatternSet::is_empty")]
+    pub sender_is: PatternSet,
+    /// Only match messages with at least one of these Outlook categories
+    #[serde(default)]
+    #[serde(skip_serializing_if = "PatternSet::is_empty")]
+    pub has_category: PatternSet,
+    /// Only match read (true) or unread (false) messages
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_read: Option<bool>,
+    /// Only match messages with (true) or without (false) attachments
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub has_attachments: Option<bool>,
+    /// Only match messages flagged for follow-up (true) or not flagged (false)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_flagged: Option<bool>,
+    /// Only match messages received longer ago than this (e.g. "30d")
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<String>,
+    /// Only match messages received within this duration (e.g. "2h")
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newer_than: Option<String>,
+    /// Folder the rule applies to (well-known name or display name path), defaults to the inbox
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    /// Action to apply to matching messages
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: Option<RuleAction>,
+    /// Several actions to apply in order, instead of a single `action`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub actions: Vec<RuleAction>,
+    /// Compiled matchers, built once on first use (see `Rule::compile`)
+    #[serde(skip)]
+    pub(crate) matchers: OnceLock<Result<RuleMatchers, String>>,
+}
+
+/// Compiled sender and subject matchers for a rule
+#[derive(Debug)]
+pub struct RuleMatchers {
+    pub sender: Vec<Matcher>,
+    pub subject: Vec<Matcher>,
+    pub sender_excluded: Vec<Matcher>,
+    pub subject_excluded: Vec<Matcher>,
+    /// Lowercased sender addresses from `sender_is`
+    pub sender_is: Vec<String>,
+    pub older_than: Option<Duration>,
+    pub newer_than: Option<Duration>,
 }
 
 impl Rule {
+    /// Actions to apply to matching messages, in order
+    pub fn actions(&self) -> &[RuleAction] {
+        match &self.action {
+            Some(action) => std::slice::from_ref(action),
+            None => &self.actions,
+        }
+    }
+
+    /// Compile the rule's patterns, caching the result for subsequent calls
+    pub fn compile(&self) -> Result<&RuleMatchers, String> {
+        self.matchers
+            .get_or_init(|| {
+                let mut sender = self.sender_contains.compile(false)?;
+                sender.extend(self.sender_regex.compile(true)?);
+
+                let mut subject = self.subject_contains.compile(false)?;
+                subject.extend(self.subject_regex.compile(true)?);
+
+                Ok(RuleMatchers {
+                    sender,
+                    subject,
+                    sender_excluded: self.sender_not_contains.compile(false)?,
+                    subject_excluded: self.subject_not_contains.compile(false)?,
+                    sender_is: self.sender_is.patterns().map(str::to_lowercase).collect(),
+                    older_than: parse_age("older_than", self.older_than.as_deref())?,
+                    newer_than: parse_age("newer_than", self.newer_than.as_deref())?,
+                })
+            })
+            .as_ref()
+            .map_err(|e| e.clone())
+    }
+
     /// Check if a message matches this rule
-    pub fn matches(&self, sender: &str, subject: &str) -> bool {
-        let sender_patterns = self.sender_contains.to_vec();
-        let subject_patterns = self.subject_contains.to_vec();
+    pub fn matches(&self, message: &Message) -> bool {
+        // Rules with invalid patterns never match (reported by validation)
+        let Ok(matchers) = self.compile() else {
+            return false;
+        };
+
+        let has_conditions = matchers.older_than.is_some()
+            || matchers.newer_than.is_some()
+            || !matchers.sender_is.is_empty()
+            || !self.has_category.is_empty()
+            || self.is_read.is_some()
+            || self.has_attachments.is_some()
+            || self.is_flagged.is_some();
 
         // Skip empty rules (should be caught by validation, but just in case)
-        if sender_patterns.is_empty() && subject_patterns.is_empty() {
+        if matchers.sender.is_empty() && matchers.subject.is_empty() && !has_conditions {
             return false;
         }
 
+        // Exact sender address and message state conditions
+        if !matchers.sender_is.is_empty()
+            && !matchers
+                .sender_is
+                .contains(&message.sender_address.to_lowercase())
+        {
+            return false;
+        }
+        if !self.has_category.is_empty()
+            && !self.has_category.patterns().any(|category| {
+                message
+                    .categories
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(category))
+            })
+        {
+            return false;
+        }
+        if self
+            .is_read
+            .is_some_and(|is_read| message.is_read != is_read)
+            || self
+                .has_attachments
+                .is_some_and(|has_attachments| message.has_attachments != has_attachments)
+            || self.is_flagged.is_some_and(|is_flagged| {
+                (message.flag_status == FlagStatus::Flagged) != is_flagged
+            })
+        {
+            return false;
+        }
+
+        // Age conditions are checked against the time the message was received
+        let age = Utc::now().signed_duration_since(message.received_date);
+        if matchers
+            .older_than
+            .is_some_and(|older_than| age <= older_than)
+            || matchers
+                .newer_than
+                .is_some_and(|newer_than| age >= newer_than)
+        {
+            return false;
+        }
+
+        let sender = message.sender.as_str();
+        let subject = message.subject.as_str();
+
         // If both pattern types are present, need to match at least one from each
-        if !sender_patterns.is_empty() && !subject_patterns.is_empty() {
-            // Check for sender match
-            let mut sender_matched = false;
-            for pattern in &sender_patterns {
-                if sender.to_lowercase().contains(&pattern.to_lowercase()) {
-                    sender_matched = true;
-                    break;
-                }
-            }
+        let sender_matched =
+            matchers.sender.is_empty() || matchers.sender.iter().any(|m| m.is_match(sender));
+        let subject_matched =
+            matchers.subject.is_empty() || matchers.subject.iter().any(|m| m.is_match(subject));
 
-            // Check for subject match
-            let mut subject_matched = false;
-            for pattern in &subject_patterns {
-                if subject.to_lowercase().contains(&pattern.to_lowercase()) {
-                    subject_matched = true;
-                    break;
-                }
-            }
+        if !(sender_matched && subject_matched) {
+            return false;
+        }
+
+        // Any exclusion pattern hit vetoes the match
+        let excluded = matchers.sender_excluded.iter().any(|m| m.is_match(sender))
+            || matchers
+                .subject_excluded
+                .iter()
+                .any(|m| m.is_match(subject));
+
+        !excluded
+    }
+
+    /// Pattern lists of the rule with their field names
+    fn pattern_fields(&self) -> [(&'static str, &PatternSet); 8] {
+        [
+            ("sender_contains", &self.sender_contains),
+            ("subject_contains", &self.subject_contains),
+            ("sender_regex", &self.sender_regex),
+            ("subject_regex", &self.subject_regex),
+            ("sender_not_contains", &self.sender_not_contains),
+            ("subject_not_contains", &self.subject_not_contains),
+            ("sender_is", &self.sender_is),
+            ("has_category", &self.has_category),
+        ]
+    }
 
-            // Both must match for the rule to apply
-            return sender_matched && subject_matched;
+    /// Describe the patterns and conditions a matching message meets, e.g.
+    /// `sender contains "news"`. Only the first hit of each pattern list is shown.
+    pub fn explain_match(&self, message: &Message) -> Vec<String> {
+        let Ok(matchers) = self.compile() else {
+            return Vec::new();
+        };
+        let mut reasons = Vec::new();
+
+        if let Some(matcher) = matchers.sender.iter().find(|m| m.is_match(&message.sender)) {
+            reasons.push(format!("sender {}", matcher));
+        }
+        if let Some(matcher) = matchers
+            .subject
+            .iter()
+            .find(|m| m.is_match(&message.subject))
+        {
+            reasons.push(format!("subject {}", matcher));
+        }
+        if !matchers.sender_is.is_empty() {
+            reasons.push(format!(
+                "sender is {}",
+                message.sender_address.to_lowercase()
+            ));
+        }
+        if let Some(category) = self.has_category.patterns().find(|category| {
+            message
+                .categories
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(category))
+        }) {
+            reasons.push(format!("has category \"{}\"", category));
         }
-        // If only sender patterns exist
-        else if !sender_patterns.is_empty() {
-            for pattern in &sender_patterns {
-                if sender.to_lowercase().contains(&pattern.to_lowercase()) {
-                    return true;
+        if let Some(is_read) = self.is_read {
+            reasons.push(if is_read { "is read" } else { "is unread" }.to_string());
+        }
+        if let Some(has_attachments) = self.has_attachments {
+            reasons.push(
+                if has_attachments {
+                    "has attachments"
+                } else {
+                    "has no attachments"
                 }
-            }
+                .to_string(),
+            );
         }
-        // If only subject patterns exist
-        else if !subject_patterns.is_empty() {
-            for pattern in &subject_patterns {
-                if subject.to_lowercase().contains(&pattern.to_lowercase()) {
-                    return true;
+        if let Some(is_flagged) = self.is_flagged {
+            reasons.push(
+                if is_flagged {
+                    "is flagged"
+                } else {
+                    "is not flagged"
                 }
-            }
+                .to_string(),
+            );
+        }
+        if let Some(older_than) = &self.older_than {
+            reasons.push(format!("older than {}", older_than.trim()));
+        }
+        if let Some(newer_than) = &self.newer_than {
+            reasons.push(format!("newer than {}", newer_than.trim()));
+        }
+
+        reasons
+    }
+
+    /// Whether every message this rule can match is also matched by `self`, so a later
+    /// `other` never fires. Only reports subsumption it can prove from the patterns.
+    pub fn subsumes(&self, other: &Rule) -> bool {
+        let (Ok(this), Ok(that)) = (self.compile(), other.compile()) else {
+            return false;
+        };
+
+        let same_folder = folder_key(self.folder.as_deref().unwrap_or("inbox"))
+            == folder_key(other.folder.as_deref().unwrap_or("inbox"));
+        if !same_folder {
+            return false;
+        }
+
+        // Each condition of this rule must hold for every message the other matches
+        let sender_covered = this.sender.is_empty()
+            || (!that.sender.is_empty() && covers_all(&this.sender, &that.sender))
+            // The sender text contains the address, so sender_is implies the patterns it contains
+            || (!that.sender_is.is_empty()
+                && that.sender_is.iter().all(|address| {
+                    this.sender
+                        .iter()
+                        .any(|m| matches!(m, Matcher::Contains(p) if address.contains(p.as_str())))
+                }));
+        let subject_covered = this.subject.is_empty()
+            || (!that.subject.is_empty() && covers_all(&this.subject, &that.subject));
+        let sender_is_covered = this.sender_is.is_empty()
+            || (!that.sender_is.is_empty()
+                && that.sender_is.iter().all(|a| this.sender_is.contains(a)));
+        let categories_covered = self.has_category.is_empty()
+            || (!other.has_category.is_empty()
+                && other.has_category.patterns().all(|c| {
+                    self.has_category
+                        .patterns()
+                        .any(|own| own.eq_ignore_ascii_case(c))
+                }));
+        let state_covered = (self.is_read.is_none() || self.is_read == other.is_read)
+            && (self.has_attachments.is_none() || self.has_attachments == other.has_attachments)
+            && (self.is_flagged.is_none() || self.is_flagged == other.is_flagged);
+        let age_covered = this
+            .older_than
+            .is_none_or(|own| that.older_than.is_some_and(|o| o >= own))
+            && this
+                .newer_than
+                .is_none_or(|own| that.newer_than.is_some_and(|o| o <= own));
+
+        // A message excluded here must also be excluded by the other rule
+        let exclusions_covered = covers_all(&that.sender_excluded, &this.sender_excluded)
+            && covers_all(&that.subject_excluded, &this.subject_excluded);
+
+        sender_covered
+            && subject_covered
+            && sender_is_covered
+            && categories_covered
+            && state_covered
+            && age_covered
+            && exclusions_covered
+    }
+
+    /// Whether some message could match both this rule and `other`. Only rules it can
+    /// prove to be disjoint from their folders, states, addresses or ages are reported
+    /// as not overlapping.
+    pub fn may_overlap(&self, other: &Rule) -> bool {
+        let (Ok(this), Ok(that)) = (self.compile(), other.compile()) else {
+            return true;
+        };
+
+        let same_folder = folder_key(self.folder.as_deref().unwrap_or("inbox"))
+            == folder_key(other.folder.as_deref().unwrap_or("inbox"));
+        let conflicts =
+            |a: Option<bool>, b: Option<bool>| matches!((a, b), (Some(a), Some(b)) if a != b);
+        let conflicting_state = conflicts(self.is_read, other.is_read)
+            || conflicts(self.has_attachments, other.has_attachments)
+            || conflicts(self.is_flagged, other.is_flagged);
+        let disjoint_senders = !this.sender_is.is_empty()
+            && !that.sender_is.is_empty()
+            && !this.sender_is.iter().any(|a| that.sender_is.contains(a));
+        // One rule only matches messages older than the other's newest
+        let disjoint_age = matches!((this.older_than, that.newer_than), (Some(o), Some(n)) if o >= n)
+            || matches!((that.older_than, this.newer_than), (Some(o), Some(n)) if o >= n);
+
+        same_folder && !conflicting_state && !disjoint_senders && !disjoint_age
+    }
+
+    /// Translate the rule's server-side filterable conditions (sender address,
+    /// categories, age, read, flag and attachment state) into an OData `$filter` expression.
+    ///
+    /// The filter only narrows down candidates; `matches` still makes the final
+    /// decision. Returns `None` if the rule has no such conditions, meaning every
+    /// message is a candidate.
+    pub fn graph_filter(&self, now: DateTime<Utc>) -> Option<String> {
+        let matchers = self.compile().ok()?;
+        let mut clauses = Vec::new();
+
+        if !matchers.sender_is.is_empty() {
+            let addresses: Vec<String> = matchers
+                .sender_is
+                .iter()
+                .map(|address| {
+                    format!(
+                        "from/emailAddress/address eq '{}'",
+                        address.replace('\'', "''")
+                    )
+                })
+                .collect();
+            clauses.push(if addresses.len() == 1 {
+                addresses[0].clone()
+            } else {
+                format!("({})", addresses.join(" or "))
+            });
+        }
+
+        if !self.has_category.is_empty() {
+            let categories: Vec<String> = self
+                .has_category
+                .patterns()
+                .map(|category| {
+                    format!("categories/any(c:c eq '{}')", category.replace('\'', "''"))
+                })
+                .collect();
+            clauses.push(if categories.len() == 1 {
+                categories[0].clone()
+            } else {
+                format!("({})", categories.join(" or "))
+            });
+        }
+
+        if let Some(older_than) = matchers.older_than {
+            clauses.push(format!(
+                "receivedDateTime le {}",
+                (now - older_than).to_rfc3339_opts(SecondsFormat::Secs, true)
+            ));
+        }
+        if let Some(newer_than) = matchers.newer_than {
+            clauses.push(format!(
+                "receivedDateTime ge {}",
+                (now - newer_than).to_rfc3339_opts(SecondsFormat::Secs, true)
+            ));
         }
+        if let Some(is_read) = self.is_read {
+            clauses.push(format!("isRead eq {}", is_read));
+        }
+        if let Some(has_attachments) = self.has_attachments {
+            clauses.push(format!("hasAttachments eq {}", has_attachments));
+        }
+        if let Some(is_flagged) = self.is_flagged {
+            clauses.push(format!(
+                "flag/flagStatus {} 'flagged'",
+                if is_flagged { "eq" } else { "ne" }
+            ));
+        }
+
+        if clauses.is_empty() {
+            None
+        } else {
+            Some(clauses.join(" and "))
+        }
+    }
+}
+
+/// Parse the due date of a flag, relative to when the message was received (e.g. "+2d")
+pub fn parse_due(value: &str) -> Result<Duration, String> {
+    let trimmed = value.trim();
+    let duration = humantime::parse_duration(trimmed.strip_prefix('+').unwrap_or(trimmed))
+        .map_err(|e| format!("invalid flag_due duration '{}': {}", value, e))?;
+    Duration::from_std(duration).map_err(|_| format!("flag_due duration '{}' is too large", value))
+}
+
+/// Whether a pattern is short enough to match far more than intended: at most two
+/// characters of text or of a regex without its anchors, or a regex of only wildcards
+fn is_broad_pattern(field: &str, pattern: &str) -> bool {
+    let body = match pattern.strip_prefix(REGEX_PREFIX) {
+        Some(source) => source,
+        None if field.ends_with("_regex") => pattern,
+        None => return pattern.chars().count() <= 2,
+    };
+
+    let body = body.trim_start_matches('^').trim_end_matches('$');
+    body.chars().count() <= 2 || body.chars().all(|c| matches!(c, '.' | '*' | '+' | '?'))
+}
+
+/// Parse a human-readable duration such as "30d" or "2h" for an age condition
+fn parse_age(field: &str, value: Option<&str>) -> Result<Option<Duration>, String> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    let duration = humantime::parse_duration(value.trim())
+        .map_err(|e| format!("invalid {} duration '{}': {}", field, value, e))?;
+    let duration = Duration::from_std(duration)
+        .map_err(|_| format!("{} duration '{}' is too large", field, value))?;
 
-        false
+    Ok(Some(duration))
+}
+
+/// A single compiled pattern
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// Case-insensitive substring match (stored lowercased)
+    Contains(String),
+    /// Case-insensitive regular expression
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Contains(pattern) => text.to_lowercase().contains(pattern),
+            Matcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+impl std::fmt::Display for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Matcher::Contains(pattern) => write!(f, "contains \"{}\"", pattern),
+            Matcher::Regex(regex) => write!(f, "matches /{}/", regex.as_str()),
+        }
+    }
+}
+
+impl Matcher {
+    /// Whether any text this matcher's `other` matches is also matched by this one.
+    /// Regular expressions only cover identical ones.
+    pub fn covers(&self, other: &Matcher) -> bool {
+        match (self, other) {
+            (Matcher::Contains(own), Matcher::Contains(other)) => other.contains(own.as_str()),
+            (Matcher::Regex(own), Matcher::Regex(other)) => own.as_str() == other.as_str(),
+            _ => false,
+        }
+    }
+}
+
+/// Whether each of `others` is covered by one of `matchers`
+fn covers_all(matchers: &[Matcher], others: &[Matcher]) -> bool {
+    others
+        .iter()
+        .all(|other| matchers.iter().any(|m| m.covers(other)))
+}
+
+/// The first of `rules` that matches a message, which is the rule `clean` applies
+pub fn first_match<'a>(rules: &[&'a Rule], message: &Message) -> Option<&'a Rule> {
+    rules.iter().copied().find(|rule| rule.matches(message))
+}
+
+impl std::fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleAction::Archive => write!(f, "archive"),
+            RuleAction::Delete => write!(f, "delete"),
+            RuleAction::MarkRead => write!(f, "mark_read"),
+            RuleAction::MoveTo(path) => write!(f, "move_to:{}", path),
+            RuleAction::AddCategories(categories) => {
+                write!(f, "add_categories:{}", categories.join(","))
+            }
+            RuleAction::RemoveCategories(categories) => {
+                write!(f, "remove_categories:{}", categories.join(","))
+            }
+            RuleAction::Flag => write!(f, "flag"),
+            RuleAction::FlagDue(due) => write!(f, "flag_due:{}", due),
+            RuleAction::CompleteFlag => write!(f, "complete_flag"),
+            RuleAction::ClearFlag => write!(f, "clear_flag"),
+        }
     }
 }
 
@@ -109,12 +594,50 @@ impl PatternSet {
         self.0.is_empty() || self.0.iter().all(|s| s.trim().is_empty())
     }
 
+    /// Non-blank patterns, trimmed
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|s| s.trim()).filter(|s| !s.is_empty())
+    }
+
+    #[cfg(test)]
     pub fn to_vec(&self) -> Vec<String> {
         self.0.clone()
     }
+
+    /// Compile the patterns into matchers, skipping blank entries.
+    /// Entries prefixed with `re:` are compiled as regular expressions,
+    /// as are all entries when `all_regex` is set.
+    pub fn compile(&self, all_regex: bool) -> Result<Vec<Matcher>, String> {
+        let mut matchers = Vec::new();
+
+        for pattern in &self.0 {
+            if pattern.trim().is_empty() {
+                continue;
+            }
+
+            let regex_source = match pattern.strip_prefix(REGEX_PREFIX) {
+                Some(source) => Some(source),
+                None if all_regex => Some(pattern.as_str()),
+                None => None,
+            };
+
+            match regex_source {
+                Some(source) => {
+                    let regex = RegexBuilder::new(source)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(|e| format!("invalid regex '{}': {}", source, e))?;
+                    matchers.push(Matcher::Regex(regex));
+                }
+                None => matchers.push(Matcher::Contains(pattern.to_lowercase())),
+            }
+        }
+
+        Ok(matchers)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
 pub enum RuleAction {
     #[serde(rename = "archive")]
     #[default]
@@ -123,18 +646,66 @@ pub enum RuleAction {
     Delete,
     #[serde(rename = "mark_read")]
     MarkRead,
+    /// Move to a folder given by its display name path, e.g. "Projects/Alpha"
+    #[serde(rename = "move_to")]
+    MoveTo(String),
+    /// Add Outlook categories, keeping the ones the message already has
+    #[serde(rename = "add_categories")]
+    AddCategories(Vec<String>),
+    /// Remove Outlook categories, if the message has them
+    #[serde(rename = "remove_categories")]
+    RemoveCategories(Vec<String>),
+    /// Flag for follow-up
+    #[serde(rename = "flag")]
+    Flag,
+    /// Flag for follow-up, due this long after the message was received (e.g. "+2d")
+    #[serde(rename = "flag_due")]
+    FlagDue(String),
+    /// Mark the follow-up flag as complete
+    #[serde(rename = "complete_flag")]
+    CompleteFlag,
+    /// Remove the follow-up flag
+    #[serde(rename = "clear_flag")]
+    ClearFlag,
+}
+
+impl RuleAction {
+    /// Whether the action takes the message out of its folder
+    pub fn moves_message(&self) -> bool {
+        matches!(
+            self,
+            RuleAction::Archive | RuleAction::Delete | RuleAction::MoveTo(_)
+        )
+    }
+
+    /// Flag status the action sets, for flag actions
+    pub fn flag_status(&self) -> Option<FlagStatus> {
+        match self {
+            RuleAction::Flag | RuleAction::FlagDue(_) => Some(FlagStatus::Flagged),
+            RuleAction::CompleteFlag => Some(FlagStatus::Complete),
+            RuleAction::ClearFlag => Some(FlagStatus::NotFlagged),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Rules {
-    #[serde(flatten)]
+    /// Mailbox the rules apply to (e.g. a shared mailbox), defaults to the signed-in user's
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<String>,
+    #[serde(rename = "rules")]
     pub items: Vec<Rule>,
 }
 
 impl Rules {
     pub fn new() -> Self {
         // Create an empty rules configuration
-        Self { items: Vec::new() }
+        Self {
+            mailbox: None,
+            items: Vec::new(),
+        }
     }
 
     /// Get example rules to help users
@@ -147,7 +718,8 @@ impl Rules {
                     "updates".to_string(),
                 ]),
                 subject_contains: PatternSet::new(),
-                action: RuleAction::Archive,
+                action: Some(RuleAction::Archive),
+                ..Default::default()
             },
             Rule {
                 name: "Delete promotions".to_string(),
@@ -157,13 +729,15 @@ impl Rules {
                     "sale".to_string(),
                     "offer".to_string(),
                 ]),
-                action: RuleAction::Delete,
+                action: Some(RuleAction::Delete),
+                ..Default::default()
             },
             Rule {
                 name: "Mark read meeting invites".to_string(),
                 sender_contains: PatternSet::new(),
                 subject_contains: PatternSet::with_patterns(vec!["invitation".to_string()]),
-                action: RuleAction::MarkRead,
+                action: Some(RuleAction::MarkRead),
+                ..Default::default()
             },
             Rule {
                 name: "Archive tech updates from company domain".to_string(),
@@ -172,7 +746,8 @@ impl Rules {
                     "tech update".to_string(),
                     "technology news".to_string(),
                 ]),
-                action: RuleAction::Archive,
+                action: Some(RuleAction::Archive),
+                ..Default::default()
             },
         ]
     }
@@ -181,6 +756,12 @@ impl Rules {
     pub fn validate(&self) -> Vec<String> {
         let mut errors = Vec::new();
 
+        if let Some(mailbox) = &self.mailbox
+            && mailbox.trim().is_empty()
+        {
+            errors.push("mailbox cannot be empty".to_string());
+        }
+
         // Validate each rule
         for (i, rule) in self.items.iter().enumerate() {
             // Validate rule name
@@ -189,19 +770,169 @@ impl Rules {
             }
 
             // Validate match conditions (must have at least one pattern that's not empty)
-            if rule.sender_contains.is_empty() && rule.subject_contains.is_empty() {
+            if rule.sender_contains.is_empty()
+                && rule.subject_contains.is_empty()
+                && rule.sender_regex.is_empty()
+                && rule.subject_regex.is_empty()
+                && rule.sender_is.is_empty()
+                && rule.has_category.is_empty()
+                && rule.older_than.is_none()
+                && rule.newer_than.is_none()
+                && rule.is_read.is_none()
+                && rule.has_attachments.is_none()
+                && rule.is_flagged.is_none()
+            {
+                errors.push(format!(
+                    "Rule '{}': must specify at least one match pattern or condition (sender_contains, subject_contains, sender_regex, subject_regex, sender_is, has_category, older_than, newer_than, is_read, has_attachments or is_flagged)",
+                    rule.name
+                ));
+
+                if !rule.sender_not_contains.is_empty() || !rule.subject_not_contains.is_empty() {
+                    errors.push(format!(
+                        "Rule '{}': exclusion patterns (sender_not_contains, subject_not_contains) only narrow a rule and cannot be used on their own",
+                        rule.name
+                    ));
+                }
+            }
+
+            // Validate that all regular expressions and durations compile
+            match rule.compile() {
+                Ok(matchers) => {
+                    if let (Some(older_than), Some(newer_than)) =
+                        (matchers.older_than, matchers.newer_than)
+                        && older_than >= newer_than
+                    {
+                        errors.push(format!(
+                            "Rule '{}': older_than must be shorter than newer_than, otherwise the rule can never match",
+                            rule.name
+                        ));
+                    }
+                }
+                Err(e) => errors.push(format!("Rule '{}': {}", rule.name, e)),
+            }
+
+            // Validate the actions
+            if rule.action.is_some() && !rule.actions.is_empty() {
                 errors.push(format!(
-                    "Rule '{}': must specify at least one match pattern (sender_contains or subject_contains)",
+                    "Rule '{}': use either action or actions, not both",
+                    rule.name
+                ));
+            } else if rule.actions().is_empty() {
+                errors.push(format!(
+                    "Rule '{}': must specify an action (action or actions)",
                     rule.name
                 ));
             }
 
+            if rule.actions().iter().filter(|a| a.moves_message()).count() > 1 {
+                errors.push(format!(
+                    "Rule '{}': only one of archive, delete and move_to can be used, as each moves the message",
+                    rule.name
+                ));
+            }
+
+            // Validate move_to destinations, categories and due dates
+            for action in rule.actions() {
+                match action {
+                    RuleAction::MoveTo(path)
+                        if path.split('/').all(|segment| segment.trim().is_empty()) =>
+                    {
+                        errors.push(format!(
+                            "Rule '{}': move_to folder cannot be empty",
+                            rule.name
+                        ));
+                    }
+                    RuleAction::AddCategories(categories)
+                    | RuleAction::RemoveCategories(categories)
+                        if categories.is_empty()
+                            || categories.iter().any(|c| c.trim().is_empty()) =>
+                    {
+                        errors.push(format!(
+                            "Rule '{}': categories to add or remove cannot be empty",
+                            rule.name
+                        ));
+                    }
+                    RuleAction::FlagDue(due) => {
+                        if let Err(e) = parse_due(due) {
+                            errors.push(format!("Rule '{}': {}", rule.name, e));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // Validate the folder the rule applies to
+            if let Some(folder) = &rule.folder
+                && folder.split('/').all(|segment| segment.trim().is_empty())
+            {
+                errors.push(format!("Rule '{}': folder cannot be empty", rule.name));
+            }
+
             // No need to check if arrays are empty since PatternSet::is_empty handles that
         }
 
         errors
     }
 
+    /// Find rules that are valid but probably not what was intended: rules that can never
+    /// fire because an earlier rule matches everything they do, duplicate names and
+    /// patterns, and very short patterns in delete rules. Returns a list of warnings.
+    pub fn lint(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for (i, rule) in self.items.iter().enumerate() {
+            if let Some(earlier) = self.items[..i]
+                .iter()
+                .find(|e| e.name.trim().eq_ignore_ascii_case(rule.name.trim()))
+                && !rule.name.trim().is_empty()
+            {
+                warnings.push(format!(
+                    "Rule '{}' (#{}): another rule is also named '{}'",
+                    rule.name,
+                    i + 1,
+                    earlier.name
+                ));
+            }
+
+            if let Some(earlier) = self.items[..i].iter().find(|e| e.subsumes(rule)) {
+                warnings.push(format!(
+                    "Rule '{}' (#{}) can never fire: rule '{}' comes first and matches every message it does",
+                    rule.name,
+                    i + 1,
+                    earlier.name
+                ));
+            }
+
+            let deletes = rule.actions().contains(&RuleAction::Delete);
+            for (field, patterns) in rule.pattern_fields() {
+                let mut seen: Vec<String> = Vec::new();
+                for pattern in patterns.patterns() {
+                    let key = pattern.to_lowercase();
+                    if seen.contains(&key) {
+                        warnings.push(format!(
+                            "Rule '{}': pattern '{}' appears more than once in {}",
+                            rule.name, pattern, field
+                        ));
+                    } else {
+                        seen.push(key);
+                    }
+
+                    // Exact addresses and categories can't match more than they say
+                    let partial = field.ends_with("_contains") || field.ends_with("_regex");
+                    let positive = !field.ends_with("_not_contains");
+                    if deletes && partial && positive && is_broad_pattern(field, pattern) {
+                        warnings.push(format!(
+                            "Rule '{}': pattern '{}' in {} is very short for a delete rule and may match far more messages than intended",
+                            rule.name, pattern, field
+                        ));
+                    }
+                }
+            }
+        }
+
+        warnings
+    }
+
     /// Gets the path to the JSON schema file in the rules directory
     pub fn get_schema_path() -> Result<PathBuf> {
         let schema_path = Self::get_rules_dir()?.join("rules.schema.json");
@@ -244,15 +975,14 @@ impl Rules {
                 return Ok(default_rules);
             }
 
-            // Try parsing the YAML directly as an array of Rule objects
-            match serde_yaml::from_str::<Vec<Rule>>(&rules_str) {
-                Ok(rule_items) => Ok(Rules { items: rule_items }),
-                Err(_) => {
-                    // If that fails, try parsing as a Rules struct (for backward compatibility)
-                    let rules: Rules = serde_yaml::from_str(&rules_str)?;
-                    Ok(rules)
-                }
+            let rules = Self::parse(&rules_str)?;
+
+            // Compile patterns up front; invalid ones are reported by `validate`
+            for rule in &rules.items {
+                let _ = rule.compile();
             }
+
+            Ok(rules)
         } else {
             let default_rules = Rules::new();
             default_rules.save()?;
@@ -260,6 +990,22 @@ impl Rules {
         }
     }
 
+    /// Parse rules from a YAML string
+    pub fn parse(rules_str: &str) -> Result<Self> {
+        // Try parsing the YAML directly as an array of Rule objects
+        match serde_yaml::from_str::<Vec<Rule>>(rules_str) {
+            Ok(rule_items) => Ok(Rules {
+                mailbox: None,
+                items: rule_items,
+            }),
+            Err(_) => {
+                // If that fails, try parsing as a Rules struct (with mailbox settings)
+                let rules: Rules = serde_yaml::from_str(rules_str)?;
+                Ok(rules)
+            }
+        }
+    }
+
     /// Save rules to disk
     pub fn save(&self) -> Result<()> {
         let rules_path = Self::get_rules_path()?;
@@ -297,18 +1043,27 @@ impl Rules {
             return Ok(());
         }
 
-        // Let's simplify and just use the standard serialization
-        let mut rules_str = serde_yaml::to_string(&self.items)?;
+        content.push_str(&self.to_yaml()?);
+        fs::write(&rules_path, content)?;
+
+        Ok(())
+    }
+
+    /// Serialize the rules as they are saved. The plain list form is used unless
+    /// file-level settings like the mailbox are present.
+    pub fn to_yaml(&self) -> Result<String> {
+        let mut rules_str = if self.mailbox.is_some() {
+            serde_yaml::to_string(self)?
+        } else {
+            serde_yaml::to_string(&self.items)?
+        };
 
         // Replace the standard 2-space indentation with 4-space indentation
         if rules_str.contains("\n  - ") {
             rules_str = rules_str.replace("\n  - ", "\n    - ");
         }
 
-        content.push_str(&rules_str);
-        fs::write(&rules_path, content)?;
-
-        Ok(())
+        Ok(rules_str)
     }
 
     /// Get the rules directory path
@@ -329,6 +1084,17 @@ impl Rules {
 mod tests {
     use super::*;
 
+    fn message(sender: &str, subject: &str) -> Message {
+        Message {
+            id: "1".to_string(),
+            subject: subject.to_string(),
+            sender: sender.to_string(),
+            sender_address: sender.to_string(),
+            received_date: Utc::now(),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_yaml_formatting() {
         // Create a rule with multiple patterns
@@ -342,11 +1108,15 @@ mod tests {
                 "important".to_string(),
                 "urgent".to_string(),
             ]),
-            action: RuleAction::Archive,
+            action: Some(RuleAction::Archive),
+            ..Default::default()
         };
 
         // Create a rules set with the rule
-        let rules = Rules { items: vec![rule] };
+        let rules = Rules {
+            items: vec![rule],
+            ..Default::default()
+        };
 
         // Serialize to YAML with our formatting logic
         let yaml = serde_yaml::to_string(&rules.items).unwrap();
@@ -373,11 +1143,13 @@ mod tests {
             name: "Empty rule".to_string(),
             sender_contains: PatternSet::new(),
             subject_contains: PatternSet::new(),
-            action: RuleAction::Archive,
+            action: Some(RuleAction::Archive),
+            ..Default::default()
         };
 
         let rules = Rules {
             items: vec![empty_rule],
+            ..Default::default()
         };
 
         let errors = rules.validate();
@@ -392,11 +1164,13 @@ mod tests {
             name: "Sender rule".to_string(),
             sender_contains: PatternSet::with_patterns(vec!["example.com".to_string()]),
             subject_contains: PatternSet::new(),
-            action: RuleAction::Delete,
+            action: Some(RuleAction::Delete),
+            ..Default::default()
         };
 
         let rules = Rules {
             items: vec![sender_rule],
+            ..Default::default()
         };
 
         let errors = rules.validate();
@@ -410,11 +1184,13 @@ mod tests {
             name: "Subject rule".to_string(),
             sender_contains: PatternSet::new(),
             subject_contains: PatternSet::with_patterns(vec!["important".to_string()]),
-            action: RuleAction::MarkRead,
+            action: Some(RuleAction::MarkRead),
+            ..Default::default()
         };
 
         let rules = Rules {
             items: vec![subject_rule],
+            ..Default::default()
         };
 
         let errors = rules.validate();
@@ -428,11 +1204,13 @@ mod tests {
             name: "".to_string(),
             sender_contains: PatternSet::with_patterns(vec!["example.com".to_string()]),
             subject_contains: PatternSet::new(),
-            action: RuleAction::Archive,
+            action: Some(RuleAction::Archive),
+            ..Default::default()
         };
 
         let rules = Rules {
             items: vec![no_name_rule],
+            ..Default::default()
         };
 
         let errors = rules.validate();
@@ -444,6 +1222,473 @@ mod tests {
             errors[0].contains("name cannot be empty"),
             "Error should mention empty name"
         );
+
+        // Test rule with a blank folder
+        let rules = Rules {
+            items: vec![Rule {
+                name: "Blank folder".to_string(),
+                subject_contains: PatternSet::with_patterns(vec!["test".to_string()]),
+                folder: Some(" / ".to_string()),
+                action: Some(RuleAction::Archive),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let errors = rules.validate();
+        assert_eq!(errors, vec!["Rule 'Blank folder': folder cannot be empty"]);
+    }
+
+    #[test]
+    fn test_regex_patterns() {
+        // Prefixed regex in a contains list, mixed with a plain substring
+        let rule = Rule {
+            name: "Regex rule".to_string(),
+            subject_contains: PatternSet::with_patterns(vec![
+                "re:\\bsale\\b".to_string(),
+                "newsletter".to_string(),
+            ]),
+            action: Some(RuleAction::Delete),
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&message("shop@example.com", "Big SALE today")));
+        assert!(rule.matches(&message("shop@example.com", "Monthly newsletter")));
+        assert!(
+            !rule.matches(&message("shop@example.com", "Wholesale prices")),
+            "Word-boundary regex should not match inside other words"
+        );
+
+        // Dedicated regex field
+        let rule = Rule {
+            name: "Sender regex".to_string(),
+            sender_regex: PatternSet::with_patterns(vec![
+                "^noreply@.*\\.example\\.(com|net)$".to_string(),
+            ]),
+            action: Some(RuleAction::Archive),
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&message("noreply@mail.example.net", "Hi")));
+        assert!(!rule.matches(&message("noreply@example.org", "Hi")));
+
+        // Invalid regexes are reported with the rule name and never match
+        let rules = Rules {
+            items: vec![Rule {
+                name: "Broken".to_string(),
+                sender_contains: PatternSet::with_patterns(vec!["re:(unclosed".to_string()]),
+                action: Some(RuleAction::Archive),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let errors = rules.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("Rule 'Broken'") && errors[0].contains("invalid regex"));
+        assert!(!rules.items[0].matches(&message("(unclosed", "Any subject")));
+    }
+
+    #[test]
+    fn test_exclusion_patterns() {
+        let rule = Rule {
+            name: "Vendor mail except invoices".to_string(),
+            sender_contains: PatternSet::with_patterns(vec!["@vendor.com".to_string()]),
+            subject_not_contains: PatternSet::with_patterns(vec!["invoice".to_string()]),
+            action: Some(RuleAction::Archive),
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&message("sales@vendor.com", "New products")));
+        assert!(
+            !rule.matches(&message("billing@vendor.com", "Your INVOICE for May")),
+            "Excluded subject should prevent the match"
+        );
+
+        let rule = Rule {
+            name: "Vendor mail except billing".to_string(),
+            sender_contains: PatternSet::with_patterns(vec!["@vendor.com".to_string()]),
+            sender_not_contains: PatternSet::with_patterns(vec!["re:^billing@".to_string()]),
+            action: Some(RuleAction::Archive),
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&message("sales@vendor.com", "Anything")));
+        assert!(!rule.matches(&message("billing@vendor.com", "Anything")));
+
+        // Exclusions alone are not a valid match condition
+        let rules = Rules {
+            items: vec![Rule {
+                name: "Only exclusions".to_string(),
+                subject_not_contains: PatternSet::with_patterns(vec!["invoice".to_string()]),
+                action: Some(RuleAction::Delete),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let errors = rules.validate();
+        assert!(
+            errors
+                .iter()
+                .any(|e| e.contains("must specify at least one match pattern"))
+        );
+    }
+
+    #[test]
+    fn test_age_conditions() {
+        let rule = Rule {
+            name: "Old CI notifications".to_string(),
+            sender_contains: PatternSet::with_patterns(vec!["ci@".to_string()]),
+            older_than: Some("7d".to_string()),
+            action: Some(RuleAction::Delete),
+            ..Default::default()
+        };
+
+        let mut old = message("ci@example.com", "Build passed");
+        old.received_date = Utc::now() - Duration::days(8);
+        let recent = message("ci@example.com", "Build passed");
+
+        assert!(rule.matches(&old), "Message older than 7 days should match");
+        assert!(!rule.matches(&recent), "Recent message should not match");
+
+        let rule = Rule {
+            name: "Fresh alerts".to_string(),
+            subject_contains: PatternSet::with_patterns(vec!["alert".to_string()]),
+            newer_than: Some("2h".to_string()),
+            action: Some(RuleAction::MarkRead),
+            ..Default::default()
+        };
+
+        let mut stale = message("monitor@example.com", "Alert: disk full");
+        stale.received_date = Utc::now() - Duration::hours(3);
+
+        assert!(rule.matches(&message("monitor@example.com", "Alert: disk full")));
+        assert!(!rule.matches(&stale));
+
+        // Invalid and contradictory durations are reported
+        let rules = Rules {
+            items: vec![
+                Rule {
+                    name: "Bad duration".to_string(),
+                    older_than: Some("a while".to_string()),
+                    action: Some(RuleAction::Delete),
+                    ..Default::default()
+                },
+                Rule {
+                    name: "Impossible window".to_string(),
+                    older_than: Some("7d".to_string()),
+                    newer_than: Some("1d".to_string()),
+                    action: Some(RuleAction::Delete),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let errors = rules.validate();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("Rule 'Bad duration': invalid older_than duration"));
+        assert!(errors[1].contains("Rule 'Impossible window'"));
+    }
+
+    #[test]
+    fn test_server_filter() {
+        let now = DateTime::parse_from_rfc3339("2024-06-30T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let rule = Rule {
+            name: "Old unread reports".to_string(),
+            sender_is: PatternSet::with_patterns(vec![
+                "Reports@Example.com".to_string(),
+                "o'brien@example.com".to_string(),
+            ]),
+            subject_contains: PatternSet::with_patterns(vec!["report".to_string()]),
+            older_than: Some("30d".to_string()),
+            is_read: Some(false),
+            has_attachments: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            rule.graph_filter(now).as_deref(),
+            Some(
+                "(from/emailAddress/address eq 'reports@example.com' or from/emailAddress/address eq 'o''brien@example.com') and receivedDateTime le 2024-05-31T12:00:00Z and isRead eq false and hasAttachments eq true"
+            )
+        );
+
+        // Subject patterns can't be pushed down, so every message is a candidate
+        let subject_only = Rule {
+            name: "Reports".to_string(),
+            subject_contains: PatternSet::with_patterns(vec!["report".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(subject_only.graph_filter(now), None);
+
+        // Client-side matching applies the same conditions
+        let mut report = message("Reports <reports@example.com>", "Weekly report");
+        report.sender_address = "reports@example.com".to_string();
+        report.received_date = Utc::now() - Duration::days(40);
+        report.has_attachments = true;
+        assert!(rule.matches(&report));
+
+        report.is_read = true;
+        assert!(!rule.matches(&report));
+
+        report.is_read = false;
+        report.sender_address = "someone@example.com".to_string();
+        assert!(!rule.matches(&report));
+    }
+
+    #[test]
+    fn test_move_to_action_yaml() {
+        let yaml = "- name: File alpha\n  subject_contains:\n    - alpha\n  action:\n    move_to: Projects/Alpha\n- name: Archive\n  subject_contains:\n    - beta\n  action: archive\n";
+        let rules = Rules::parse(yaml).unwrap();
+
+        assert_eq!(
+            rules.items[0].actions(),
+            [RuleAction::MoveTo("Projects/Alpha".to_string())]
+        );
+        assert_eq!(rules.items[1].actions(), [RuleAction::Archive]);
+
+        // Round-trips using the same map form
+        let serialized = serde_yaml::to_string(&rules.items).unwrap();
+        assert!(serialized.contains("action:\n    move_to: Projects/Alpha"));
+        assert!(serialized.contains("action: archive"));
+    }
+
+    #[test]
+    fn test_multiple_actions() {
+        let yaml = "- name: Read and file\n  subject_contains:\n    - alpha\n  actions:\n    - mark_read\n    - move_to: Projects/Alpha\n";
+        let rules = Rules::parse(yaml).unwrap();
+
+        assert_eq!(
+            rules.items[0].actions(),
+            [
+                RuleAction::MarkRead,
+                RuleAction::MoveTo("Projects/Alpha".to_string())
+            ]
+        );
+        assert!(rules.validate().is_empty());
+
+        // Round-trips using the list form
+        let serialized = serde_yaml::to_string(&rules.items).unwrap();
+        assert!(serialized.contains("actions:\n  - mark_read\n  - move_to: Projects/Alpha"));
+        assert!(!serialized.contains("action:"));
+
+        // Exactly one of action and actions, with at most one action that moves the message
+        let rule = |action: Option<RuleAction>, actions: Vec<RuleAction>| Rule {
+            name: "Rule".to_string(),
+            subject_contains: PatternSet::with_patterns(vec!["x".to_string()]),
+            action,
+            actions,
+            ..Default::default()
+        };
+        let rules = Rules {
+            items: vec![
+                rule(None, Vec::new()),
+                rule(Some(RuleAction::Archive), vec![RuleAction::MarkRead]),
+                rule(None, vec![RuleAction::Archive, RuleAction::Delete]),
+            ],
+            ..Default::default()
+        };
+
+        let errors = rules.validate();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].contains("must specify an action"));
+        assert!(errors[1].contains("either action or actions"));
+        assert!(errors[2].contains("only one of archive, delete and move_to"));
+    }
+
+    #[test]
+    fn test_categories() {
+        let yaml = "- name: Done\n  has_category:\n    - Done\n    - O'Neil\n  actions:\n    - remove_categories: [Done]\n    - add_categories: [Filed]\n";
+        let rules = Rules::parse(yaml).unwrap();
+        let rule = &rules.items[0];
+
+        assert_eq!(
+            rule.actions(),
+            [
+                RuleAction::RemoveCategories(vec!["Done".to_string()]),
+                RuleAction::AddCategories(vec!["Filed".to_string()])
+            ]
+        );
+        assert!(rules.validate().is_empty());
+
+        // Categories are compared case-insensitively and filtered on the server
+        let mut done = message("boss@example.com", "Meeting");
+        done.categories = vec!["Red".to_string(), "done".to_string()];
+        assert!(rule.matches(&done));
+        assert!(!rule.matches(&message("boss@example.com", "Meeting")));
+        assert_eq!(
+            rule.graph_filter(Utc::now()).as_deref(),
+            Some("(categories/any(c:c eq 'Done') or categories/any(c:c eq 'O''Neil'))")
+        );
+
+        // Category actions need category names
+        let rules = Rules::parse(
+            "- name: Blank\n  subject_contains: [x]\n  action:\n    add_categories: [\" \"]\n",
+        )
+        .unwrap();
+        let errors = rules.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("categories to add or remove cannot be empty"));
+    }
+
+    #[test]
+    fn test_flag_actions() {
+        let yaml = "- name: CEO\n  sender_is: [ceo@example.com]\n  subject_contains: [action required]\n  action:\n    flag_due: +2d\n- name: Cleanup\n  older_than: 30d\n  is_flagged: false\n  action: delete\n";
+        let rules = Rules::parse(yaml).unwrap();
+
+        assert_eq!(
+            rules.items[0].actions(),
+            [RuleAction::FlagDue("+2d".to_string())]
+        );
+        assert!(rules.validate().is_empty());
+        assert_eq!(parse_due("+2d"), Ok(Duration::days(2)));
+        assert_eq!(
+            RuleAction::CompleteFlag.flag_status(),
+            Some(FlagStatus::Complete)
+        );
+
+        // Flagged messages are protected from the cleanup rule
+        let cleanup = &rules.items[1];
+        let mut old = message("shop@example.com", "Sale");
+        old.received_date = Utc::now() - Duration::days(40);
+        assert!(cleanup.matches(&old));
+        old.flag_status = FlagStatus::Flagged;
+        assert!(!cleanup.matches(&old));
+        assert!(
+            cleanup
+                .graph_filter(Utc::now())
+                .unwrap()
+                .ends_with(" and flag/flagStatus ne 'flagged'")
+        );
+
+        let rules = Rules::parse(
+            "- name: Bad due\n  subject_contains: [x]\n  action:\n    flag_due: soon\n",
+        )
+        .unwrap();
+        let errors = rules.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("invalid flag_due duration 'soon'"));
+    }
+
+    #[test]
+    fn test_first_match_and_explain() {
+        let rules = Rules::parse(
+            "- name: Newsletters\n  subject_contains: [newsletter]\n  action: archive\n- name: News\n  sender_regex: ['^news@']\n  is_read: false\n  action: delete\n- name: Other\n  subject_contains: [invoice]\n  action: mark_read\n",
+        )
+        .unwrap();
+        let candidates: Vec<&Rule> = rules.items.iter().collect();
+
+        let newsletter = message("news@example.com", "Weekly Newsletter");
+        let winner = first_match(&candidates, &newsletter).unwrap();
+        assert_eq!(winner.name, "Newsletters");
+        assert_eq!(
+            winner.explain_match(&newsletter),
+            vec!["subject contains \"newsletter\""]
+        );
+        assert_eq!(
+            rules.items[1].explain_match(&newsletter),
+            vec!["sender matches /^news@/", "is unread"]
+        );
+
+        assert!(first_match(&candidates, &message("boss@example.com", "Meeting")).is_none());
+    }
+
+    #[test]
+    fn test_lint() {
+        let rules = Rules::parse(
+            "- name: Newsletters\n  subject_contains: [newsletter]\n  action: archive\n\
+             - name: Weekly newsletters\n  subject_contains: [weekly newsletter]\n  is_read: false\n  action: delete\n\
+             - name: newsletters\n  subject_contains: [digest, Digest]\n  sender_not_contains: [boss]\n  action: mark_read\n\
+             - name: Digests from anyone\n  subject_contains: [digest]\n  action: archive\n\
+             - name: Other folder\n  folder: Reading\n  subject_contains: [newsletter]\n  action: delete\n\
+             - name: Short\n  sender_contains: [ab]\n  action: delete\n",
+        )
+        .unwrap();
+        assert!(rules.validate().is_empty());
+
+        let warnings = rules.lint();
+        assert_eq!(warnings.len(), 4, "{:?}", warnings);
+        assert!(warnings[0].contains("'Weekly newsletters' (#2) can never fire"));
+        assert!(warnings[0].contains("rule 'Newsletters'"));
+        assert!(warnings[1].contains("another rule is also named 'Newsletters'"));
+        assert!(
+            warnings[2].contains("pattern 'Digest' appears more than once in subject_contains")
+        );
+        assert!(warnings[3].contains("pattern 'ab' in sender_contains is very short"));
+
+        // Broad regexes are measured without their prefix and anchors; exact addresses
+        // can't over-match, and duplicate categories are reported
+        let rules = Rules::parse(
+            "- name: Any\n  subject_contains: ['re:.']\n  action: delete\n\
+             - name: Anchored\n  sender_regex: ['^a', '.+', '^news@']\n  action: delete\n\
+             - name: Exact\n  sender_is: [a@b.c]\n  has_category: [Red, red]\n  action: delete\n",
+        )
+        .unwrap();
+        let warnings = rules.lint();
+        assert_eq!(warnings.len(), 4, "{:?}", warnings);
+        assert!(warnings[0].contains("pattern 're:.' in subject_contains is very short"));
+        assert!(warnings[1].contains("pattern '^a' in sender_regex is very short"));
+        assert!(warnings[2].contains("pattern '.+' in sender_regex is very short"));
+        assert!(warnings[3].contains("pattern 'red' appears more than once in has_category"));
+
+        // Exclusions on the earlier rule let some messages through to the later one
+        let rules = Rules::parse(
+            "- name: A\n  subject_contains: [digest]\n  sender_not_contains: [boss@]\n  action: archive\n\
+             - name: B\n  subject_contains: [digest]\n  action: mark_read\n\
+             - name: C\n  sender_is: [news@example.com]\n  subject_contains: [daily digest]\n  sender_not_contains: [boss]\n  action: mark_read\n",
+        )
+        .unwrap();
+        let warnings = rules.lint();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("'C' (#3) can never fire: rule 'A'"));
+    }
+
+    #[test]
+    fn test_may_overlap() {
+        let rules = Rules::parse(
+            "- name: Unread boss\n  sender_is: [boss@example.com]\n  is_read: false\n  action: mark_read\n\
+             - name: Example\n  sender_contains: ['@example.com']\n  action: delete\n\
+             - name: Alerts\n  sender_is: [alerts@example.com]\n  action: delete\n\
+             - name: Read\n  subject_contains: [report]\n  is_read: true\n  action: archive\n\
+             - name: Old\n  older_than: 30d\n  action: delete\n\
+             - name: Recent\n  newer_than: 7d\n  action: mark_read\n\
+             - name: Junk\n  folder: junk\n  subject_contains: [report]\n  action: delete\n",
+        )
+        .unwrap();
+        let rule = |name: &str| rules.items.iter().find(|r| r.name == name).unwrap();
+
+        assert!(rule("Unread boss").may_overlap(rule("Example")));
+        assert!(!rule("Unread boss").may_overlap(rule("Alerts")));
+        assert!(!rule("Unread boss").may_overlap(rule("Read")));
+        assert!(!rule("Old").may_overlap(rule("Recent")));
+        assert!(rule("Old").may_overlap(rule("Example")));
+        assert!(!rule("Read").may_overlap(rule("Junk")));
+    }
+
+    #[test]
+    fn test_mailbox_rules_file() {
+        let yaml = "mailbox: support@contoso.com\nrules:\n  - name: Spam\n    subject_contains:\n      - lottery\n    action: delete\n";
+        let rules = Rules::parse(yaml).unwrap();
+
+        assert_eq!(rules.mailbox.as_deref(), Some("support@contoso.com"));
+        assert_eq!(rules.items.len(), 1);
+        assert_eq!(rules.items[0].actions(), [RuleAction::Delete]);
+        let yaml = rules.to_yaml().unwrap();
+        assert!(
+            yaml.starts_with("mailbox: support@contoso.com\n"),
+            "{}",
+            yaml
+        );
+        assert_eq!(Rules::parse(&yaml).unwrap().mailbox, rules.mailbox);
+
+        // The plain list form has no mailbox
+        let rules = Rules::parse("- name: Spam\n  subject_contains: [lottery]\n  action: delete\n")
+            .unwrap();
+        assert!(rules.mailbox.is_none());
+        assert_eq!(rules.items.len(), 1);
     }
 
     #[test]
@@ -0,0 +1,646 @@
+//! End-to-end tests running `mailsweep clean` against a local mock Graph server
+
+use serde_json::{Value, json};
+use std::fs;
+use std::process::Output;
+use tempfile::TempDir;
+use wiremock::matchers::{body_partial_json, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// A config directory with a logged-in token cache and rules, plus a mock Graph server
+struct TestEnv {
+    config_home: TempDir,
+    server: MockServer,
+}
+
+impl TestEnv {
+    async fn new(rules_yaml: &str) -> Self {
+        let config_home = tempfile::tempdir().unwrap();
+        let app_dir = config_home.path().join("mailsweep");
+        fs::create_dir_all(&app_dir).unwrap();
+
+        // A token that doesn't expire during the test, so no refresh is attempted
+        fs::write(
+            app_dir.join("token_cache.yaml"),
+            "access_token: test-token\nrefresh_token: test-refresh\nexpires_at: [2100, 1, 0, 0, 0, 0, 0, 0, 0]\n",
+        )
+        .unwrap();
+        fs::write(app_dir.join("rules.yaml"), rules_yaml).unwrap();
+
+        Self {
+            config_home,
+            server: MockServer::start().await,
+        }
+    }
+
+    /// Run mailsweep with the given arguments against the mock server
+    async fn run(&self, args: &[&str]) -> Output {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_mailsweep"))
+            .args(args)
+            .env("XDG_CONFIG_HOME", self.config_home.path())
+            .env("APPDATA", self.config_home.path())
+            .env("MAILSWEEP_GRAPH_URL", self.server.uri())
+            .env(
+                "MAILSWEEP_LOGIN_URL",
+                format!("{}/login", self.server.uri()),
+            )
+            .env_remove("MAILSWEEP_PROFILE")
+            .env_remove("RUST_BACKTRACE")
+            .output()
+            .await
+            .unwrap()
+    }
+
+    fn app_file(&self, name: &str) -> String {
+        fs::read_to_string(self.config_home.path().join("mailsweep").join(name)).unwrap()
+    }
+}
+
+fn message(id: &str, sender: &str, subject: &str, received: &str) -> Value {
+    json!({
+        "id": id,
+        "subject": subject,
+        "from": { "emailAddress": { "address": sender, "name": sender } },
+        "receivedDateTime": received,
+        "parentFolderId": "inbox-id",
+        "isRead": false,
+        "hasAttachments": false
+    })
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[tokio::test]
+async fn test_clean_follows_pagination() {
+    let env = TestEnv::new(
+        "- name: Newsletters\n  subject_contains:\n    - newsletter\n  action: archive\n",
+    )
+    .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .and(query_param("$top", "50"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m1", "news@example.com", "Weekly newsletter", "2024-01-02T00:00:00Z"),
+                message("m2", "boss@example.com", "Meeting", "2024-01-02T00:00:00Z"),
+            ],
+            "@odata.nextLink": format!("{}/me/mailFolders/inbox/messages?$skip=2", env.server.uri())
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .and(query_param("$skip", "2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m3", "news@example.com", "Monthly newsletter", "2024-01-01T00:00:00Z"),
+            ]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--dry-run", "--output", "json"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    // Matches from both pages, newest first
+    let records: Vec<Value> = serde_json::from_str(&stdout(&output)).unwrap();
+    let ids: Vec<&str> = records.iter().map(|r| r["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, vec!["m1", "m3"]);
+    assert_eq!(records[0]["action"], "archive");
+}
+
+#[tokio::test]
+async fn test_clean_batch_partial_failure() {
+    let env =
+        TestEnv::new("- name: Promotions\n  subject_contains:\n    - promo\n  action: delete\n")
+            .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m1", "shop@example.com", "promo 1", "2024-01-03T00:00:00Z"),
+                message("m2", "shop@example.com", "promo 2", "2024-01-02T00:00:00Z"),
+                message("m3", "shop@example.com", "promo 3", "2024-01-01T00:00:00Z"),
+            ]
+        })))
+        .mount(&env.server)
+        .await;
+
+    // First batch: one success, one throttled sub-request and one failure
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [
+                { "id": "1", "status": 201, "body": { "id": "new-m1" } },
+                { "id": "2", "status": 429, "headers": { "Retry-After": "0" } },
+                { "id": "3", "status": 404, "body": { "error": { "message": "Not found" } } }
+            ]
+        })))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    // Only the throttled sub-request is sent again
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .and(body_partial_json(json!({ "requests": [{ "id": "2" }] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [
+                { "id": "2", "status": 201, "body": { "id": "new-m2" } }
+            ]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = stdout(&output);
+    assert!(
+        stdout.contains("Completed: 2 messages deleted, 1 failure"),
+        "{}",
+        stdout
+    );
+
+    // Successful actions are journaled with the messages' new IDs
+    let journal: Vec<Value> = env
+        .app_file("journal.jsonl")
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let moved: Vec<(&str, &str)> = journal
+        .iter()
+        .map(|e| {
+            (
+                e["message_id"].as_str().unwrap(),
+                e["current_id"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(moved, vec![("m1", "new-m1"), ("m2", "new-m2")]);
+}
+
+#[tokio::test]
+async fn test_clean_pushes_sender_filter_to_server() {
+    let env = TestEnv::new(
+        "- name: Reports\n  sender_is:\n    - reports@example.com\n  action: mark_read\n",
+    )
+    .await;
+
+    // Without the expected $filter the request isn't matched and the run fails
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .and(query_param(
+            "$filter",
+            "from/emailAddress/address eq 'reports@example.com'",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m1", "reports@example.com", "Daily report", "2024-01-01T00:00:00Z"),
+            ]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--dry-run", "--output", "csv"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = stdout(&output);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("id,sender,subject,received,rule,action"));
+    assert!(
+        lines
+            .next()
+            .unwrap()
+            .starts_with("m1,reports@example.com,Daily report,")
+    );
+    assert_eq!(lines.next(), None);
+}
+
+#[tokio::test]
+async fn test_clean_csv_output_stays_parseable_with_warnings() {
+    let env = TestEnv::new(
+        "- name: Reports\n  sender_is:\n    - reports@example.com\n  action: mark_read\n",
+    )
+    .await;
+
+    // The server rejects the filter, which is logged before falling back to local matching
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .and(query_param(
+            "$filter",
+            "from/emailAddress/address eq 'reports@example.com'",
+        ))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": { "code": "InefficientFilter", "message": "The restriction is too complex" }
+        })))
+        .with_priority(1)
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    // A throttled request is logged and retried as well
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "0"))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m1", "reports@example.com", "Daily report", "2024-01-02T00:00:00Z"),
+                message("m2", "boss@example.com", "Meeting", "2024-01-01T00:00:00Z"),
+            ]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--dry-run", "--output", "csv"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    // The warnings go to stderr, so stdout is only CSV
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Server-side filter failed"), "{}", stderr);
+    assert!(stderr.contains("retrying"), "{}", stderr);
+
+    let mut reader = csv::Reader::from_reader(output.stdout.as_slice());
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec!["id", "sender", "subject", "received", "rule", "action"]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(&rows[0][0], "m1");
+    assert_eq!(&rows[0][4], "Reports");
+    assert_eq!(&rows[0][5], "mark_read");
+}
+
+#[tokio::test]
+async fn test_incremental_rescans_only_when_delta_link_expired() {
+    let env = TestEnv::new(
+        "- name: Newsletters\n  subject_contains:\n    - newsletter\n  action: archive\n",
+    )
+    .await;
+    let delta_links = env.config_home.path().join("mailsweep/delta_links.json");
+    let save_link = |token: &str| {
+        fs::write(
+            &delta_links,
+            json!({
+                "me/inbox": format!("{}/me/mailFolders/inbox/messages/delta?$deltatoken={}", env.server.uri(), token)
+            })
+            .to_string(),
+        )
+        .unwrap()
+    };
+
+    // A rejected token fails the run instead of rescanning the folder
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages/delta"))
+        .and(query_param("$deltatoken", "unauthorized"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "error": { "code": "InvalidAuthenticationToken", "message": "Access token has expired" }
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    // An expired delta link starts a new sync
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages/delta"))
+        .and(query_param("$deltatoken", "expired"))
+        .respond_with(ResponseTemplate::new(410).set_body_json(json!({
+            "error": { "code": "syncStateNotFound", "message": "The sync state is not found" }
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages/delta"))
+        .and(query_param("$select", "id,subject,from,receivedDateTime,parentFolderId,isRead,hasAttachments,categories,flag"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m1", "news@example.com", "Weekly newsletter", "2024-01-02T00:00:00Z"),
+            ],
+            "@odata.deltaLink": format!("{}/me/mailFolders/inbox/messages/delta?$deltatoken=next", env.server.uri())
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    save_link("unauthorized");
+    let output = env
+        .run(&["clean", "--incremental", "--dry-run", "--output", "json"])
+        .await;
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Access token has expired"),
+        "{:?}",
+        output
+    );
+
+    save_link("expired");
+    let output = env
+        .run(&["clean", "--incremental", "--dry-run", "--output", "json"])
+        .await;
+    assert!(output.status.success(), "{:?}", output);
+    let records: Vec<Value> = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["id"], "m1");
+}
+
+#[tokio::test]
+async fn test_clean_and_undo_categories() {
+    let env = TestEnv::new(
+        "- name: Alpha\n  sender_contains:\n    - \"@alpha.com\"\n  action:\n    add_categories: [Project Alpha]\n",
+    )
+    .await;
+
+    let mut categorized = message("m1", "dev@alpha.com", "Build", "2024-01-01T00:00:00Z");
+    categorized["categories"] = json!(["Red"]);
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "value": [categorized] })))
+        .mount(&env.server)
+        .await;
+
+    // The new category is added to the ones the message already has
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .and(body_partial_json(json!({ "requests": [{
+            "method": "PATCH",
+            "url": "/me/messages/m1",
+            "body": { "categories": ["Red", "Project Alpha"] }
+        }] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [{ "id": "1", "status": 200, "body": { "id": "m1" } }]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        stdout(&output).contains("1 message categorized as Project Alpha"),
+        "{}",
+        stdout(&output)
+    );
+
+    // Undo restores the previous categories
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .and(body_partial_json(json!({ "requests": [{
+            "method": "PATCH",
+            "url": "/me/messages/m1",
+            "body": { "categories": ["Red"] }
+        }] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [{ "id": "1", "status": 200, "body": { "id": "m1" } }]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["undo", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        stdout(&output).contains("1 restored"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[tokio::test]
+async fn test_clean_flags_with_due_date() {
+    let env = TestEnv::new(
+        "- name: CEO\n  sender_is:\n    - ceo@example.com\n  is_flagged: false\n  action:\n    flag_due: +2d\n",
+    )
+    .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .and(query_param(
+            "$filter",
+            "from/emailAddress/address eq 'ceo@example.com' and flag/flagStatus ne 'flagged'",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                message("m1", "ceo@example.com", "Action required", "2024-01-01T09:30:00Z"),
+            ]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    // The due date is relative to when the message was received
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .and(body_partial_json(json!({ "requests": [{
+            "method": "PATCH",
+            "url": "/me/messages/m1",
+            "body": { "flag": {
+                "flagStatus": "flagged",
+                "startDateTime": { "dateTime": "2024-01-01T09:30:00", "timeZone": "UTC" },
+                "dueDateTime": { "dateTime": "2024-01-03T09:30:00", "timeZone": "UTC" }
+            } }
+        }] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [{ "id": "1", "status": 200, "body": { "id": "m1" } }]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    // The previous flag status is journaled for undo
+    let journal = env.app_file("journal.jsonl");
+    let entry: Value = serde_json::from_str(journal.lines().next().unwrap()).unwrap();
+    assert_eq!(entry["action"], json!({ "flag_due": "+2d" }));
+    assert_eq!(entry["flag_status"], "notFlagged");
+}
+
+#[tokio::test]
+async fn test_undo_restores_flag_dates() {
+    let env =
+        TestEnv::new("- name: Done\n  subject_contains:\n    - Done\n  action: clear_flag\n").await;
+
+    let mut flagged = message("m1", "boss@example.com", "Done", "2024-01-01T09:30:00Z");
+    flagged["flag"] = json!({
+        "flagStatus": "flagged",
+        "startDateTime": { "dateTime": "2024-01-01T09:30:00.0000000", "timeZone": "UTC" },
+        "dueDateTime": { "dateTime": "2024-01-03T09:30:00.0000000", "timeZone": "UTC" }
+    });
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messages"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "value": [flagged] })))
+        .mount(&env.server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .and(body_partial_json(json!({ "requests": [{
+            "method": "PATCH",
+            "url": "/me/messages/m1",
+            "body": { "flag": { "flagStatus": "notFlagged" } }
+        }] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [{ "id": "1", "status": 200, "body": { "id": "m1" } }]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["clean", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    // Undo flags the message again with its previous start and due date
+    Mock::given(method("POST"))
+        .and(path("/$batch"))
+        .and(body_partial_json(json!({ "requests": [{
+            "method": "PATCH",
+            "url": "/me/messages/m1",
+            "body": { "flag": {
+                "flagStatus": "flagged",
+                "startDateTime": { "dateTime": "2024-01-01T09:30:00", "timeZone": "UTC" },
+                "dueDateTime": { "dateTime": "2024-01-03T09:30:00", "timeZone": "UTC" }
+            } }
+        }] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "responses": [{ "id": "1", "status": 200, "body": { "id": "m1" } }]
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["undo", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        stdout(&output).contains("1 restored"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[tokio::test]
+async fn test_rules_test_with_sample_state() {
+    let env = TestEnv::new(
+        "- name: Unread\n  is_read: false\n  action: mark_read\n- name: Red\n  has_category: [Red]\n  action: archive\n- name: Broken\n  action: delete\n",
+    )
+    .await;
+
+    // A sample is unread, unflagged and uncategorized unless told otherwise
+    let output = env
+        .run(&["rules", "test", "--sender", "a@example.com"])
+        .await;
+    assert!(output.status.success(), "{:?}", output);
+    let text = stdout(&output);
+    assert!(
+        text.contains("State:   unread, not flagged, no categories"),
+        "{}",
+        text
+    );
+    assert!(text.contains("'Unread' applies"), "{}", text);
+
+    let output = env
+        .run(&[
+            "rules",
+            "test",
+            "--sender",
+            "a@example.com",
+            "--read",
+            "--category",
+            "Red",
+        ])
+        .await;
+    assert!(output.status.success(), "{:?}", output);
+    let text = stdout(&output);
+    assert!(text.contains("'Red' applies"), "{}", text);
+
+    // Rules that clean would refuse are reported
+    assert!(
+        text.contains("⚠️ Rules have 1 validation error(s)"),
+        "{}",
+        text
+    );
+}
+
+#[tokio::test]
+async fn test_auth_list_creates_no_profile() {
+    let env = TestEnv::new("[]\n").await;
+
+    let output = env.run(&["--profile", "work", "auth", "list"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    // The default profile is logged in, but not the active one
+    assert_eq!(stdout(&output), "  default\n");
+    assert!(
+        !env.config_home
+            .path()
+            .join("mailsweep")
+            .join("profiles")
+            .exists()
+    );
+}
+
+#[tokio::test]
+async fn test_rules_add_folder_and_move_to() {
+    let env = TestEnv::new("[]\n").await;
+
+    // --folder scopes the rule, --move-to is where it files messages
+    let output = env
+        .run(&[
+            "rules",
+            "add",
+            "--name",
+            "Junk receipts",
+            "--folder",
+            "junk",
+            "--action",
+            "move_to",
+            "--move-to",
+            "Finance/Receipts",
+            "--subject",
+            "receipt",
+        ])
+        .await;
+    assert!(output.status.success(), "{:?}", output);
+    let rules: Value = serde_yaml::from_str(&env.app_file("rules.yaml")).unwrap();
+    assert_eq!(rules[0]["folder"], "junk");
+    assert_eq!(rules[0]["action"], json!({ "move_to": "Finance/Receipts" }));
+
+    let output = env
+        .run(&[
+            "rules",
+            "add",
+            "--name",
+            "Junk",
+            "--folder",
+            "junk",
+            "--action",
+            "delete",
+            "--subject",
+            "lottery",
+        ])
+        .await;
+    assert!(output.status.success(), "{:?}", output);
+    let rules: Value = serde_yaml::from_str(&env.app_file("rules.yaml")).unwrap();
+    assert_eq!(rules[1]["folder"], "junk");
+    assert_eq!(rules[1]["action"], "delete");
+}
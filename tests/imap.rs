@@ -0,0 +1,404 @@
+//! End-to-end tests running `mailsweep clean` and `undo` against an in-process IMAP stub
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone)]
+struct StoredMessage {
+    uid: u32,
+    seen: bool,
+    deleted: bool,
+    header: String,
+}
+
+struct Folder {
+    attributes: &'static str,
+    uid_next: u32,
+    messages: Vec<StoredMessage>,
+}
+
+type Folders = Arc<Mutex<BTreeMap<String, Folder>>>;
+
+/// A minimal IMAP server supporting the commands mailsweep sends, over plain TCP
+struct ImapStub {
+    port: u16,
+    folders: Folders,
+}
+
+impl ImapStub {
+    fn start() -> Self {
+        Self::with_capabilities("IMAP4rev1 MOVE UIDPLUS")
+    }
+
+    fn with_capabilities(capabilities: &'static str) -> Self {
+        let mut folders = BTreeMap::new();
+        for (name, attributes) in [("INBOX", ""), ("Deleted Messages", "\\Trash")] {
+            folders.insert(
+                name.to_string(),
+                Folder {
+                    attributes,
+                    uid_next: 1,
+                    messages: Vec::new(),
+                },
+            );
+        }
+        let folders: Folders = Arc::new(Mutex::new(folders));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn({
+            let folders = folders.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let folders = folders.clone();
+                    thread::spawn(move || serve(stream.unwrap(), folders, capabilities));
+                }
+            }
+        });
+
+        Self { port, folders }
+    }
+
+    fn deliver(&self, from: &str, subject: &str) {
+        self.deliver_flagged(from, subject, false);
+    }
+
+    /// Deliver a message, marked as deleted but not expunged yet if `deleted` is set
+    fn deliver_flagged(&self, from: &str, subject: &str, deleted: bool) {
+        let mut folders = self.folders.lock().unwrap();
+        let inbox = folders.get_mut("INBOX").unwrap();
+        inbox.messages.push(StoredMessage {
+            uid: inbox.uid_next,
+            seen: false,
+            deleted,
+            header: format!(
+                "From: {}\r\nSubject: {}\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000\r\n\r\n",
+                from, subject
+            ),
+        });
+        inbox.uid_next += 1;
+    }
+
+    /// Subjects and seen flags of the messages in a folder
+    fn contents(&self, folder: &str) -> Vec<(String, bool)> {
+        let folders = self.folders.lock().unwrap();
+        folders[folder]
+            .messages
+            .iter()
+            .map(|message| {
+                let subject = message
+                    .header
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Subject: "))
+                    .unwrap()
+                    .to_string();
+                (subject, message.seen)
+            })
+            .collect()
+    }
+}
+
+fn unquote(arg: &str) -> String {
+    arg.trim_matches('"').to_string()
+}
+
+fn parse_uids(set: &str) -> Vec<u32> {
+    set.split(',').map(|uid| uid.parse().unwrap()).collect()
+}
+
+fn serve(stream: TcpStream, folders: Folders, capabilities: &str) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut out = stream;
+    let mut selected: Option<String> = None;
+    write!(out, "* OK IMAP stub ready\r\n").unwrap();
+
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+        let command = line.trim_end().to_string();
+        line.clear();
+
+        let (tag, rest) = command.split_once(' ').unwrap();
+        let mut response = String::new();
+        let mut folders = folders.lock().unwrap();
+
+        if rest.starts_with("LOGIN") || rest.starts_with("SUBSCRIBE") {
+        } else if rest == "CAPABILITY" {
+            response.push_str(&format!("* CAPABILITY {}\r\n", capabilities));
+        } else if rest == "LIST \"\" \"\"" {
+            response.push_str("* LIST (\\Noselect) \"/\" \"\"\r\n");
+        } else if rest.starts_with("LIST") {
+            for (name, folder) in folders.iter() {
+                response.push_str(&format!(
+                    "* LIST ({}) \"/\" \"{}\"\r\n",
+                    folder.attributes, name
+                ));
+            }
+        } else if let Some(name) = rest.strip_prefix("CREATE ") {
+            folders.insert(
+                unquote(name),
+                Folder {
+                    attributes: "",
+                    uid_next: 1,
+                    messages: Vec::new(),
+                },
+            );
+        } else if let Some(args) = rest.strip_prefix("STATUS ") {
+            let (name, _) = args.rsplit_once(' ').unwrap();
+            response.push_str(&format!(
+                "* STATUS {} (UIDNEXT {})\r\n",
+                name,
+                folders[&unquote(name)].uid_next
+            ));
+        } else if let Some(name) = rest
+            .strip_prefix("SELECT ")
+            .or(rest.strip_prefix("EXAMINE "))
+        {
+            let folder = &folders[&unquote(name)];
+            response.push_str(&format!(
+                "* {} EXISTS\r\n* OK [UIDVALIDITY 1] UIDs valid\r\n* OK [UIDNEXT {}] Predicted next UID\r\n",
+                folder.messages.len(),
+                folder.uid_next
+            ));
+            selected = Some(unquote(name));
+        } else if rest == "UID SEARCH DELETED" {
+            let folder = &folders[selected.as_ref().unwrap()];
+            let uids: Vec<String> = folder
+                .messages
+                .iter()
+                .filter(|message| message.deleted)
+                .map(|message| format!(" {}", message.uid))
+                .collect();
+            response.push_str(&format!("* SEARCH{}\r\n", uids.concat()));
+        } else if let Some(query) = rest.strip_prefix("UID SEARCH ") {
+            let folder = &folders[selected.as_ref().unwrap()];
+            let min_uid = match query.strip_prefix("UID ") {
+                Some(range) => range.trim_end_matches(":*").parse().unwrap(),
+                None => 0,
+            };
+            let uids: Vec<String> = folder
+                .messages
+                .iter()
+                .filter(|message| message.uid >= min_uid)
+                .map(|message| message.uid.to_string())
+                .collect();
+            response.push_str(&format!("* SEARCH {}\r\n", uids.join(" ")));
+        } else if let Some(args) = rest.strip_prefix("UID FETCH ") {
+            let uids = parse_uids(args.split(' ').next().unwrap());
+            let folder = &folders[selected.as_ref().unwrap()];
+            for (i, message) in folder.messages.iter().enumerate() {
+                if uids.contains(&message.uid) {
+                    response.push_str(&format!(
+                        "* {} FETCH (UID {} FLAGS ({}) INTERNALDATE \"01-Jan-2024 10:00:00 +0000\" BODY[HEADER] {{{}}}\r\n{})\r\n",
+                        i + 1,
+                        message.uid,
+                        if message.seen { "\\Seen" } else { "" },
+                        message.header.len(),
+                        message.header
+                    ));
+                }
+            }
+        } else if let Some(args) = rest.strip_prefix("UID STORE ") {
+            let (set, flags) = args.split_once(' ').unwrap();
+            let uids = parse_uids(set);
+            let folder = folders.get_mut(selected.as_ref().unwrap()).unwrap();
+            for message in &mut folder.messages {
+                if uids.contains(&message.uid) {
+                    if flags.contains("\\Deleted") {
+                        message.deleted = flags.starts_with('+');
+                    } else {
+                        message.seen = flags.starts_with('+');
+                    }
+                }
+            }
+        } else if rest == "EXPUNGE" {
+            let folder = folders.get_mut(selected.as_ref().unwrap()).unwrap();
+            folder.messages.retain(|message| !message.deleted);
+        } else if let Some(args) = rest.strip_prefix("UID COPY ") {
+            // Without UIDPLUS, there is no COPYUID response
+            let (set, target) = args.split_once(' ').unwrap();
+            let uids = parse_uids(set);
+            let copies: Vec<StoredMessage> = folders[selected.as_ref().unwrap()]
+                .messages
+                .iter()
+                .filter(|message| uids.contains(&message.uid))
+                .cloned()
+                .collect();
+            let target = folders.get_mut(&unquote(target)).unwrap();
+            for mut message in copies {
+                message.uid = target.uid_next;
+                target.uid_next += 1;
+                target.messages.push(message);
+            }
+        } else if let Some(args) = rest.strip_prefix("UID MOVE ") {
+            let (set, target) = args.split_once(' ').unwrap();
+            let uids = parse_uids(set);
+            let source = folders.get_mut(selected.as_ref().unwrap()).unwrap();
+            let (moved, kept): (Vec<_>, Vec<_>) = source
+                .messages
+                .drain(..)
+                .partition(|message| uids.contains(&message.uid));
+            source.messages = kept;
+
+            let target = folders.get_mut(&unquote(target)).unwrap();
+            let mut new_uids = Vec::new();
+            for mut message in moved {
+                new_uids.push(target.uid_next.to_string());
+                message.uid = target.uid_next;
+                target.uid_next += 1;
+                target.messages.push(message);
+            }
+            response.push_str(&format!(
+                "* OK [COPYUID 1 {} {}] Moved\r\n",
+                set,
+                new_uids.join(",")
+            ));
+        } else if rest == "LOGOUT" {
+            response.push_str("* BYE\r\n");
+        } else {
+            response.push_str(&format!("{} BAD unsupported\r\n", tag));
+            out.write_all(response.as_bytes()).unwrap();
+            continue;
+        }
+
+        response.push_str(&format!("{} OK done\r\n", tag));
+        out.write_all(response.as_bytes()).unwrap();
+    }
+}
+
+fn run(config_home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mailsweep"))
+        .args(args)
+        .env("XDG_CONFIG_HOME", config_home)
+        .env("APPDATA", config_home)
+        .env_remove("MAILSWEEP_PROFILE")
+        .env_remove("MAILSWEEP_IMAP_PASSWORD")
+        .env_remove("RUST_BACKTRACE")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_clean_and_undo_imap() {
+    let server = ImapStub::start();
+    server.deliver("news@example.com", "Weekly newsletter");
+    server.deliver("alerts@example.com", "Disk full");
+    server.deliver("shop@example.com", "Big promo");
+    server.deliver("boss@example.com", "Meeting");
+
+    let config_home = tempfile::tempdir().unwrap();
+    let app_dir = config_home.path().join("mailsweep");
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(
+        app_dir.join("config.yaml"),
+        format!(
+            "imap:\n  host: 127.0.0.1\n  port: {}\n  username: jane\n  password: secret\n  tls: false\n",
+            server.port
+        ),
+    )
+    .unwrap();
+    fs::write(
+        app_dir.join("rules.yaml"),
+        "- name: Newsletters\n  subject_contains:\n    - newsletter\n  action:\n    move_to: Reading\n- name: Alerts\n  sender_contains:\n    - alerts@\n  action: mark_read\n- name: Promotions\n  subject_contains:\n    - promo\n  action: delete\n",
+    )
+    .unwrap();
+
+    let output = run(config_home.path(), &["clean", "--create-folders", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert_eq!(
+        server.contents("INBOX"),
+        vec![
+            ("Disk full".to_string(), true),
+            ("Meeting".to_string(), false)
+        ]
+    );
+    assert_eq!(
+        server.contents("Reading"),
+        vec![("Weekly newsletter".to_string(), false)]
+    );
+    // The folder marked \Trash is used for deleted messages
+    assert_eq!(
+        server.contents("Deleted Messages"),
+        vec![("Big promo".to_string(), false)]
+    );
+
+    let output = run(config_home.path(), &["undo", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let mut inbox = server.contents("INBOX");
+    inbox.sort();
+    assert_eq!(
+        inbox,
+        vec![
+            ("Big promo".to_string(), false),
+            ("Disk full".to_string(), false),
+            ("Meeting".to_string(), false),
+            ("Weekly newsletter".to_string(), false)
+        ]
+    );
+    assert!(server.contents("Reading").is_empty());
+}
+
+#[test]
+fn test_move_without_move_or_uidplus() {
+    let server = ImapStub::with_capabilities("IMAP4rev1");
+    server.deliver("news@example.com", "Weekly newsletter");
+    server.deliver_flagged("old@example.com", "Marked as deleted elsewhere", true);
+
+    let config_home = tempfile::tempdir().unwrap();
+    let app_dir = config_home.path().join("mailsweep");
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(
+        app_dir.join("config.yaml"),
+        format!(
+            "imap:\n  host: 127.0.0.1\n  port: {}\n  username: jane\n  password: secret\n  tls: false\n",
+            server.port
+        ),
+    )
+    .unwrap();
+    fs::write(
+        app_dir.join("rules.yaml"),
+        "- name: Newsletters\n  subject_contains:\n    - newsletter\n  action:\n    move_to: Reading\n",
+    )
+    .unwrap();
+
+    // A plain EXPUNGE would also remove the message someone else marked as deleted
+    let output = run(config_home.path(), &["clean", "--create-folders", "--yes"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("marked as deleted"), "{}", stderr);
+    assert_eq!(server.contents("INBOX").len(), 2);
+    assert!(server.contents("Reading").is_empty());
+
+    server
+        .folders
+        .lock()
+        .unwrap()
+        .get_mut("INBOX")
+        .unwrap()
+        .messages
+        .retain(|m| !m.deleted);
+    let output = run(config_home.path(), &["clean", "--create-folders", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(server.contents("INBOX").is_empty());
+    assert_eq!(
+        server.contents("Reading"),
+        vec![("Weekly newsletter".to_string(), false)]
+    );
+
+    // The new UID isn't known, so undo can't find the message instead of guessing
+    let output = run(config_home.path(), &["undo", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("1 message(s) can't be restored"),
+        "{}",
+        stdout
+    );
+    assert_eq!(server.contents("Reading").len(), 1);
+}
@@ -0,0 +1,224 @@
+//! End-to-end tests running `mailsweep clean` and `undo` on a local Maildir
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn deliver(dir: &Path, file_name: &str, from: &str, subject: &str) {
+    fs::write(
+        dir.join(file_name),
+        format!(
+            "From: {}\r\nSubject: {}\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000\r\n\r\nHello\r\n",
+            from, subject
+        ),
+    )
+    .unwrap();
+}
+
+fn run(config_home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mailsweep"))
+        .args(args)
+        .env("XDG_CONFIG_HOME", config_home)
+        .env("APPDATA", config_home)
+        .env_remove("MAILSWEEP_PROFILE")
+        .env_remove("RUST_BACKTRACE")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_clean_and_undo_maildir() {
+    let config_home = tempfile::tempdir().unwrap();
+    let app_dir = config_home.path().join("mailsweep");
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(
+        app_dir.join("rules.yaml"),
+        "- name: Newsletters\n  subject_contains:\n    - newsletter\n  action:\n    move_to: Reading\n- name: Alerts\n  sender_contains:\n    - alerts@\n  action: mark_read\n",
+    )
+    .unwrap();
+
+    let maildir = tempfile::tempdir().unwrap();
+    for subdir in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.path().join(subdir)).unwrap();
+    }
+    let new = maildir.path().join("new");
+    deliver(&new, "1.a.host", "news@example.com", "Weekly newsletter");
+    deliver(&new, "2.b.host", "alerts@example.com", "Disk full");
+    deliver(&new, "3.c.host", "boss@example.com", "Meeting");
+
+    let maildir_arg = maildir.path().to_str().unwrap();
+    let output = run(
+        config_home.path(),
+        &[
+            "clean",
+            "--maildir",
+            maildir_arg,
+            "--create-folders",
+            "--yes",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    assert!(maildir.path().join(".Reading/new/1.a.host").is_file());
+    assert!(maildir.path().join("cur/2.b.host:2,S").is_file());
+    assert!(new.join("3.c.host").is_file());
+
+    // Undo finds the Maildir in the journal
+    let output = run(config_home.path(), &["undo", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert!(new.join("1.a.host").is_file());
+    assert!(maildir.path().join("cur/2.b.host:2,").is_file());
+    assert!(new.join("3.c.host").is_file());
+}
+
+#[test]
+fn test_undo_keeps_previously_read_messages_read() {
+    let config_home = tempfile::tempdir().unwrap();
+    let app_dir = config_home.path().join("mailsweep");
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(
+        app_dir.join("rules.yaml"),
+        "- name: Alerts\n  sender_contains:\n    - alerts@\n  action: mark_read\n",
+    )
+    .unwrap();
+
+    let maildir = tempfile::tempdir().unwrap();
+    for subdir in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.path().join(subdir)).unwrap();
+    }
+    let cur = maildir.path().join("cur");
+    deliver(&cur, "1.a.host:2,", "alerts@example.com", "Disk full");
+    deliver(
+        &cur,
+        "2.b.host:2,S",
+        "alerts@example.com",
+        "Disk almost full",
+    );
+
+    let maildir_arg = maildir.path().to_str().unwrap();
+    let output = run(
+        config_home.path(),
+        &["clean", "--maildir", maildir_arg, "--yes"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(cur.join("1.a.host:2,S").is_file());
+    assert!(cur.join("2.b.host:2,S").is_file());
+
+    let output = run(config_home.path(), &["undo", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("1 message(s) to mark as unread"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("1 message(s) stay read"), "{}", stdout);
+
+    assert!(cur.join("1.a.host:2,").is_file());
+    assert!(cur.join("2.b.host:2,S").is_file());
+
+    // The run is fully undone
+    let output = run(config_home.path(), &["undo", "--yes"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Nothing to undo"), "{}", stdout);
+}
+
+#[test]
+fn test_multiple_actions_maildir() {
+    let config_home = tempfile::tempdir().unwrap();
+    let app_dir = config_home.path().join("mailsweep");
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(
+        app_dir.join("rules.yaml"),
+        "- name: Receipts\n  subject_contains:\n    - receipt\n  actions:\n    - mark_read\n    - move_to: Receipts\n",
+    )
+    .unwrap();
+
+    let maildir = tempfile::tempdir().unwrap();
+    for subdir in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.path().join(subdir)).unwrap();
+    }
+    let new = maildir.path().join("new");
+    deliver(&new, "1.a.host", "shop@example.com", "Your receipt");
+
+    let maildir_arg = maildir.path().to_str().unwrap();
+    let output = run(
+        config_home.path(),
+        &[
+            "clean",
+            "--maildir",
+            maildir_arg,
+            "--create-folders",
+            "--yes",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    // Marked as read first, then moved using the ID it got from being marked
+    assert!(maildir.path().join(".Receipts/cur/1.a.host:2,S").is_file());
+
+    // Undo reverts the actions in reverse order
+    let output = run(config_home.path(), &["undo", "--yes"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(maildir.path().join("cur/1.a.host:2,").is_file());
+}
+
+#[test]
+fn test_analyze_maildir() {
+    let config_home = tempfile::tempdir().unwrap();
+    let app_dir = config_home.path().join("mailsweep");
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(
+        app_dir.join("rules.yaml"),
+        "- name: Alerts\n  sender_contains:\n    - alerts@\n  action: mark_read\n",
+    )
+    .unwrap();
+
+    let maildir = tempfile::tempdir().unwrap();
+    for subdir in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.path().join(subdir)).unwrap();
+    }
+    let new = maildir.path().join("new");
+    deliver(
+        &new,
+        "1.a.host",
+        "jira@example.com",
+        "[JIRA] PROJ-1 created",
+    );
+    deliver(
+        &new,
+        "2.b.host",
+        "jira@example.com",
+        "[JIRA] PROJ-2 updated",
+    );
+    deliver(&new, "3.c.host", "alerts@example.com", "Disk full");
+    deliver(&new, "4.d.host", "boss@other.org", "Meeting");
+
+    let maildir_arg = maildir.path().to_str().unwrap();
+    let output = run(
+        config_home.path(),
+        &["analyze", "--maildir", maildir_arg, "--report-only"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("Analyzed 4 messages (100% unread)"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("jira@example.com"), "{}", stdout);
+    assert!(stdout.contains("[JIRA]"), "{}", stdout);
+    // The domain groups the alerts the existing rule already covers
+    let domain_row = stdout
+        .lines()
+        .find(|l| l.trim_start().starts_with("example.com"))
+        .unwrap();
+    assert_eq!(
+        domain_row.split_whitespace().collect::<Vec<_>>(),
+        ["example.com", "3", "100%", "1"]
+    );
+    // Single messages aren't reported
+    assert!(!stdout.contains("boss@other.org"), "{}", stdout);
+}
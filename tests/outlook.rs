@@ -0,0 +1,438 @@
+//! End-to-end tests syncing rules with Outlook inbox rules on a local mock Graph server
+
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Output;
+use tempfile::TempDir;
+use wiremock::matchers::{body_json, body_partial_json, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// A config directory with a logged-in token cache and rules, plus a mock Graph server
+struct TestEnv {
+    config_home: TempDir,
+    server: MockServer,
+}
+
+impl TestEnv {
+    async fn new(rules_yaml: &str) -> Self {
+        let config_home = tempfile::tempdir().unwrap();
+        let app_dir = config_home.path().join("mailsweep");
+        fs::create_dir_all(&app_dir).unwrap();
+
+        // A token that doesn't expire during the test, so no refresh is attempted
+        fs::write(
+            app_dir.join("token_cache.yaml"),
+            "access_token: test-token\nrefresh_token: test-refresh\nexpires_at: [2100, 1, 0, 0, 0, 0, 0, 0, 0]\n",
+        )
+        .unwrap();
+        fs::write(app_dir.join("rules.yaml"), rules_yaml).unwrap();
+
+        Self {
+            config_home,
+            server: MockServer::start().await,
+        }
+    }
+
+    /// Run mailsweep with the given arguments against the mock server
+    async fn run(&self, args: &[&str]) -> Output {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_mailsweep"))
+            .args(args)
+            .env("XDG_CONFIG_HOME", self.config_home.path())
+            .env("APPDATA", self.config_home.path())
+            .env("MAILSWEEP_GRAPH_URL", self.server.uri())
+            .env(
+                "MAILSWEEP_LOGIN_URL",
+                format!("{}/login", self.server.uri()),
+            )
+            .env_remove("MAILSWEEP_PROFILE")
+            .env_remove("RUST_BACKTRACE")
+            .output()
+            .await
+            .unwrap()
+    }
+
+    fn rules_path(&self) -> PathBuf {
+        self.config_home.path().join("mailsweep").join("rules.yaml")
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[tokio::test]
+async fn test_import_outlook_rules() {
+    let env = TestEnv::new(
+        "- name: Newsletters\n  subject_contains:\n    - newsletter\n  action: archive\n",
+    )
+    .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                {
+                    "id": "r2",
+                    "displayName": "Body filter",
+                    "sequence": 2,
+                    "isEnabled": true,
+                    "conditions": { "bodyContains": ["unsubscribe"] },
+                    "actions": { "delete": true }
+                },
+                {
+                    "id": "r1",
+                    "displayName": "Receipts",
+                    "sequence": 1,
+                    "isEnabled": true,
+                    "conditions": { "subjectContains": ["receipt"] },
+                    "actions": { "moveToFolder": "receipts-id", "markAsRead": true }
+                },
+                {
+                    "id": "r3",
+                    "displayName": "newsletters",
+                    "sequence": 3,
+                    "isEnabled": true,
+                    "conditions": { "senderContains": ["news"] },
+                    "actions": { "delete": true }
+                }
+            ]
+        })))
+        .mount(&env.server)
+        .await;
+
+    // The folder path is looked up by walking up to the root folder
+    for (id, name, parent) in [
+        ("msgfolderroot", "Top of Information Store", "root-parent"),
+        ("receipts-id", "Receipts", "inbox-id"),
+        ("inbox-id", "Inbox", "msgfolderroot-id"),
+    ] {
+        let id_in_body = if id == "msgfolderroot" {
+            "msgfolderroot-id"
+        } else {
+            id
+        };
+        Mock::given(method("GET"))
+            .and(path(format!("/me/mailFolders/{}", id)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": id_in_body,
+                "displayName": name,
+                "parentFolderId": parent
+            })))
+            .mount(&env.server)
+            .await;
+    }
+
+    let output = env.run(&["rules", "import-outlook"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = stdout(&output);
+    assert!(stdout.contains("Imported 'Receipts'"), "{}", stdout);
+    assert!(
+        stdout.contains("Skipped 'Body filter': condition bodyContains"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Skipped 'newsletters': a rule with this name already exists"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("1 rule(s) imported, 2 skipped"),
+        "{}",
+        stdout
+    );
+
+    // The imported rule is appended after the existing ones
+    let rules = fs::read_to_string(env.rules_path()).unwrap();
+    let newsletters = rules.find("name: Newsletters").unwrap();
+    let receipts = rules.find("name: Receipts").unwrap();
+    assert!(newsletters < receipts, "{}", rules);
+    assert!(
+        rules.contains("actions:\n    - mark_read\n    - move_to: Inbox/Receipts"),
+        "{}",
+        rules
+    );
+}
+
+#[tokio::test]
+async fn test_push_outlook_rules() {
+    let env = TestEnv::new(
+        "- name: Promotions\n  subject_contains:\n    - promo\n  action: delete\n- name: Alerts\n  sender_is:\n    - alerts@example.com\n  action: mark_read\n- name: Old CI\n  sender_contains:\n    - ci@\n  older_than: 7d\n  action: delete\n",
+    )
+    .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [
+                {
+                    "id": "own",
+                    "displayName": "My own rule",
+                    "sequence": 1,
+                    "isEnabled": true,
+                    "conditions": { "bodyContains": ["x"] },
+                    "actions": { "delete": true }
+                },
+                {
+                    "id": "alerts",
+                    "displayName": "[mailsweep] Alerts",
+                    "sequence": 2,
+                    "isEnabled": true,
+                    "conditions": {
+                        "fromAddresses": [{ "emailAddress": { "name": "Alerts", "address": "alerts@example.com" } }],
+                        "subjectContains": null
+                    },
+                    "actions": { "markAsRead": true, "stopProcessingRules": true }
+                },
+                {
+                    "id": "stale",
+                    "displayName": "[mailsweep] Removed rule",
+                    "sequence": 3,
+                    "isEnabled": true,
+                    "conditions": { "subjectContains": ["old"] },
+                    "actions": { "delete": true }
+                }
+            ]
+        })))
+        .mount(&env.server)
+        .await;
+
+    // Pushed rules go after the user's own rules, in the order of the rules file
+    Mock::given(method("POST"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .and(body_json(json!({
+            "displayName": "[mailsweep] Promotions",
+            "sequence": 2,
+            "isEnabled": true,
+            "conditions": { "subjectContains": ["promo"] },
+            "actions": { "delete": true, "stopProcessingRules": true }
+        })))
+        .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+            "id": "new",
+            "displayName": "[mailsweep] Promotions"
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    Mock::given(method("PATCH"))
+        .and(path("/me/mailFolders/inbox/messageRules/alerts"))
+        .and(body_partial_json(json!({ "sequence": 3 })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "alerts" })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    // Only the stale rule created by mailsweep is deleted, the user's own rule is kept
+    Mock::given(method("DELETE"))
+        .and(path("/me/mailFolders/inbox/messageRules/stale"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["rules", "push-outlook", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = stdout(&output);
+    assert!(
+        stdout.contains("'Old CI' only works client-side: older_than/newer_than"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("1 to create, 1 to update, 1 to delete, 0 unchanged"),
+        "{}",
+        stdout
+    );
+}
+
+#[tokio::test]
+async fn test_push_outlook_keeps_names_unique() {
+    let env = TestEnv::new(
+        "- name: Alerts\n  sender_is:\n    - alerts@example.com\n  action: mark_read\n",
+    )
+    .await;
+
+    // A rule pushed twice under the same name leaves one to update and one to delete
+    let pushed_alerts = |id: &str| {
+        json!({
+            "id": id,
+            "displayName": "[mailsweep] Alerts",
+            "sequence": 1,
+            "isEnabled": true,
+            "conditions": {
+                "fromAddresses": [{ "emailAddress": { "name": "Alerts", "address": "alerts@example.com" } }]
+            },
+            "actions": { "markAsRead": true, "stopProcessingRules": true }
+        })
+    };
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [pushed_alerts("first"), pushed_alerts("second")]
+        })))
+        .mount(&env.server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/me/mailFolders/inbox/messageRules/second"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["rules", "push-outlook", "--yes"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        stdout(&output).contains("0 to create, 0 to update, 1 to delete, 1 unchanged"),
+        "{:?}",
+        output
+    );
+
+    // Two rules with the same name can't be told apart on the next push
+    fs::write(
+        env.rules_path(),
+        "- name: Alerts\n  sender_is:\n    - alerts@example.com\n  action: mark_read\n- name: alerts\n  subject_contains:\n    - alert\n  action: delete\n",
+    )
+    .unwrap();
+    let output = env.run(&["rules", "push-outlook", "--yes"]).await;
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Several rules are named 'alerts'"),
+        "{:?}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn test_push_outlook_stops_on_folder_lookup_errors() {
+    let env = TestEnv::new(
+        "- name: Receipts\n  subject_contains:\n    - receipt\n  action:\n    move_to: Inbox/Receipts\n",
+    )
+    .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [{
+                "id": "receipts",
+                "displayName": "[mailsweep] Receipts",
+                "sequence": 1,
+                "isEnabled": true,
+                "conditions": { "subjectContains": ["receipt"] },
+                "actions": { "moveToFolder": "receipts-id", "stopProcessingRules": true }
+            }]
+        })))
+        .mount(&env.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/childFolders"))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+            "error": { "code": "ErrorAccessDenied", "message": "Access is denied" }
+        })))
+        .mount(&env.server)
+        .await;
+
+    // The pushed rule isn't deleted as if its folder were gone
+    Mock::given(method("DELETE"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(0)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["rules", "push-outlook", "--yes"]).await;
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Access is denied"),
+        "{:?}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn test_push_outlook_keeps_client_side_rules_first() {
+    let env = TestEnv::new(
+        "- name: Unread boss\n  sender_is:\n    - boss@example.com\n  is_read: false\n  action: mark_read\n\
+         - name: Alerts\n  sender_is:\n    - alerts@example.com\n  action: mark_read\n\
+         - name: Example\n  sender_contains:\n    - \"@example.com\"\n  action: delete\n",
+    )
+    .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "value": [] })))
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["rules", "push-outlook", "--dry-run"]).await;
+    assert!(output.status.success(), "{:?}", output);
+
+    // The delete rule would take the boss's unread mail on the server; the alerts rule can't
+    let stdout = stdout(&output);
+    assert!(
+        stdout.contains(
+            "'Example' isn't pushed: it could take messages the earlier client-side rule 'Unread boss' matches"
+        ),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("+ Create 'Alerts'"), "{}", stdout);
+    assert!(
+        stdout.contains("1 to create, 0 to update, 0 to delete, 0 unchanged"),
+        "{}",
+        stdout
+    );
+}
+
+#[tokio::test]
+async fn test_push_outlook_continues_after_failures() {
+    let env =
+        TestEnv::new("- name: Promotions\n  subject_contains:\n    - promo\n  action: delete\n")
+            .await;
+
+    Mock::given(method("GET"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "value": [{
+                "id": "stale",
+                "displayName": "[mailsweep] Removed rule",
+                "sequence": 1,
+                "isEnabled": true,
+                "conditions": { "subjectContains": ["old"] },
+                "actions": { "delete": true }
+            }]
+        })))
+        .mount(&env.server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/me/mailFolders/inbox/messageRules"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": { "code": "ErrorInvalidRule", "message": "Invalid rule" }
+        })))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    // The failed create doesn't keep the stale rule from being deleted
+    Mock::given(method("DELETE"))
+        .and(path("/me/mailFolders/inbox/messageRules/stale"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&env.server)
+        .await;
+
+    let output = env.run(&["rules", "push-outlook", "--yes"]).await;
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(
+        stdout(&output).contains("Completed: 1 applied, 1 failed"),
+        "{:?}",
+        output
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Invalid rule"),
+        "{:?}",
+        output
+    );
+}